@@ -0,0 +1,513 @@
+//! Include/exclude rules selecting which files get indexed and transferred.
+//!
+//! Rules are similar to rsync's: each rule is a glob pattern, prefixed with
+//! `+ ` (include) or `- ` (exclude), and the first rule that matches a path
+//! decides whether it is excluded. Paths that no rule matches are included.
+//!
+//! * A pattern starting with `/` is anchored to the root of the tree,
+//!   otherwise it can match the end of the path at any depth.
+//! * A pattern ending with `/` only matches directories.
+//! * `*` matches anything except `/`, `**` matches anything, `?` matches a
+//!   single character, and `[a-z]` matches a character class (`[!a-z]` for
+//!   the complement).
+//!
+//! Excluding a directory excludes everything under it.
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::path::{Component, Path};
+
+use crate::Error;
+
+/// Name of the files containing rules for the directory they are in
+pub const IGNORE_FILE_NAME: &str = ".syncfastignore";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Include,
+    Exclude,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    action: Action,
+    /// The pattern, split into path components
+    components: Vec<String>,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Rule {
+    /// Parse a rule, with an optional `+ ` or `- ` prefix
+    fn parse(rule: &str, default: Action) -> Result<Rule, Error> {
+        let (action, pattern) = if let Some(p) = rule.strip_prefix("+ ") {
+            (Action::Include, p)
+        } else if let Some(p) = rule.strip_prefix("- ") {
+            (Action::Exclude, p)
+        } else {
+            (default, rule)
+        };
+        Rule::new(action, pattern, rule)
+    }
+
+    /// Build a rule from a pattern, `rule` is used in error messages
+    fn new(action: Action, mut pattern: &str, rule: &str) -> Result<Rule, Error> {
+        let anchored = pattern.starts_with('/');
+        if anchored {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.ends_with('/');
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+        if pattern.is_empty() {
+            return Err(Error::BadFilter(format!("Empty pattern: {:?}", rule)));
+        }
+        let components: Vec<String> =
+            pattern.split('/').map(ToOwned::to_owned).collect();
+        for component in &components {
+            if component.is_empty() {
+                return Err(Error::BadFilter(
+                    format!("Empty path component: {:?}", rule),
+                ));
+            }
+            check_glob(component).map_err(|e| {
+                Error::BadFilter(format!("{}: {:?}", e, rule))
+            })?;
+        }
+        Ok(Rule { action, components, anchored, dir_only })
+    }
+
+    fn to_rule_string(&self) -> String {
+        let mut result = String::new();
+        result.push_str(match self.action {
+            Action::Include => "+ ",
+            Action::Exclude => "- ",
+        });
+        if self.anchored {
+            result.push('/');
+        }
+        result.push_str(&self.components.join("/"));
+        if self.dir_only {
+            result.push('/');
+        }
+        result
+    }
+
+    fn matches(&self, path: &[Cow<[u8]>], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            match_components(&self.components, path)
+        } else {
+            (0..path.len()).any(|i| match_components(&self.components, &path[i..]))
+        }
+    }
+}
+
+/// A list of include/exclude rules
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+}
+
+impl Filter {
+    /// Create an empty filter, which doesn't exclude anything
+    pub fn new() -> Filter {
+        Default::default()
+    }
+
+    /// Read rules from a file, one per line
+    ///
+    /// Empty lines and lines starting with `#` are ignored. Lines without a
+    /// `+ ` or `- ` prefix are exclude rules.
+    pub fn from_file(path: &Path) -> Result<Filter, Error> {
+        let mut filter = Filter::new();
+        filter.add_rules_from_file(path)?;
+        Ok(filter)
+    }
+
+    /// Whether this filter has no rules
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Add a rule, `+ pattern` or `- pattern`
+    pub fn add_rule(&mut self, rule: &str) -> Result<(), Error> {
+        if !rule.starts_with("+ ") && !rule.starts_with("- ") {
+            return Err(Error::BadFilter(
+                format!("Rule should start with '+ ' or '- ': {:?}", rule),
+            ));
+        }
+        self.rules.push(Rule::parse(rule, Action::Exclude)?);
+        Ok(())
+    }
+
+    /// Add a rule excluding paths matching a pattern
+    pub fn exclude(&mut self, pattern: &str) -> Result<(), Error> {
+        self.rules.push(Rule::new(Action::Exclude, pattern, pattern)?);
+        Ok(())
+    }
+
+    /// Add a rule including paths matching a pattern
+    pub fn include(&mut self, pattern: &str) -> Result<(), Error> {
+        self.rules.push(Rule::new(Action::Include, pattern, pattern)?);
+        Ok(())
+    }
+
+    /// Add rules from a file, see `from_file()`
+    pub fn add_rules_from_file(&mut self, path: &Path) -> Result<(), Error> {
+        let content = std::fs::read_to_string(path)?;
+        for line in content.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.rules.push(Rule::parse(line, Action::Exclude)?);
+        }
+        Ok(())
+    }
+
+    /// Get the rules, in the format accepted by `add_rule()`
+    pub fn rules(&self) -> Vec<String> {
+        self.rules.iter().map(Rule::to_rule_string).collect()
+    }
+
+    /// Check a path against the rules
+    ///
+    /// Returns `Some(true)` if the first matching rule excludes it,
+    /// `Some(false)` if it includes it, and `None` if no rule matches.
+    pub fn check(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let components = path_components(path);
+        for rule in &self.rules {
+            if rule.matches(&components, is_dir) {
+                return Some(rule.action == Action::Exclude);
+            }
+        }
+        None
+    }
+
+    /// Check whether a path is excluded by the rules
+    ///
+    /// This doesn't check whether the parent directories are excluded, see
+    /// `is_path_excluded()` for that.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.check(path, is_dir).unwrap_or(false)
+    }
+
+    /// Check whether a path or any of its parent directories are excluded
+    pub fn is_path_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let mut parent = path.parent();
+        while let Some(p) = parent {
+            if p.as_os_str().is_empty() {
+                break;
+            }
+            if self.is_excluded(p, true) {
+                return true;
+            }
+            parent = p.parent();
+        }
+        self.is_excluded(path, is_dir)
+    }
+}
+
+fn path_components(path: &Path) -> Vec<Cow<'_, [u8]>> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(name_bytes(s)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Get the bytes of a file name, which globs are matched against
+#[cfg(unix)]
+fn name_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn name_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    match name.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// Match pattern components against path components, handling `**`
+fn match_components(pattern: &[String], path: &[Cow<[u8]>]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(p) if p == "**" => {
+            (0..=path.len()).any(|i| match_components(&pattern[1..], &path[i..]))
+        }
+        Some(p) => {
+            !path.is_empty()
+                && match_glob(p.as_bytes(), &path[0])
+                && match_components(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+/// Check that a glob is valid, e.g. character classes are terminated
+fn check_glob(pattern: &str) -> Result<(), &'static str> {
+    let pattern = pattern.as_bytes();
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'[' => match class_end(pattern, i) {
+                Some(end) => i = end,
+                None => return Err("Unterminated character class"),
+            },
+            b'\\' => i += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// Find the position of the `]` closing a character class starting at `start`
+fn class_end(pattern: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if i < pattern.len() && (pattern[i] == b'!' || pattern[i] == b'^') {
+        i += 1;
+    }
+    // A ']' right at the start is part of the class
+    if i < pattern.len() && pattern[i] == b']' {
+        i += 1;
+    }
+    while i < pattern.len() {
+        if pattern[i] == b']' {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Decode the character at the start of `bytes`, returning it and its length
+///
+/// Bytes that are not valid UTF-8 are read one at a time, as values above
+/// `std::char::MAX` so they don't match any character of the pattern.
+fn next_char(bytes: &[u8]) -> (u32, usize) {
+    let len = match bytes[0] {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 0,
+    };
+    if len > 0 && len <= bytes.len() {
+        if let Ok(s) = std::str::from_utf8(&bytes[..len]) {
+            return (s.chars().next().unwrap() as u32, len);
+        }
+    }
+    (std::char::MAX as u32 + 1 + bytes[0] as u32, 1)
+}
+
+/// Match a character class (starting with `[`) against a character
+fn match_class(class: &[u8], c: u32) -> bool {
+    let mut i = 1;
+    let negate = class[i] == b'!' || class[i] == b'^';
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let end = class.len() - 1;
+    let mut first = true;
+    while i < end {
+        if class[i] == b']' && !first {
+            break;
+        }
+        first = false;
+        let (start, len) = next_char(&class[i..end]);
+        i += len;
+        if i + 1 < end && class[i] == b'-' {
+            let (last, len) = next_char(&class[i + 1..end]);
+            if start <= c && c <= last {
+                matched = true;
+            }
+            i += 1 + len;
+        } else if start == c {
+            matched = true;
+        }
+    }
+    matched != negate
+}
+
+/// Match a glob against a single path component
+///
+/// `?` and character classes match one character if the name is valid UTF-8
+/// there, one byte otherwise.
+fn match_glob(pattern: &[u8], name: &[u8]) -> bool {
+    // Iterative matching with backtracking to the last star
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let (ch, len) = next_char(&name[n..]);
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    // Consecutive stars are the same as one
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    star = Some((p, n));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    n += len;
+                    continue;
+                }
+                b'[' => {
+                    let end = class_end(pattern, p).unwrap();
+                    if match_class(&pattern[p..=end], ch) {
+                        p = end + 1;
+                        n += len;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    let (escaped, escaped_len) = next_char(&pattern[p + 1..]);
+                    if escaped == ch {
+                        p += 1 + escaped_len;
+                        n += len;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == name[n] {
+                        p += 1;
+                        n += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // Mismatch, backtrack: have the last star match one more character
+        match star {
+            Some((star_p, star_n)) => {
+                let star_n = star_n + next_char(&name[star_n..]).1;
+                p = star_p;
+                n = star_n;
+                star = Some((star_p, star_n));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Filter, match_glob};
+
+    #[test]
+    fn test_glob() {
+        assert!(match_glob(b"*.rs", b"main.rs"));
+        assert!(match_glob(b"*.rs", b".rs"));
+        assert!(!match_glob(b"*.rs", b"main.rc"));
+        assert!(match_glob(b"a*b*c", b"aXXbYYbc"));
+        assert!(match_glob(b"file?.txt", b"file1.txt"));
+        assert!(!match_glob(b"file?.txt", b"file.txt"));
+        assert!(match_glob(b"[a-c]x", b"bx"));
+        assert!(!match_glob(b"[!a-c]x", b"bx"));
+        assert!(match_glob(b"[]]", b"]"));
+        assert!(match_glob(b"\\*", b"*"));
+        assert!(!match_glob(b"\\*", b"a"));
+
+        // Non-ASCII characters are matched as a whole
+        assert!(match_glob("caf?".as_bytes(), "café".as_bytes()));
+        assert!(!match_glob("caf??".as_bytes(), "café".as_bytes()));
+        assert!(match_glob("caf[éè]".as_bytes(), "café".as_bytes()));
+        assert!(!match_glob("[à-ï]t[!é]".as_bytes(), "été".as_bytes()));
+        assert!(match_glob("[à-ï]t[!a]".as_bytes(), "été".as_bytes()));
+        assert!(match_glob("*?".as_bytes(), "é".as_bytes()));
+        assert!(!match_glob("*??".as_bytes(), "é".as_bytes()));
+        assert!(match_glob("\\é".as_bytes(), "é".as_bytes()));
+        // Bytes that are not valid UTF-8 are matched one at a time
+        assert!(match_glob(b"caf?", b"caf\xe9"));
+        assert!(!match_glob("caf[é]".as_bytes(), b"caf\xe9"));
+        assert!(match_glob(b"caf[!e]", b"caf\xe9"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = Filter::new();
+        filter.include("important.tmp").unwrap();
+        filter.exclude("/target/").unwrap();
+        filter.exclude("*.tmp").unwrap();
+        filter.exclude("cache/**/*.bin").unwrap();
+        filter.add_rule("- .git/").unwrap();
+        assert!(filter.add_rule("nothing").is_err());
+        assert!(filter.exclude("[abc").is_err());
+
+        let excluded = |path: &str, is_dir| {
+            filter.is_path_excluded(Path::new(path), is_dir)
+        };
+        assert!(excluded("target", true));
+        assert!(!excluded("target", false));
+        assert!(excluded("target/debug/build", false));
+        assert!(!excluded("src/important.tmp", false));
+        assert!(!excluded("src/target", true));
+        assert!(excluded("a.tmp", false));
+        assert!(excluded("src/deep/a.tmp", false));
+        assert!(excluded("src/cache/a.bin", false));
+        assert!(excluded("cache/x/y/a.bin", false));
+        assert!(!excluded("cache/a.bin.old", false));
+        assert!(excluded("sub/.git/config", false));
+        assert!(!excluded("sub/.gitignore", false));
+
+        assert_eq!(
+            filter.rules(),
+            vec![
+                "+ important.tmp",
+                "- /target/",
+                "- *.tmp",
+                "- cache/**/*.bin",
+                "- .git/",
+            ],
+        );
+
+        // The pattern given to include() or exclude() is not a rule
+        let mut filter = Filter::new();
+        filter.include("- dash").unwrap();
+        assert_eq!(filter.check(Path::new("- dash"), false), Some(false));
+        assert_eq!(filter.check(Path::new("dash"), false), None);
+        assert_eq!(filter.rules(), vec!["+ - dash"]);
+        let mut copy = Filter::new();
+        copy.add_rule(&filter.rules()[0]).unwrap();
+        assert_eq!(copy.check(Path::new("- dash"), false), Some(false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut filter = Filter::new();
+        filter.exclude("*.tmp").unwrap();
+        filter.exclude("/cache/").unwrap();
+
+        // Latin-1 names, which are not valid UTF-8
+        let excluded = |path: &[u8], is_dir| {
+            filter.is_path_excluded(Path::new(OsStr::from_bytes(path)), is_dir)
+        };
+        assert!(excluded(b"caf\xe9.tmp", false));
+        assert!(excluded(b"r\xe9pertoire/caf\xe9.tmp", false));
+        assert!(excluded(b"cache/caf\xe9", false));
+        assert!(!excluded(b"caf\xe9", false));
+        assert!(!excluded(b"r\xe9pertoire/cache/caf\xe9", false));
+    }
+}
@@ -1,24 +1,39 @@
 use cdchunking::{ChunkInput, Chunker, ZPAQ};
 use log::{debug, info, warn};
-use rusqlite;
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
 use rusqlite::types::ToSql;
 use sha1::Sha1;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
-use crate::{Error, HashDigest, temp_name};
+use crate::{
+    Error, HashDigest, MANIFEST_NAME, SINGLE_INDEX_PREFIX, TEMP_PREFIX,
+    bytes_to_path, path_to_bytes, temp_name,
+};
+use crate::filter::{Filter, IGNORE_FILE_NAME};
 
 const SCHEMA: &str = "
     CREATE TABLE files(
         file_id INTEGER NOT NULL PRIMARY KEY,
-        name VARCHAR(512) NOT NULL,
+        -- raw bytes of the path, which might not be valid UTF-8
+        name BLOB NOT NULL,
+        -- 0 = regular file, 1 = symbolic link, 2 = directory
+        kind INTEGER NOT NULL DEFAULT 0,
+        link_target BLOB NULL,
         modified DATETIME NOT NULL,
+        mode INTEGER NOT NULL,
+        -- identify hard links to the same file, if supported
+        device INTEGER NULL,
+        inode INTEGER NULL,
         size INTEGER NULL,
         blocks_hash VARCHAR(40) NULL,
         temporary BOOLEAN NOT NULL
     );
     CREATE INDEX idx_files_name ON files(name);
+    CREATE INDEX idx_files_inode ON files(device, inode);
 
     CREATE TABLE blocks(
         file_id INTEGER NOT NULL,
@@ -34,16 +49,218 @@ const SCHEMA: &str = "
     CREATE INDEX idx_blocks_present ON blocks(file_id, present);
 
     PRAGMA application_id=0x51367457;
-    PRAGMA user_version=0x00000000;
+    PRAGMA user_version=0x00000005;
 ";
 
+/// Version of the schema, has to match the `user_version` set by `SCHEMA`
+const SCHEMA_VERSION: i64 = 5;
+
 pub const ZPAQ_BITS: usize = 13; // 13 bits = 8 KiB block average
 pub const MAX_BLOCK_SIZE: usize = 1 << 15; // 32 KiB
 
+static ZEROS: [u8; MAX_BLOCK_SIZE] = [0; MAX_BLOCK_SIZE];
+
+thread_local! {
+    static ZERO_DIGESTS: RefCell<HashMap<usize, HashDigest>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Get the digest of a block of `size` zero bytes
+///
+/// Those blocks are never transferred, they are written as holes.
+pub fn zero_digest(size: usize) -> HashDigest {
+    ZERO_DIGESTS.with(|digests| {
+        digests
+            .borrow_mut()
+            .entry(size)
+            .or_insert_with(|| {
+                let mut sha1 = Sha1::new();
+                let mut left = size;
+                while left > 0 {
+                    let len = left.min(ZEROS.len());
+                    sha1.update(&ZEROS[..len]);
+                    left -= len;
+                }
+                HashDigest(sha1.digest().bytes())
+            })
+            .clone()
+    })
+}
+
+/// Cut a stream into blocks, calling `f(offset, size, digest)` for each one
+fn chunk_stream<R: Read, F: FnMut(usize, usize, HashDigest) -> Result<(), Error>>(
+    reader: R,
+    mut f: F,
+) -> Result<(), Error> {
+    // Use ZPAQ to cut the stream into blocks
+    let chunker = Chunker::new(
+        ZPAQ::new(ZPAQ_BITS), // 13 bits = 8 KiB block average
+    ).max_size(MAX_BLOCK_SIZE);
+    let mut chunk_iterator = chunker.stream(reader);
+    let mut start_offset = 0;
+    let mut offset = 0;
+    let mut sha1 = Sha1::new();
+    // Whether the current block is only zeros so far, in which case
+    // we don't hash it (yet)
+    let mut zeros = true;
+    while let Some(chunk) = chunk_iterator.read() {
+        match chunk? {
+            ChunkInput::Data(d) => {
+                if zeros && d.iter().any(|&b| b != 0) {
+                    // Hash the zeros we skipped
+                    let mut left = offset - start_offset;
+                    while left > 0 {
+                        let len = left.min(ZEROS.len());
+                        sha1.update(&ZEROS[..len]);
+                        left -= len;
+                    }
+                    zeros = false;
+                }
+                if !zeros {
+                    sha1.update(d);
+                }
+                offset += d.len();
+            }
+            ChunkInput::End => {
+                let size = offset - start_offset;
+                let digest = if zeros {
+                    zero_digest(size)
+                } else {
+                    HashDigest(sha1.digest().bytes())
+                };
+                zeros = true;
+                f(start_offset, size, digest)?;
+                start_offset = offset;
+                sha1.reset();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compute the hash of the list of blocks of a file, without indexing it
+///
+/// This is the same hash as is stored in the index as `blocks_hash`.
+pub fn file_blocks_hash(path: &Path) -> Result<HashDigest, Error> {
+    let mut sha1 = Sha1::new();
+    chunk_stream(File::open(path)?, |_offset, _size, digest| {
+        sha1.update(&digest.0);
+        Ok(())
+    })?;
+    Ok(HashDigest(sha1.digest().bytes()))
+}
+
+/// What to do with symbolic links when indexing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Record links as links, to be recreated at the destination
+    Preserve,
+    /// Follow links, indexing what they point to as if it was in the tree
+    Copy,
+    /// Ignore links entirely
+    Skip,
+    /// Record links as links, but ignore those pointing outside of the tree
+    /// (absolute, or going up too many levels with `..`)
+    SkipUnsafe,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> SymlinkPolicy {
+        SymlinkPolicy::Preserve
+    }
+}
+
+/// Options controlling how a tree is indexed
+#[derive(Clone, Debug, Default)]
+pub struct IndexOptions {
+    /// What to do with symbolic links
+    pub symlinks: SymlinkPolicy,
+    /// Which files to index, in addition to the rules found in
+    /// `.syncfastignore` files
+    pub filter: Filter,
+}
+
+/// Get the permission bits of a file
+#[cfg(unix)]
+pub(crate) fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// Get the device and inode number of a file, identifying hard links
+#[cfg(unix)]
+fn file_inode(metadata: &std::fs::Metadata) -> Option<(i64, i64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev() as i64, metadata.ino() as i64))
+}
+
+#[cfg(not(unix))]
+fn file_inode(_metadata: &std::fs::Metadata) -> Option<(i64, i64)> {
+    None
+}
+
+/// Check whether a path is excluded, by the filter from the options or by
+/// the rules read from `.syncfastignore` files in its parent directories
+fn is_excluded(
+    path: &Path,
+    is_dir: bool,
+    filter: &Filter,
+    dir_filters: &[(PathBuf, Filter)],
+) -> bool {
+    if let Some(excluded) = filter.check(path, is_dir) {
+        return excluded;
+    }
+    // Rules from deeper directories take precedence
+    for (dir, dir_filter) in dir_filters.iter().rev() {
+        let rel = path.strip_prefix(dir).unwrap();
+        if let Some(excluded) = dir_filter.check(rel, is_dir) {
+            return excluded;
+        }
+    }
+    false
+}
+
+/// Check that a symbolic link doesn't point outside of the tree
+///
+/// `name` is the path of the link relative to the root of the tree.
+fn is_link_safe(name: &Path, target: &Path) -> bool {
+    use std::path::Component;
+
+    // Start from the directory containing the link
+    let mut depth = name.components().count() as isize - 1;
+    for component in target.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return false,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            Component::Normal(_) => depth += 1,
+        }
+    }
+    true
+}
+
 /// Index of files and blocks
 pub struct Index {
     db: Connection,
     in_transaction: bool,
+    /// Changes are never committed, see `open_uncommitted()`
+    no_commit: bool,
 }
 
 impl Index {
@@ -54,15 +271,66 @@ impl Index {
         if !exists {
             warn!("Database doesn't exist, creating tables...");
             db.execute_batch(SCHEMA)?;
+        } else {
+            let version: i64 = db.query_row(
+                "PRAGMA user_version;",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            if version != SCHEMA_VERSION {
+                // The index is only a cache, it is safe to start over
+                warn!(
+                    "Database has a different version ({}), re-creating \
+                     tables...",
+                    version,
+                );
+                db.execute_batch(
+                    "
+                    DROP TABLE IF EXISTS files;
+                    DROP TABLE IF EXISTS blocks;
+                    ",
+                )?;
+                db.execute_batch(SCHEMA)?;
+            }
         }
-        Ok(Index { db, in_transaction: false })
+        Ok(Index { db, in_transaction: false, no_commit: false })
     }
 
     /// Open an in-memory index
     pub fn open_in_memory() -> Result<Index, Error> {
         let db = Connection::open_in_memory()?;
         db.execute_batch(SCHEMA)?;
-        Ok(Index { db, in_transaction: false })
+        Ok(Index { db, in_transaction: false, no_commit: false })
+    }
+
+    /// Open an index from a file, without ever changing it
+    ///
+    /// This is used for dry runs. Changes are kept in a transaction that
+    /// `commit()` doesn't end, and that is dropped with the index. If the file
+    /// doesn't exist or has a different version, it is left alone and an
+    /// empty in-memory index is used instead.
+    pub fn open_uncommitted(filename: &Path) -> Result<Index, Error> {
+        let mut index = if filename.exists() {
+            let db = Connection::open_with_flags(
+                filename,
+                OpenFlags::SQLITE_OPEN_READ_WRITE,
+            )?;
+            let version: i64 = db.query_row(
+                "PRAGMA user_version;",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            if version == SCHEMA_VERSION {
+                Index { db, in_transaction: false, no_commit: false }
+            } else {
+                Index::open_in_memory()?
+            }
+        } else {
+            Index::open_in_memory()?
+        };
+        index.no_commit = true;
+        index.begin()?;
+        Ok(index)
     }
 
     pub fn begin(&mut self) -> Result<(), Error> {
@@ -89,8 +357,8 @@ impl Index {
         let mut rows = stmt.query(&[hash as &dyn ToSql])?;
         if let Some(row) = rows.next() {
             let row = row?;
-            let path: String = row.get(0);
-            let path: PathBuf = path.into();
+            let path: Vec<u8> = row.get(0);
+            let path = bytes_to_path(path)?;
             let offset: i64 = row.get(1);
             let offset = offset as usize;
             let size: i64 = row.get(2);
@@ -102,24 +370,26 @@ impl Index {
     }
 
     /// Try to get a file from its name
+    #[allow(clippy::type_complexity)]
     pub fn get_file(
         &self,
         name: &Path,
-    ) -> Result<Option<(u32, chrono::DateTime<chrono::Utc>, HashDigest)>, Error> {
+    ) -> Result<Option<(u32, chrono::DateTime<chrono::Utc>, u32, HashDigest)>, Error> {
         let mut stmt = self.db.prepare(
             "
-            SELECT file_id, modified, blocks_hash
+            SELECT file_id, modified, mode, blocks_hash
             FROM files
-            WHERE name = ? AND temporary = 0;
+            WHERE name = ? AND temporary = 0 AND kind = 0;
             ",
         )?;
-        let mut rows = stmt.query(&[name.to_str().ok_or(Error::BadFilenameEncoding)?])?;
+        let mut rows = stmt.query(&[path_to_bytes(name)?])?;
         if let Some(row) = rows.next() {
             let row = row?;
             let file_id = row.get(0);
             let modified = row.get(1);
-            let blocks_hash = row.get(2);
-            Ok(Some((file_id, modified, blocks_hash)))
+            let mode = row.get(2);
+            let blocks_hash = row.get(3);
+            Ok(Some((file_id, modified, mode, blocks_hash)))
         } else {
             Ok(None)
         }
@@ -130,15 +400,15 @@ impl Index {
         &self,
         name: &Path,
     ) -> Result<Option<(u32, chrono::DateTime<chrono::Utc>)>, Error> {
-        let name = temp_name(&name)?;
+        let name = temp_name(name)?;
         let mut stmt = self.db.prepare(
             "
             SELECT file_id, modified
             FROM files
-            WHERE name = ? AND temporary = 1;
+            WHERE name = ? AND temporary = 1 AND kind = 0;
             ",
         )?;
-        let mut rows = stmt.query(&[name.to_str().ok_or(Error::BadFilenameEncoding)?])?;
+        let mut rows = stmt.query(&[path_to_bytes(&name)?])?;
         if let Some(row) = rows.next() {
             let row = row?;
             let file_id = row.get(0);
@@ -161,8 +431,8 @@ impl Index {
         let mut rows = stmt.query(&[file_id])?;
         if let Some(row) = rows.next() {
             let row = row?;
-            let path: String = row.get(0);
-            Ok(Some(path.into()))
+            let path: Vec<u8> = row.get(0);
+            Ok(Some(bytes_to_path(path)?))
         } else {
             Ok(None)
         }
@@ -173,13 +443,17 @@ impl Index {
     /// This returns a tuple `(file_id, up_to_date)` where `file_id` can be
     /// used to insert blocks, and `up_to_date` indicates whether the file's
     /// modification date has changed and it should be re-indexed.
+    ///
+    /// The mode is always updated, since changing it doesn't change the
+    /// modification date.
     pub fn add_file(
         &mut self,
         name: &Path,
         modified: chrono::DateTime<chrono::Utc>,
+        mode: u32,
     ) -> Result<(u32, bool), Error> {
         self.begin()?;
-        if let Some((file_id, old_modified, _)) = self.get_file(name)? {
+        if let Some((file_id, old_modified, old_mode, _)) = self.get_file(name)? {
             if old_modified != modified {
                 info!("Resetting file {:?}, modified", name);
                 // Delete blocks
@@ -193,30 +467,205 @@ impl Index {
                 self.db.execute(
                     "
                     UPDATE files
-                    SET modified = ?, size = NULL, blocks_hash = NULL, temporary = 0
+                    SET modified = ?, mode = ?, size = NULL, blocks_hash = NULL, temporary = 0
                     WHERE file_id = ?;
                     ",
-                    &[&modified as &dyn ToSql, &file_id],
+                    &[&modified as &dyn ToSql, &mode, &file_id],
                 )?;
                 Ok((file_id, false))
             } else {
                 debug!("File {:?} up to date", name);
+                if old_mode != mode {
+                    debug!("Updating mode of file {:?}", name);
+                    self.db.execute(
+                        "
+                        UPDATE files SET mode = ? WHERE file_id = ?;
+                        ",
+                        &[&mode, &file_id],
+                    )?;
+                }
                 Ok((file_id, true))
             }
         } else {
             info!("Inserting new file {:?}", name);
+            let name = path_to_bytes(name)?;
+            // Remove entry of a different kind, if any
             self.db.execute(
                 "
-                INSERT INTO files(name, modified, temporary)
-                VALUES(?, ?, 0);
+                DELETE FROM files WHERE name = ? AND temporary = 0;
                 ",
-                &[&name.to_str().ok_or(Error::BadFilenameEncoding)? as &dyn ToSql, &modified],
+                &[name],
+            )?;
+            self.db.execute(
+                "
+                INSERT INTO files(name, modified, mode, temporary)
+                VALUES(?, ?, ?, 0);
+                ",
+                &[&name as &dyn ToSql, &modified, &mode],
             )?;
             let file_id = self.db.last_insert_rowid();
             Ok((file_id as u32, false))
         }
     }
 
+    /// Add a symbolic link to the index
+    pub fn add_symlink(
+        &mut self,
+        name: &Path,
+        target: &Path,
+        modified: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), Error> {
+        self.begin()?;
+        let target = path_to_bytes(target)?;
+        if let Some((file_id, old_target)) = self.get_symlink(name)? {
+            if path_to_bytes(&old_target)? == target {
+                debug!("Symlink {:?} up to date", name);
+                return Ok(());
+            }
+            self.remove_file(file_id)?;
+        } else if let Some((file_id, _, _, _)) = self.get_file(name)? {
+            self.remove_file(file_id)?;
+        } else if let Some((file_id, _, _)) = self.get_directory(name)? {
+            self.remove_file(file_id)?;
+        }
+        info!("Inserting new symlink {:?}", name);
+        self.db.execute(
+            "
+            INSERT INTO files(name, kind, link_target, modified, mode, temporary)
+            VALUES(?, 1, ?, ?, 511, 0);
+            ",
+            &[
+                &path_to_bytes(name)? as &dyn ToSql,
+                &target,
+                &modified,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Try to get a symbolic link from its name
+    pub fn get_symlink(
+        &self,
+        name: &Path,
+    ) -> Result<Option<(u32, PathBuf)>, Error> {
+        let mut stmt = self.db.prepare(
+            "
+            SELECT file_id, link_target
+            FROM files
+            WHERE name = ? AND temporary = 0 AND kind = 1;
+            ",
+        )?;
+        let mut rows = stmt.query(&[path_to_bytes(name)?])?;
+        if let Some(row) = rows.next() {
+            let row = row?;
+            let file_id = row.get(0);
+            let target: Vec<u8> = row.get(1);
+            Ok(Some((file_id, bytes_to_path(target)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Add a directory to the index, or update its metadata
+    pub fn add_directory(
+        &mut self,
+        name: &Path,
+        modified: chrono::DateTime<chrono::Utc>,
+        mode: u32,
+    ) -> Result<(), Error> {
+        self.begin()?;
+        if let Some((file_id, old_modified, old_mode)) = self.get_directory(name)? {
+            if old_modified != modified || old_mode != mode {
+                debug!("Updating metadata of directory {:?}", name);
+                self.set_file_metadata(file_id, modified, mode)?;
+            }
+            return Ok(());
+        }
+        info!("Inserting new directory {:?}", name);
+        let name = path_to_bytes(name)?;
+        // Remove entry of a different kind, if any
+        self.db.execute(
+            "
+            DELETE FROM blocks WHERE file_id IN (
+                SELECT file_id
+                FROM files
+                WHERE name = ? AND temporary = 0
+            );
+            ",
+            &[name],
+        )?;
+        self.db.execute(
+            "
+            DELETE FROM files WHERE name = ? AND temporary = 0;
+            ",
+            &[name],
+        )?;
+        self.db.execute(
+            "
+            INSERT INTO files(name, kind, modified, mode, temporary)
+            VALUES(?, 2, ?, ?, 0);
+            ",
+            &[&name as &dyn ToSql, &modified, &mode],
+        )?;
+        Ok(())
+    }
+
+    /// Try to get a directory from its name
+    pub fn get_directory(
+        &self,
+        name: &Path,
+    ) -> Result<Option<(u32, chrono::DateTime<chrono::Utc>, u32)>, Error> {
+        let mut stmt = self.db.prepare(
+            "
+            SELECT file_id, modified, mode
+            FROM files
+            WHERE name = ? AND temporary = 0 AND kind = 2;
+            ",
+        )?;
+        let mut rows = stmt.query(&[path_to_bytes(name)?])?;
+        if let Some(row) = rows.next() {
+            let row = row?;
+            Ok(Some((row.get(0), row.get(1), row.get(2))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Add a temporary symbolic link to the index
+    ///
+    /// It will be created and moved into place along with the temporary
+    /// files.
+    pub fn add_temp_symlink(
+        &mut self,
+        name: &Path,
+        target: &Path,
+    ) -> Result<u32, Error> {
+        self.begin()?;
+        let modified: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+        let name = temp_name(name)?;
+        let name = path_to_bytes(&name)?;
+        self.db.execute(
+            "
+            DELETE FROM files WHERE name = ? AND temporary = 1;
+            ",
+            &[name],
+        )?;
+        info!("Inserting new symlink {:?}", name);
+        self.db.execute(
+            "
+            INSERT INTO files(name, kind, link_target, modified, mode, temporary)
+            VALUES(?, 1, ?, ?, 511, 1);
+            ",
+            &[
+                &name as &dyn ToSql,
+                &path_to_bytes(target)?,
+                &modified,
+            ],
+        )?;
+        let file_id = self.db.last_insert_rowid();
+        Ok(file_id as u32)
+    }
+
     /// Replace file in the index
     ///
     /// This is like add_file but will always replace an existing file.
@@ -224,9 +673,10 @@ impl Index {
         &mut self,
         name: &Path,
         modified: chrono::DateTime<chrono::Utc>,
+        mode: u32,
     ) -> Result<u32, Error> {
         self.begin()?;
-        if let Some((file_id, _, _)) = self.get_file(name)? {
+        if let Some((file_id, _, _, _)) = self.get_file(name)? {
             info!("Resetting file {:?}", name);
             // Delete blocks
             self.db.execute(
@@ -239,34 +689,73 @@ impl Index {
             self.db.execute(
                 "
                 UPDATE files
-                SET modified = ?, size = NULL, blocks_hash = NULL, temporary = 0
+                SET modified = ?, mode = ?, size = NULL, blocks_hash = NULL, temporary = 0
                 WHERE file_id = ?;
                 ",
-                &[&modified as &dyn ToSql, &file_id],
+                &[&modified as &dyn ToSql, &mode, &file_id],
             )?;
             Ok(file_id)
         } else {
             info!("Inserting new file {:?}", name);
             self.db.execute(
                 "
-                INSERT INTO files(name, modified, temporary)
-                VALUES(?, ?, 0);
+                INSERT INTO files(name, modified, mode, temporary)
+                VALUES(?, ?, ?, 0);
                 ",
-                &[&name.to_str().ok_or(Error::BadFilenameEncoding)? as &dyn ToSql, &modified],
+                &[&path_to_bytes(name)? as &dyn ToSql, &modified, &mode],
             )?;
             let file_id = self.db.last_insert_rowid();
             Ok(file_id as u32)
         }
     }
 
+    /// Add a temporary file to the index
+    ///
+    /// The modification time and mode are the ones the file should be given
+    /// once it is complete and moved into place, and `blocks_hash` the one
+    /// announced by the source.
+    ///
+    /// If a temporary file for the same content is already recorded with its
+    /// full list of blocks, for example from an interrupted sync, it is kept
+    /// along with the blocks already received, unless `keep_blocks` is false.
+    /// This returns a tuple `(file_id, resumed)`.
     pub fn add_temp_file(
         &mut self,
         name: &Path,
-    ) -> Result<u32, Error> {
+        modified: chrono::DateTime<chrono::Utc>,
+        mode: u32,
+        blocks_hash: &HashDigest,
+        keep_blocks: bool,
+    ) -> Result<(u32, bool), Error> {
         self.begin()?;
-        let modified: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
         let name = temp_name(name)?;
-        if let Some((file_id, _, _)) = self.get_file(&name)? {
+        let name = path_to_bytes(&name)?;
+        let existing: Option<(u32, bool, Option<HashDigest>)> = {
+            let mut stmt = self.db.prepare(
+                "
+                SELECT file_id, size IS NOT NULL, blocks_hash
+                FROM files
+                WHERE name = ? AND temporary = 1 AND kind = 0;
+                ",
+            )?;
+            let mut rows = stmt.query(&[name])?;
+            match rows.next() {
+                Some(row) => {
+                    let row = row?;
+                    Some((row.get(0), row.get(1), row.get(2)))
+                }
+                None => None,
+            }
+        };
+        if let Some((file_id, complete, old_blocks_hash)) = existing {
+            if keep_blocks
+                && complete
+                && old_blocks_hash.as_ref() == Some(blocks_hash)
+            {
+                info!("Resuming file {:?}", name);
+                self.set_file_metadata(file_id, modified, mode)?;
+                return Ok((file_id, true));
+            }
             info!("Resetting file {:?}", name);
             // Delete blocks
             self.db.execute(
@@ -279,23 +768,23 @@ impl Index {
             self.db.execute(
                 "
                 UPDATE files
-                SET modified = ?, size = NULL, blocks_hash = NULL, temporary = 1
+                SET modified = ?, mode = ?, size = NULL, blocks_hash = ?, temporary = 1
                 WHERE file_id = ?;
                 ",
-                &[&modified as &dyn ToSql, &file_id],
+                &[&modified as &dyn ToSql, &mode, blocks_hash, &file_id],
             )?;
-            Ok(file_id)
+            Ok((file_id, false))
         } else {
             info!("Inserting new file {:?}", name);
             self.db.execute(
                 "
-                INSERT INTO files(name, modified, temporary)
-                VALUES(?, ?, 1);
+                INSERT INTO files(name, modified, mode, blocks_hash, temporary)
+                VALUES(?, ?, ?, ?, 1);
                 ",
-                &[&name.to_str().ok_or(Error::BadFilenameEncoding)? as &dyn ToSql, &modified],
+                &[&name as &dyn ToSql, &modified, &mode, blocks_hash],
             )?;
             let file_id = self.db.last_insert_rowid();
-            Ok(file_id as u32)
+            Ok((file_id as u32, false))
         }
     }
 
@@ -317,6 +806,155 @@ impl Index {
         Ok(())
     }
 
+    /// Record the device and inode number of a file
+    fn set_file_inode(
+        &mut self,
+        file_id: u32,
+        inode: Option<(i64, i64)>,
+    ) -> Result<(), Error> {
+        self.begin()?;
+        let (device, inode) = match inode {
+            Some((d, i)) => (Some(d), Some(i)),
+            None => (None, None),
+        };
+        self.db.execute(
+            "
+            UPDATE files SET device = ?, inode = ?
+            WHERE file_id = ?;
+            ",
+            &[&device as &dyn ToSql, &inode, &file_id],
+        )?;
+        Ok(())
+    }
+
+    /// Copy the blocks of a file to another entry, which has the same content
+    fn copy_blocks(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        self.begin()?;
+        self.db.execute(
+            "
+            INSERT INTO blocks(hash, file_id, offset, size, present)
+            SELECT hash, ?, offset, size, present
+            FROM blocks
+            WHERE file_id = ?;
+            ",
+            &[&to, &from],
+        )?;
+        self.db.execute(
+            "
+            UPDATE files SET
+                size = (SELECT size FROM files WHERE file_id = ?),
+                blocks_hash = (SELECT blocks_hash FROM files WHERE file_id = ?)
+            WHERE file_id = ?;
+            ",
+            &[&from, &from, &to],
+        )?;
+        Ok(())
+    }
+
+    /// Add a file to the index as a hard link to another, copying its blocks
+    pub fn add_hard_link(
+        &mut self,
+        name: &Path,
+        target: &Path,
+    ) -> Result<(), Error> {
+        self.begin()?;
+        let target_id = match self.get_file(target)? {
+            Some((file_id, _, _, _)) => file_id,
+            None => {
+                return Err(Error::Sync(format!(
+                    "Hard link target {:?} is not in the index",
+                    target,
+                )));
+            }
+        };
+        info!("Inserting hard link {:?} to {:?}", name, target);
+        let name = path_to_bytes(name)?;
+        // Remove previous entry, if any
+        self.db.execute(
+            "
+            DELETE FROM blocks WHERE file_id IN (
+                SELECT file_id
+                FROM files
+                WHERE name = ? AND temporary = 0
+            );
+            ",
+            &[name],
+        )?;
+        self.db.execute(
+            "
+            DELETE FROM files WHERE name = ? AND temporary = 0;
+            ",
+            &[name],
+        )?;
+        self.db.execute(
+            "
+            INSERT INTO files(name, kind, modified, mode, device, inode, temporary)
+            SELECT ?, kind, modified, mode, device, inode, 0
+            FROM files
+            WHERE file_id = ?;
+            ",
+            &[&name as &dyn ToSql, &target_id],
+        )?;
+        let file_id = self.db.last_insert_rowid() as u32;
+        self.copy_blocks(target_id, file_id)
+    }
+
+    /// Get the files that are hard links to another file in the index
+    ///
+    /// This returns `(name, target)` pairs, where the target is the first of
+    /// the linked files by name; the target itself is not included.
+    pub fn list_hard_links(&self) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+        let mut stmt = self.db.prepare(
+            "
+            SELECT name, target
+            FROM (
+                SELECT f.name AS name, (
+                    SELECT MIN(g.name)
+                    FROM files g
+                    WHERE g.device = f.device AND g.inode = f.inode
+                        AND g.temporary = 0 AND g.kind = 0
+                ) AS target
+                FROM files f
+                WHERE f.temporary = 0 AND f.kind = 0
+                    AND f.device IS NOT NULL AND f.inode IS NOT NULL
+            )
+            WHERE name != target;
+            ",
+        )?;
+        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+        let mut results = Vec::new();
+        loop {
+            match rows.next() {
+                Some(Ok(row)) => {
+                    let name: Vec<u8> = row.get(0);
+                    let target: Vec<u8> = row.get(1);
+                    results.push((bytes_to_path(name)?, bytes_to_path(target)?));
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        Ok(results)
+    }
+
+    /// Update the modification time and mode of a file
+    pub fn set_file_metadata(
+        &mut self,
+        file_id: u32,
+        modified: chrono::DateTime<chrono::Utc>,
+        mode: u32,
+    ) -> Result<(), Error> {
+        self.begin()?;
+        self.db.execute(
+            "
+            UPDATE files SET modified = ?, mode = ?
+            WHERE file_id = ?;
+            ",
+            &[&modified as &dyn ToSql, &mode, &file_id],
+        )?;
+        Ok(())
+    }
+
     /// Move a file, possibly over another
     pub fn move_temp_file_into_place(
         &mut self,
@@ -324,7 +962,7 @@ impl Index {
         destination: &Path,
     ) -> Result<(), Error> {
         self.begin()?;
-        let destination = destination.to_str().ok_or(Error::BadFilenameEncoding)?;
+        let destination = path_to_bytes(destination)?;
 
         // Delete old file
         self.db.execute(
@@ -356,15 +994,83 @@ impl Index {
     }
 
     /// Get a list of all the files in the index
+    #[allow(clippy::type_complexity)]
     pub fn list_files(
         &self,
-    ) -> Result<Vec<(u32, PathBuf, chrono::DateTime<chrono::Utc>, usize, HashDigest)>, Error>
+    ) -> Result<Vec<(u32, PathBuf, chrono::DateTime<chrono::Utc>, u32, usize, HashDigest)>, Error>
+    {
+        let mut stmt = self.db.prepare(
+            "
+            SELECT file_id, name, modified, mode, size, blocks_hash
+            FROM files
+            WHERE temporary = 0 AND kind = 0;
+            ",
+        )?;
+        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+        let mut results = Vec::new();
+        loop {
+            match rows.next() {
+                Some(Ok(row)) => {
+                    let path: Vec<u8> = row.get(1);
+                    let size: Option<i64> = row.get(4);
+                    results.push((row.get(0), bytes_to_path(path)?, row.get(2), row.get(3), size.unwrap_or(0) as usize, row.get(5)))
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        Ok(results)
+    }
+
+    /// Get a list of all the symbolic links in the index, with their targets
+    pub fn list_symlinks(&self) -> Result<Vec<(u32, PathBuf, PathBuf)>, Error> {
+        self.list_symlinks_(false)
+    }
+
+    /// Get a list of the temporary symbolic links, with their targets
+    pub fn list_temp_symlinks(&self) -> Result<Vec<(u32, PathBuf, PathBuf)>, Error> {
+        self.list_symlinks_(true)
+    }
+
+    fn list_symlinks_(
+        &self,
+        temporary: bool,
+    ) -> Result<Vec<(u32, PathBuf, PathBuf)>, Error> {
+        let mut stmt = self.db.prepare(
+            "
+            SELECT file_id, name, link_target
+            FROM files
+            WHERE temporary = ? AND kind = 1;
+            ",
+        )?;
+        let mut rows = stmt.query(&[temporary])?;
+        let mut results = Vec::new();
+        loop {
+            match rows.next() {
+                Some(Ok(row)) => {
+                    let name: Vec<u8> = row.get(1);
+                    let target: Vec<u8> = row.get(2);
+                    results.push((row.get(0), bytes_to_path(name)?, bytes_to_path(target)?));
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        Ok(results)
+    }
+
+    /// Get a list of all the directories in the index, parents first
+    #[allow(clippy::type_complexity)]
+    pub fn list_directories(
+        &self,
+    ) -> Result<Vec<(u32, PathBuf, chrono::DateTime<chrono::Utc>, u32)>, Error>
     {
         let mut stmt = self.db.prepare(
             "
-            SELECT file_id, name, modified, size, blocks_hash
+            SELECT file_id, name, modified, mode
             FROM files
-            WHERE temporary = 0;
+            WHERE temporary = 0 AND kind = 2
+            ORDER BY name;
             ",
         )?;
         let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
@@ -372,9 +1078,8 @@ impl Index {
         loop {
             match rows.next() {
                 Some(Ok(row)) => {
-                    let path: String = row.get(1);
-                    let size: Option<i64> = row.get(3);
-                    results.push((row.get(0), path.into(), row.get(2), size.unwrap_or(0) as usize, row.get(4)))
+                    let name: Vec<u8> = row.get(1);
+                    results.push((row.get(0), bytes_to_path(name)?, row.get(2), row.get(3)));
                 }
                 Some(Err(e)) => return Err(e.into()),
                 None => break,
@@ -431,14 +1136,30 @@ impl Index {
         Ok(())
     }
 
-    pub fn set_file_size_and_compute_blocks_hash(
+    /// Set the size of a file once its list of blocks has been received
+    ///
+    /// This returns false if the blocks don't match the `blocks_hash` the
+    /// file was announced with.
+    pub fn set_file_size_and_check_blocks_hash(
         &mut self,
         file_id: u32,
         size: usize,
-    ) -> Result<(), Error> {
+    ) -> Result<bool, Error> {
         self.begin()?;
 
         let blocks_hash = self.compute_blocks_hash(file_id)?;
+        let expected: Option<HashDigest> = self.db.query_row(
+            "
+            SELECT blocks_hash FROM files WHERE file_id = ?;
+            ",
+            &[&file_id],
+            |row| row.get(0),
+        )?;
+        if let Some(expected) = expected {
+            if expected != blocks_hash {
+                return Ok(false);
+            }
+        }
         self.db.execute(
             "
             UPDATE files
@@ -447,7 +1168,7 @@ impl Index {
             ",
             &[&(size as i64) as &dyn ToSql, &blocks_hash, &file_id],
         )?;
-        Ok(())
+        Ok(true)
     }
 
     /// Get a list of all the blocks in a specific file
@@ -459,7 +1180,8 @@ impl Index {
             "
             SELECT hash, offset, size
             FROM blocks
-            WHERE file_id = ?;
+            WHERE file_id = ?
+            ORDER BY offset;
             ",
         )?;
         let mut rows = stmt.query(&[file_id])?;
@@ -478,13 +1200,14 @@ impl Index {
         Ok(results)
     }
 
-    /// Get a list of temporary files
-    pub fn list_temp_files(&self) -> Result<Vec<PathBuf>, Error> {
+    /// Get a list of temporary files, and whether their list of blocks is
+    /// complete
+    pub fn list_temp_files(&self) -> Result<Vec<(u32, PathBuf, bool)>, Error> {
         let mut stmt = self.db.prepare(
             "
-            SELECT name
+            SELECT file_id, name, size IS NOT NULL
             FROM files
-            WHERE temporary = 1;
+            WHERE temporary = 1 AND kind = 0;
             ",
         )?;
         let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
@@ -492,8 +1215,8 @@ impl Index {
         loop {
             match rows.next() {
                 Some(Ok(row)) => {
-                    let name: String = row.get(0);
-                    results.push(name.into());
+                    let name: Vec<u8> = row.get(1);
+                    results.push((row.get(0), bytes_to_path(name)?, row.get(2)));
                 }
                 Some(Err(e)) => return Err(e.into()),
                 None => break,
@@ -502,18 +1225,23 @@ impl Index {
         Ok(results)
     }
 
-    pub fn check_temp_files(&self) -> Result<Vec<(u32, PathBuf, bool)>, Error> {
+    /// Get the temporary files, with their target metadata, and whether they
+    /// are missing blocks
+    #[allow(clippy::type_complexity)]
+    pub fn check_temp_files(
+        &self,
+    ) -> Result<Vec<(u32, PathBuf, chrono::DateTime<chrono::Utc>, u32, Option<HashDigest>, bool)>, Error> {
         let mut stmt = self.db.prepare(
             "
             SELECT
-                file_id, name,
+                file_id, name, modified, mode, blocks_hash,
                 EXISTS (
                     SELECT hash FROM blocks
                     WHERE blocks.file_id = files.file_id
                         AND present = 0
                 ) AS missing
             FROM files
-            WHERE temporary = 1;
+            WHERE temporary = 1 AND kind = 0;
             ",
         )?;
         let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
@@ -522,9 +1250,12 @@ impl Index {
             match rows.next() {
                 Some(Ok(row)) => {
                     let file_id = row.get(0);
-                    let name: String = row.get(1);
-                    let missing_blocks = row.get(2);
-                    results.push((file_id, name.into(), missing_blocks));
+                    let name: Vec<u8> = row.get(1);
+                    let modified = row.get(2);
+                    let mode = row.get(3);
+                    let blocks_hash = row.get(4);
+                    let missing_blocks = row.get(5);
+                    results.push((file_id, bytes_to_path(name)?, modified, mode, blocks_hash, missing_blocks));
                 }
                 Some(Err(e)) => return Err(e.into()),
                 None => break,
@@ -534,10 +1265,12 @@ impl Index {
     }
 
     /// Get a list of blocks that are referenced by files but not present
+    ///
+    /// Each hash is listed once, even if it is missing in multiple places.
     pub fn list_missing_blocks(&self) -> Result<Vec<HashDigest>, Error> {
         let mut stmt = self.db.prepare(
             "
-            SELECT hash
+            SELECT DISTINCT hash
             FROM blocks
             WHERE present = 0;
             ",
@@ -557,6 +1290,30 @@ impl Index {
         Ok(results)
     }
 
+    /// Get a list of the blocks that are present, each hash listed once
+    pub fn list_present_blocks(&self) -> Result<Vec<HashDigest>, Error> {
+        let mut stmt = self.db.prepare(
+            "
+            SELECT DISTINCT hash
+            FROM blocks
+            WHERE present = 1;
+            ",
+        )?;
+        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+        let mut results = Vec::new();
+        loop {
+            match rows.next() {
+                Some(Ok(row)) => {
+                    let hash: HashDigest = row.get(0);
+                    results.push(hash);
+                },
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        Ok(results)
+    }
+
     /// Get all locations where a block is to be found
     pub fn list_block_locations(
         &self,
@@ -576,10 +1333,10 @@ impl Index {
             match rows.next() {
                 Some(Ok(row)) => {
                     let file_id = row.get(0);
-                    let name: String = row.get(1);
+                    let name: Vec<u8> = row.get(1);
                     let offset: i64 = row.get(2);
                     let size: i64 = row.get(3);
-                    results.push((file_id, name.into(), offset as usize, size as usize));
+                    results.push((file_id, bytes_to_path(name)?, offset as usize, size as usize));
                 }
                 Some(Err(e)) => return Err(e.into()),
                 None => break,
@@ -613,39 +1370,63 @@ impl Index {
         name: &Path,
     ) -> Result<(), Error> {
         let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let modified: chrono::DateTime<chrono::Utc> =
+            metadata.modified()?.into();
         let (file_id, up_to_date) = self.add_file(
             name,
-            file.metadata()?.modified()?.into(),
+            modified,
+            file_mode(&metadata),
         )?;
+        // Only record the inode of the file itself, not of the target of a
+        // symbolic link we followed, which is not a hard link to it
+        let link_metadata = std::fs::symlink_metadata(path)?;
+        let inode = if link_metadata.file_type().is_file() {
+            file_inode(&link_metadata)
+        } else {
+            None
+        };
+        self.set_file_inode(file_id, inode)?;
         if !up_to_date {
-            // Use ZPAQ to cut the stream into blocks
-            let chunker = Chunker::new(
-                ZPAQ::new(ZPAQ_BITS), // 13 bits = 8 KiB block average
-            ).max_size(MAX_BLOCK_SIZE);
-            let mut chunk_iterator = chunker.stream(file);
-            let mut start_offset = 0;
-            let mut offset = 0;
-            let mut sha1 = Sha1::new();
-            while let Some(chunk) = chunk_iterator.read() {
-                match chunk? {
-                    ChunkInput::Data(d) => {
-                        sha1.update(d);
-                        offset += d.len();
-                    }
-                    ChunkInput::End => {
-                        let digest = HashDigest(sha1.digest().bytes());
-                        let size = offset - start_offset;
-                        debug!(
-                            "Adding block, offset={}, size={}, sha1={}",
-                            start_offset, size, digest,
-                        );
-                        self.add_block(&digest, file_id, start_offset, size)?;
-                        start_offset = offset;
-                        sha1.reset();
-                    }
+            // If this is a hard link to a file we already indexed, re-use
+            // its blocks instead of reading it again
+            if let Some((device, inode)) = inode {
+                let mut stmt = self.db.prepare(
+                    "
+                    SELECT file_id
+                    FROM files
+                    WHERE device = ? AND inode = ? AND file_id != ?
+                        AND modified = ? AND blocks_hash IS NOT NULL
+                        AND temporary = 0 AND kind = 0;
+                    ",
+                )?;
+                let mut rows = stmt.query(&[
+                    &device as &dyn ToSql,
+                    &inode,
+                    &file_id,
+                    &modified,
+                ])?;
+                let other: Option<u32> = match rows.next() {
+                    Some(row) => Some(row?.get(0)),
+                    None => None,
+                };
+                drop(rows);
+                drop(stmt);
+                if let Some(other) = other {
+                    debug!("File {:?} is a hard link, copying blocks", name);
+                    return self.copy_blocks(other, file_id);
                 }
             }
 
+
+            chunk_stream(file, |offset, size, digest| {
+                debug!(
+                    "Adding block, offset={}, size={}, sha1={}",
+                    offset, size, digest,
+                );
+                self.add_block(&digest, file_id, offset, size)
+            })?;
+
             // Set blocks_hash
             let blocks_digest = self.compute_blocks_hash(file_id)?;
             self.db.execute(
@@ -664,7 +1445,8 @@ impl Index {
             "
             SELECT hash
             FROM blocks
-            WHERE file_id = ?;
+            WHERE file_id = ?
+            ORDER BY offset;
             ",
         )?;
         let mut rows = stmt.query(&[file_id])?;
@@ -683,25 +1465,145 @@ impl Index {
 
     /// Index files and directories recursively
     pub fn index_path(&mut self, path: &Path) -> Result<(), Error> {
-        self.index_path_rec(path, Path::new(""))
+        self.index_path_with_options(path, &Default::default())
+    }
+
+    /// Index files and directories recursively, with the given options
+    pub fn index_path_with_options(
+        &mut self,
+        path: &Path,
+        options: &IndexOptions,
+    ) -> Result<(), Error> {
+        self.index_path_rec(
+            path,
+            Path::new(""),
+            options,
+            &mut Vec::new(),
+            &mut Vec::new(),
+        )
     }
 
     fn index_path_rec(
         &mut self,
         root: &Path,
         rel: &Path,
+        options: &IndexOptions,
+        parent_dirs: &mut Vec<PathBuf>,
+        dir_filters: &mut Vec<(PathBuf, Filter)>,
     ) -> Result<(), Error> {
         let path = root.join(rel);
-        if path.is_dir() {
-            info!("Indexing directory {:?} ({:?})", rel, path);
-            for entry in path.read_dir()? {
-                if let Ok(entry) = entry {
-                    if entry.file_name() == ".syncfast.idx" {
-                        continue;
+        // The root is always followed if it is a link
+        let mut metadata = if rel.as_os_str().is_empty() {
+            std::fs::metadata(&path)?
+        } else {
+            std::fs::symlink_metadata(&path)?
+        };
+        if !rel.as_os_str().is_empty() {
+            let is_dir = if metadata.file_type().is_symlink() {
+                options.symlinks == SymlinkPolicy::Copy && path.is_dir()
+            } else {
+                metadata.is_dir()
+            };
+            if is_excluded(rel, is_dir, &options.filter, dir_filters) {
+                info!("Excluding {:?}", rel);
+                return self.remove_tree(rel);
+            }
+        }
+        if metadata.file_type().is_symlink() {
+            match options.symlinks {
+                SymlinkPolicy::Skip => {
+                    info!("Skipping symlink {:?}", rel);
+                    return self.forget_symlink(rel);
+                }
+                SymlinkPolicy::Preserve | SymlinkPolicy::SkipUnsafe => {
+                    let target = std::fs::read_link(&path)?;
+                    if options.symlinks == SymlinkPolicy::SkipUnsafe
+                        && !is_link_safe(rel, &target)
+                    {
+                        warn!(
+                            "Skipping symlink {:?} pointing outside the \
+                             tree",
+                            rel,
+                        );
+                        return self.forget_symlink(rel);
                     }
-                    self.index_path_rec(root, &rel.join(entry.file_name()))?;
+                    info!("Indexing symlink {:?} ({:?})", rel, path);
+                    return self.add_symlink(
+                        rel,
+                        &target,
+                        metadata.modified()?.into(),
+                    );
+                }
+                SymlinkPolicy::Copy => {
+                    metadata = match std::fs::metadata(&path) {
+                        Ok(m) => m,
+                        Err(e) => {
+                            warn!("Skipping broken symlink {:?}: {}", rel, e);
+                            return Ok(());
+                        }
+                    };
                 }
             }
+        }
+        if metadata.is_dir() {
+            info!("Indexing directory {:?} ({:?})", rel, path);
+            // Following links might lead us in a loop
+            let canonical = if options.symlinks == SymlinkPolicy::Copy {
+                let canonical = path.canonicalize()?;
+                if parent_dirs.contains(&canonical) {
+                    warn!("Skipping directory {:?}, symlink loop", rel);
+                    return Ok(());
+                }
+                Some(canonical)
+            } else {
+                None
+            };
+            if let Some(canonical) = canonical {
+                parent_dirs.push(canonical);
+            }
+            // The root itself is not recorded
+            if !rel.as_os_str().is_empty() {
+                self.add_directory(
+                    rel,
+                    metadata.modified()?.into(),
+                    file_mode(&metadata),
+                )?;
+            }
+            // Read the rules for this directory, if any
+            let ignore_file = path.join(IGNORE_FILE_NAME);
+            let has_filter = ignore_file.is_file();
+            if has_filter {
+                debug!("Reading rules from {:?}", ignore_file);
+                dir_filters.push((rel.to_owned(), Filter::from_file(&ignore_file)?));
+            }
+            for entry in path.read_dir()?.flatten() {
+                if entry.file_name() == ".syncfast.idx"
+                    || entry.file_name() == MANIFEST_NAME
+                {
+                    continue;
+                }
+                // Temporary files are tracked by the sync, not indexed
+                let file_name = entry.file_name();
+                let name = path_to_bytes(Path::new(&file_name))?;
+                if name.starts_with(TEMP_PREFIX.as_bytes())
+                    || name.starts_with(SINGLE_INDEX_PREFIX.as_bytes())
+                {
+                    continue;
+                }
+                self.index_path_rec(
+                    root,
+                    &rel.join(entry.file_name()),
+                    options,
+                    parent_dirs,
+                    dir_filters,
+                )?;
+            }
+            if has_filter {
+                dir_filters.pop();
+            }
+            if options.symlinks == SymlinkPolicy::Copy {
+                parent_dirs.pop();
+            }
             Ok(())
         } else {
             let rel = if rel.starts_with(".") {
@@ -710,24 +1612,84 @@ impl Index {
                 rel
             };
             info!("Indexing file {:?} ({:?})", rel, path);
-            self.index_file(&path, &rel)
+            self.index_file(&path, rel)
+        }
+    }
+
+    /// Remove an entry and everything under it from the index
+    fn remove_tree(&mut self, name: &Path) -> Result<(), Error> {
+        self.begin()?;
+        let name = path_to_bytes(name)?;
+        let mut prefix = name.to_owned();
+        prefix.push(b'/');
+        let prefix_len = prefix.len() as i64;
+        self.db.execute(
+            "
+            DELETE FROM blocks WHERE file_id IN (
+                SELECT file_id
+                FROM files
+                WHERE name = ? OR substr(name, 1, ?) = ?
+            );
+            ",
+            &[&name as &dyn ToSql, &prefix_len, &prefix],
+        )?;
+        self.db.execute(
+            "
+            DELETE FROM files WHERE name = ? OR substr(name, 1, ?) = ?;
+            ",
+            &[&name as &dyn ToSql, &prefix_len, &prefix],
+        )?;
+        Ok(())
+    }
+
+    /// Remove a symlink from the index, if it was recorded before
+    fn forget_symlink(&mut self, name: &Path) -> Result<(), Error> {
+        if let Some((file_id, _target)) = self.get_symlink(name)? {
+            self.remove_file(file_id)?;
         }
+        Ok(())
     }
 
     /// List all files and remove those that don't exist on disk
     pub fn remove_missing_files(&mut self, path: &Path) -> Result<(), Error> {
-        for (file_id, file_path, _modified, _size, _blocks_hash) in self.list_files()? {
+        for (file_id, file_path, _modified, _mode, _size, _blocks_hash) in self.list_files()? {
             if !path.join(&file_path).is_file() {
                 info!("Removing missing file {:?}", file_path);
                 self.remove_file(file_id)?;
             }
         }
+        for (file_id, link_path, _target) in self.list_symlinks()? {
+            let is_link = std::fs::symlink_metadata(path.join(&link_path))
+                .map(|m| m.file_type().is_symlink())
+                .unwrap_or(false);
+            if !is_link {
+                info!("Removing missing symlink {:?}", link_path);
+                self.remove_file(file_id)?;
+            }
+        }
+        for (file_id, dir_path, _modified, _mode) in self.list_directories()? {
+            if !path.join(&dir_path).is_dir() {
+                info!("Removing missing directory {:?}", dir_path);
+                self.remove_file(file_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Abort the transaction, dropping the changes made since `begin()`
+    pub fn rollback(&mut self) -> Result<(), rusqlite::Error> {
+        if self.in_transaction {
+            self.db.execute_batch("ROLLBACK")?;
+            self.in_transaction = false;
+        }
         Ok(())
     }
 
     /// Commit the transaction
+    ///
+    /// This does nothing if the index was opened with `open_uncommitted()`.
     pub fn commit(&mut self) -> Result<(), rusqlite::Error> {
-        if self.in_transaction {
+        if self.in_transaction && !self.no_commit {
             self.db.execute_batch("COMMIT")?;
             self.in_transaction = false;
         }
@@ -741,8 +1703,10 @@ mod tests {
     use std::path::Path;
     use tempfile::NamedTempFile;
 
+    use sha1::Sha1;
+
     use crate::HashDigest;
-    use super::{Index, MAX_BLOCK_SIZE};
+    use super::{Index, MAX_BLOCK_SIZE, zero_digest};
 
     #[test]
     fn test() {
@@ -786,9 +1750,50 @@ mod tests {
         assert_eq!(block3.unwrap().1 - block2.unwrap().1, MAX_BLOCK_SIZE);
         let file1 = index.get_file(&name).expect("db").expect("get_file");
         assert_eq!(file1.0, 1);
-        assert_eq!(file1.2, HashDigest(
+        assert_eq!(file1.3, HashDigest(
             *b"\x84\xC2\x5D\x78\xED\xCD\xB6\x76\x31\x63\
             \x9C\x43\x60\x4C\xF0\x14\x95\x64\xF0\x44",
         ));
     }
+
+    #[test]
+    fn test_zero_blocks() {
+        let mut file = NamedTempFile::new().expect("tempfile");
+        file.write_all(&[0; 40000]).expect("tempfile");
+        file.write_all(b"not zeros").expect("tempfile");
+        file.flush().expect("tempfile");
+        let name = Path::new("zeros").to_path_buf();
+        let mut index = Index::open_in_memory().expect("db");
+        index.index_file(file.path(), &name).expect("index");
+        index.commit().expect("db");
+        let file_id = index.get_file(&name).expect("db").expect("get_file").0;
+        let blocks = index.list_file_blocks(file_id).expect("db");
+        let (hash, offset, size) = blocks[0].clone();
+        assert_eq!(offset, 0);
+        assert_eq!(hash, zero_digest(size));
+        let (hash, _offset, size) = blocks.last().unwrap().clone();
+        assert_ne!(hash, zero_digest(size));
+        let mut sha1 = Sha1::new();
+        sha1.update(&[0; 100]);
+        assert_eq!(zero_digest(100), HashDigest(sha1.digest().bytes()));
+    }
+
+    #[test]
+    fn test_missing_blocks() {
+        let mut index = Index::open_in_memory().expect("db");
+        let hash = HashDigest(*b"12345678901234567890");
+        let (file1, _) = index
+            .add_file(Path::new("one"), chrono::Utc::now(), 0o644)
+            .expect("add_file");
+        let (file2, _) = index
+            .add_file(Path::new("two"), chrono::Utc::now(), 0o644)
+            .expect("add_file");
+        index.add_missing_block(&hash, file1, 0, 10).expect("add");
+        index.add_missing_block(&hash, file1, 10, 10).expect("add");
+        index.add_missing_block(&hash, file2, 0, 10).expect("add");
+
+        // The block is requested once, and fills every location
+        assert_eq!(index.list_missing_blocks().expect("db"), vec![hash.clone()]);
+        assert_eq!(index.list_block_locations(&hash).expect("db").len(), 3);
+    }
 }
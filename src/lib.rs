@@ -7,6 +7,7 @@
 //! additions such as caching file signatures to make repeated synchronizations
 //! faster.
 
+mod filter;
 mod index;
 mod streaming_iterator;
 pub mod sync;
@@ -17,7 +18,8 @@ use std::fmt;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-pub use index::Index;
+pub use filter::{Filter, IGNORE_FILE_NAME};
+pub use index::{Index, IndexOptions, SymlinkPolicy};
 
 /// General error type for this library
 #[derive(Debug)]
@@ -28,6 +30,50 @@ pub enum Error {
     Sync(String),
     UnsupportedForLocation(&'static str),
     BadFilenameEncoding,
+    BadFilter(String),
+    BadConfig(String),
+    Remote(RemoteError),
+}
+
+impl Error {
+    /// A short name for the kind of error, sent to the other side along with
+    /// the message
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io(..) => "io",
+            Error::Sqlite(..) => "sqlite",
+            Error::Protocol(..) => "protocol",
+            Error::Sync(..) => "sync",
+            Error::UnsupportedForLocation(..) => "unsupported",
+            Error::BadFilenameEncoding => "filename-encoding",
+            Error::BadFilter(..) => "filter",
+            Error::BadConfig(..) => "config",
+            Error::Remote(..) => "remote",
+        }
+    }
+}
+
+/// An error that happened on the other side of a connection
+#[derive(Debug)]
+pub struct RemoteError {
+    /// The kind of error, see `Error::kind()`
+    pub kind: String,
+    /// The path the error is about, if any
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path {
+            Some(ref path) => write!(
+                f,
+                "Remote error ({}) on {}: {}",
+                self.kind, path, self.message,
+            ),
+            None => write!(f, "Remote error ({}): {}", self.kind, self.message),
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -39,6 +85,9 @@ impl fmt::Display for Error {
             Error::Sync(e) => write!(f, "{}", e),
             Error::UnsupportedForLocation(e) => write!(f, "{}", e),
             Error::BadFilenameEncoding => write!(f, "Bad filename encoding"),
+            Error::BadFilter(e) => write!(f, "Invalid filter rule: {}", e),
+            Error::BadConfig(e) => write!(f, "Invalid configuration: {}", e),
+            Error::Remote(e) => write!(f, "{}", e),
         }
     }
 }
@@ -53,6 +102,9 @@ impl std::error::Error for Error {
             Error::Sync(..) => None,
             Error::UnsupportedForLocation(..) => None,
             Error::BadFilenameEncoding => None,
+            Error::BadFilter(..) => None,
+            Error::BadConfig(..) => None,
+            Error::Remote(..) => None,
         }
     }
 }
@@ -75,8 +127,17 @@ pub const HASH_DIGEST_LEN: usize = 20;
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct HashDigest([u8; HASH_DIGEST_LEN]);
 
+impl HashDigest {
+    /// Compute the hash of some data
+    pub fn of(data: &[u8]) -> HashDigest {
+        let mut sha1 = sha1::Sha1::new();
+        sha1.update(data);
+        HashDigest(sha1.digest().bytes())
+    }
+}
+
 impl ToSql for HashDigest {
-    fn to_sql(&self) -> Result<ToSqlOutput, rusqlite::Error> {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, rusqlite::Error> {
         // Write the hash to buffer on the stack, we know the size
         let mut buffer = Vec::with_capacity(40);
         for byte in &self.0 {
@@ -121,7 +182,7 @@ impl FromSql for HashDigest {
                 )))
             } else {
                 let mut bytes = [0u8; HASH_DIGEST_LEN];
-                for (i, byte) in (&mut bytes).iter_mut().enumerate() {
+                for (i, byte) in bytes.iter_mut().enumerate() {
                     *byte = u8::from_str_radix(&s[i * 2 .. i * 2 + 2], 16)
                         .map_err(|_| {
                             FromSqlError::Other(Box::new(
@@ -144,12 +205,51 @@ impl fmt::Display for HashDigest {
     }
 }
 
+/// Prefix of the temporary files, where files are received before being
+/// moved into place
+const TEMP_PREFIX: &str = ".syncfast_tmp_";
+
+/// Prefix of the index of a single file, which is stored next to it
+const SINGLE_INDEX_PREFIX: &str = ".syncfast_idx_";
+
+/// Name of the manifest of a directory published for HTTP, see
+/// `sync::http::publish()`
+const MANIFEST_NAME: &str = ".syncfast.manifest";
+
+/// Get the raw bytes of a path, for the index and the protocol
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Result<&[u8], Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Result<&[u8], Error> {
+    path.to_str().map(str::as_bytes).ok_or(Error::BadFilenameEncoding)
+}
+
+/// Get a path back from its raw bytes
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf, Error> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(OsString::from_vec(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf, Error> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| Error::BadFilenameEncoding)
+}
+
 fn temp_name(name: &Path) -> Result<PathBuf, Error> {
     let mut temp_path = PathBuf::new();
     if let Some(parent) = name.parent() {
         temp_path.push(parent);
     }
-    let mut temp_name: OsString = ".syncfast_tmp_".into();
+    let mut temp_name: OsString = TEMP_PREFIX.into();
     temp_name.push(name.file_name().ok_or(
         std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid path"),
     )?);
@@ -165,11 +265,15 @@ fn untemp_name(name: &Path) -> Result<PathBuf, Error> {
     let temp_name = name.file_name().ok_or(
         std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid path"),
     )?;
-    let temp_name = temp_name.to_str().ok_or(Error::BadFilenameEncoding)?;
-    let stripped_name = temp_name.strip_prefix(".syncfast_tmp_").ok_or(
-        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Not a temporary path"),
-    )?;
-    temp_path.push(stripped_name);
+    let temp_name = path_to_bytes(Path::new(temp_name))?;
+    if !temp_name.starts_with(TEMP_PREFIX.as_bytes()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Not a temporary path",
+        ).into());
+    }
+    let stripped_name = &temp_name[TEMP_PREFIX.len()..];
+    temp_path.push(bytes_to_path(stripped_name.to_owned())?);
     Ok(temp_path)
 }
 
@@ -2,14 +2,275 @@ extern crate clap;
 extern crate env_logger;
 extern crate syncfast;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand};
 use std::env;
 use std::path::Path;
+use tokio::net::TcpListener;
 
-use syncfast::{Error, Index};
-use syncfast::sync::do_sync;
+use syncfast::{Error, Filter, Index, IndexOptions, SymlinkPolicy};
+use syncfast::sync::{
+    DEFAULT_COMPRESS_LEVEL, DEFAULT_MAX_MESSAGE_LENGTH, DRY_RUN_LOG_TARGET,
+    DeleteMode, DestinationOptions, SourceOptions, do_sync,
+};
+use syncfast::sync::daemon::{DaemonConfig, serve};
+use syncfast::sync::fs::fs_destination;
+use syncfast::sync::http::publish;
 use syncfast::sync::locations::Location;
-use syncfast::sync::ssh::{stdio_destination, stdio_source};
+use syncfast::sync::patch::{diff, patch_source};
+use syncfast::sync::signature::signature;
+use syncfast::sync::ssh::{stdio_destination, stdio_send_error, stdio_source};
+
+/// Add the arguments selecting files to a subcommand
+fn add_filter_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Exclude files matching PATTERN"),
+    )
+    .arg(
+        Arg::with_name("include")
+            .long("include")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Don't exclude files matching PATTERN"),
+    )
+    .arg(
+        Arg::with_name("filter")
+            .long("filter")
+            .takes_value(true)
+            .value_name("RULE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Add a filter rule, '+ PATTERN' or '- PATTERN'"),
+    )
+    .arg(
+        Arg::with_name("exclude-from")
+            .long("exclude-from")
+            .takes_value(true)
+            .value_name("FILE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Read filter rules from FILE"),
+    )
+}
+
+/// Read the filter rules from the subcommand's arguments
+///
+/// Rules apply in the order they are given on the command line.
+fn filter_options(matches: &ArgMatches) -> Filter {
+    let mut rules = Vec::new();
+    for &name in &["exclude", "include", "filter", "exclude-from"] {
+        if let (Some(indices), Some(values)) =
+            (matches.indices_of(name), matches.values_of(name))
+        {
+            for (idx, value) in indices.zip(values) {
+                rules.push((idx, name, value));
+            }
+        }
+    }
+    rules.sort_by_key(|&(idx, _, _)| idx);
+
+    let mut filter = Filter::new();
+    for (_, name, value) in rules {
+        let res = match name {
+            "exclude" => filter.exclude(value),
+            "include" => filter.include(value),
+            "filter" => filter.add_rule(value),
+            "exclude-from" => filter.add_rules_from_file(Path::new(value)),
+            _ => unreachable!(),
+        };
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    filter
+}
+
+/// Add the arguments controlling indexing to a subcommand
+fn add_index_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    add_filter_args(cmd).arg(
+        Arg::with_name("copy-links")
+            .short("L")
+            .long("copy-links")
+            .help("Follow symbolic links, copying what they point to"),
+    )
+    .arg(
+        Arg::with_name("no-links")
+            .long("no-links")
+            .help("Skip symbolic links"),
+    )
+    .arg(
+        Arg::with_name("safe-links")
+            .long("safe-links")
+            .help("Skip symbolic links that point outside of the tree"),
+    )
+    .group(ArgGroup::with_name("links").args(&[
+        "copy-links",
+        "no-links",
+        "safe-links",
+    ]))
+}
+
+/// Read the indexing options from the subcommand's arguments
+fn index_options(matches: &ArgMatches) -> IndexOptions {
+    let symlinks = if matches.is_present("copy-links") {
+        SymlinkPolicy::Copy
+    } else if matches.is_present("no-links") {
+        SymlinkPolicy::Skip
+    } else if matches.is_present("safe-links") {
+        SymlinkPolicy::SkipUnsafe
+    } else {
+        SymlinkPolicy::Preserve
+    };
+    IndexOptions { symlinks, filter: filter_options(matches) }
+}
+
+/// Add the argument controlling compression of block data to a subcommand
+fn add_compress_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("compress-level")
+            .long("compress-level")
+            .takes_value(true)
+            .value_name("NUM")
+            .validator(|v| match v.parse::<u32>() {
+                Ok(l) if l <= 9 => Ok(()),
+                _ => Err("Compression level should be between 0 and 9".into()),
+            })
+            .help("Compression level for block data sent over the network, \
+                   from 1 to 9, 0 disables compression (default: 6)"),
+    )
+}
+
+/// Read the compression level from the subcommand's arguments
+fn compress_level(matches: &ArgMatches) -> u32 {
+    match matches.value_of("compress-level") {
+        Some(level) => level.parse().unwrap(),
+        None => DEFAULT_COMPRESS_LEVEL,
+    }
+}
+
+/// Add the argument limiting the length of messages to a subcommand
+fn add_message_length_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("max-message-length")
+            .long("max-message-length")
+            .takes_value(true)
+            .value_name("BYTES")
+            .validator(|v| match v.parse::<usize>() {
+                Ok(l) if l > 0 => Ok(()),
+                _ => Err("Invalid length".into()),
+            })
+            .help("Refuse strings longer than this in messages received \
+                   over the network, such as file names (default: 1048576)"),
+    )
+}
+
+/// Read the message length limit from the subcommand's arguments
+fn max_message_length(matches: &ArgMatches) -> usize {
+    match matches.value_of("max-message-length") {
+        Some(length) => length.parse().unwrap(),
+        None => DEFAULT_MAX_MESSAGE_LENGTH,
+    }
+}
+
+/// Read the source options from the subcommand's arguments
+fn source_options(matches: &ArgMatches) -> SourceOptions {
+    let IndexOptions { symlinks, filter } = index_options(matches);
+    SourceOptions {
+        symlinks,
+        filter,
+        compress_level: compress_level(matches),
+        max_message_length: max_message_length(matches),
+    }
+}
+
+/// Add the arguments controlling the destination to a subcommand
+fn add_destination_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("delete")
+            .long("delete")
+            .help("Delete extraneous files from the destination (same as \
+                   --delete-during)"),
+    )
+    .arg(
+        Arg::with_name("delete-before")
+            .long("delete-before")
+            .help("Delete extraneous files as soon as the file list has been \
+                   received, without re-using their blocks"),
+    )
+    .arg(
+        Arg::with_name("delete-during")
+            .long("delete-during")
+            .help("Delete extraneous files once the file lists have been \
+                   received"),
+    )
+    .arg(
+        Arg::with_name("delete-after")
+            .long("delete-after")
+            .help("Delete extraneous files after the transfer"),
+    )
+    .group(ArgGroup::with_name("delete-mode").args(&[
+        "delete",
+        "delete-before",
+        "delete-during",
+        "delete-after",
+    ]))
+    .arg(
+        Arg::with_name("dry-run")
+            .short("n")
+            .long("dry-run")
+            .help("Show what would be done, without changing any file"),
+    )
+    .arg(
+        Arg::with_name("no-block-summary")
+            .long("no-block-summary")
+            .help("Don't send the list of blocks we have to the source, \
+                   block data is then only sent once requested"),
+    )
+}
+
+/// Read the destination options from the subcommand's arguments
+fn destination_options(matches: &ArgMatches) -> DestinationOptions {
+    let delete = if matches.is_present("delete-before") {
+        DeleteMode::Before
+    } else if matches.is_present("delete-after") {
+        DeleteMode::After
+    } else if matches.is_present("delete")
+        || matches.is_present("delete-during")
+    {
+        DeleteMode::During
+    } else {
+        DeleteMode::Never
+    };
+    DestinationOptions {
+        delete,
+        filter: filter_options(matches),
+        dry_run: matches.is_present("dry-run"),
+        compress_level: compress_level(matches),
+        block_summary: !matches.is_present("no-block-summary"),
+        max_message_length: max_message_length(matches),
+    }
+}
+
+/// Report an error in `remote-send` or `remote-recv` to the other side
+///
+/// It is only printed here if it can't be sent, since our stderr is also shown
+/// on the other side.
+async fn send_error(error: &Error, path: Option<&str>) {
+    if stdio_send_error(error, path).await.is_err() {
+        match path {
+            Some(path) => eprintln!("{}: {}", path, error),
+            None => eprintln!("{}", error),
+        }
+    }
+}
 
 /// Command-line entrypoint
 fn main() {
@@ -26,7 +287,7 @@ fn main() {
                 .multiple(true),
         )
         .subcommand(
-            SubCommand::with_name("index")
+            add_index_args(SubCommand::with_name("index"))
                 .about("Index a file or directory")
                 .arg(
                     Arg::with_name("path")
@@ -41,7 +302,21 @@ fn main() {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("sync")
+            add_index_args(SubCommand::with_name("publish"))
+                .about(
+                    "Index a directory and write its manifest, so it can be \
+                     served over HTTP",
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            add_message_length_args(add_compress_args(add_destination_args(
+                add_index_args(SubCommand::with_name("sync")),
+            )))
                 .about("Copy files")
                 .arg(
                     Arg::with_name("source")
@@ -55,7 +330,82 @@ fn main() {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("remote-recv")
+            add_index_args(SubCommand::with_name("signature"))
+                .about(
+                    "Index a directory and write its signature, to compute a \
+                     patch for it elsewhere",
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("blocks-only")
+                        .long("blocks-only")
+                        .help("Only write the blocks, without the file names"),
+                ),
+        )
+        .subcommand(
+            add_index_args(SubCommand::with_name("diff"))
+                .about(
+                    "Write a patch file, to update the directory described by \
+                     an index to the content of another directory",
+                )
+                .arg(
+                    Arg::with_name("old")
+                        .help("Signature or index of the directory to \
+                               update, or the directory containing the index")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            add_destination_args(add_filter_args(SubCommand::with_name("patch")))
+                .about("Apply a patch file written by diff")
+                .arg(
+                    Arg::with_name("patch")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Serve the modules set in the configuration file over TCP")
+                .arg(
+                    Arg::with_name("config")
+                        .short("c")
+                        .long("config")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            add_message_length_args(add_destination_args(add_filter_args(
+                SubCommand::with_name("remote-recv"),
+            )))
                 .about(
                     "Internal - process started on the remote to receive \
                      files. Expects stdin and stdout to be connected to the \
@@ -68,7 +418,9 @@ fn main() {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("remote-send")
+            add_message_length_args(add_compress_args(add_index_args(
+                SubCommand::with_name("remote-send"),
+            )))
                 .about(
                     "Internal - process started on the remote to send \
                      files. Expects stdin and stdout to be connected to \
@@ -99,6 +451,17 @@ fn main() {
         };
         let mut logger_builder = env_logger::builder();
         logger_builder.filter(None, level);
+        // Always show the report of a dry run
+        let dry_run = matches
+            .subcommand()
+            .1
+            .map_or(false, |m| m.is_present("dry-run"));
+        if dry_run && level < log::LevelFilter::Info {
+            logger_builder.filter(
+                Some(DRY_RUN_LOG_TARGET),
+                log::LevelFilter::Info,
+            );
+        }
         if let Ok(val) = env::var("SYNCFAST_LOG") {
             logger_builder.parse_filters(&val);
         }
@@ -119,16 +482,23 @@ fn main() {
                     Index::open(&path.join(".syncfast.idx"))?
                 },
             };
-            index.index_path(path)?;
+            index.index_path_with_options(path, &index_options(s_matches))?;
             index.remove_missing_files(path)?;
             index.commit()?;
 
             Ok(())
         }(),
+        Some("publish") => {
+            let s_matches = matches.subcommand_matches("publish").unwrap();
+            let path = Path::new(s_matches.value_of_os("path").unwrap());
+            publish(path, &index_options(s_matches))
+        }
         Some("sync") => {
             let s_matches = matches.subcommand_matches("sync").unwrap();
             let source = s_matches.value_of_os("source").unwrap();
             let dest = s_matches.value_of_os("destination").unwrap();
+            let source_options = source_options(s_matches);
+            let dest_options = destination_options(s_matches);
 
             let source = match source.to_str().and_then(Location::parse) {
                 Some(s) => s,
@@ -155,7 +525,7 @@ fn main() {
                 .unwrap();
             runtime.block_on(async move {
                 let source: syncfast::sync::Source =
-                    match source.open_source() {
+                    match source.open_source(&source_options) {
                         Ok(o) => o,
                         Err(e) => {
                             eprintln!("Failed to open source: {}", e);
@@ -163,7 +533,7 @@ fn main() {
                         }
                     };
                 let destination: syncfast::sync::Destination =
-                    match dest.open_destination() {
+                    match dest.open_destination(&dest_options) {
                         Ok(o) => o,
                         Err(e) => {
                             eprintln!("Failed to open destination: {}", e);
@@ -173,9 +543,62 @@ fn main() {
                 do_sync(source, destination).await
             })
         }
+        Some("signature") => {
+            let s_matches = matches.subcommand_matches("signature").unwrap();
+            let path = Path::new(s_matches.value_of_os("path").unwrap());
+            let output = Path::new(s_matches.value_of_os("output").unwrap());
+            signature(
+                path,
+                &index_options(s_matches),
+                s_matches.is_present("blocks-only"),
+                output,
+            )
+        }
+        Some("diff") => {
+            let s_matches = matches.subcommand_matches("diff").unwrap();
+            let old = Path::new(s_matches.value_of_os("old").unwrap());
+            let new = Path::new(s_matches.value_of_os("new").unwrap());
+            let output = Path::new(s_matches.value_of_os("output").unwrap());
+            diff(old, new, &index_options(s_matches), output)
+        }
+        Some("patch") => {
+            let s_matches = matches.subcommand_matches("patch").unwrap();
+            let patch = Path::new(s_matches.value_of_os("patch").unwrap());
+            let dest = Path::new(s_matches.value_of_os("destination").unwrap());
+            let dest_options = destination_options(s_matches);
+
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let source = patch_source(patch, &Default::default())?;
+                let destination = fs_destination(dest.to_owned(), &dest_options)?;
+                do_sync(source, destination).await
+            })
+        }
+        Some("daemon") => {
+            let s_matches = matches.subcommand_matches("daemon").unwrap();
+            let config = Path::new(s_matches.value_of_os("config").unwrap());
+
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            // Connections are handled in local tasks
+            let tasks = tokio::task::LocalSet::new();
+            tasks.block_on(&runtime, async move {
+                let config = DaemonConfig::read(config)?;
+                let listener = TcpListener::bind(
+                    (config.address.as_str(), config.port),
+                ).await?;
+                serve(listener, config).await
+            })
+        }
         Some("remote-send") => {
             let s_matches = matches.subcommand_matches("remote-send").unwrap();
             let source = s_matches.value_of_os("source").unwrap();
+            let source_options = source_options(s_matches);
+            let source_str = source.to_string_lossy().into_owned();
 
             let source = match source.to_str().and_then(Location::parse) {
                 Some(s) => s,
@@ -191,21 +614,30 @@ fn main() {
                 .unwrap();
             runtime.block_on(async move {
                 let source: syncfast::sync::Source =
-                    match source.open_source() {
+                    match source.open_source(&source_options) {
                         Ok(o) => o,
                         Err(e) => {
-                            eprintln!("Failed to open source: {}", e);
+                            send_error(&e, Some(&source_str)).await;
                             std::process::exit(1);
                         }
                     };
                 let destination: syncfast::sync::Destination =
-                    stdio_destination();
-                do_sync(source, destination).await
+                    stdio_destination(
+                        source_options.compress_level,
+                        source_options.max_message_length,
+                    );
+                if let Err(e) = do_sync(source, destination).await {
+                    send_error(&e, None).await;
+                    std::process::exit(1);
+                }
+                Ok(())
             })
         }
         Some("remote-recv") => {
             let s_matches = matches.subcommand_matches("remote-recv").unwrap();
             let destination = s_matches.value_of_os("destination").unwrap();
+            let dest_options = destination_options(s_matches);
+            let destination_str = destination.to_string_lossy().into_owned();
 
             let destination = match destination.to_str().and_then(Location::parse) {
                 Some(s) => s,
@@ -221,16 +653,20 @@ fn main() {
                 .unwrap();
             runtime.block_on(async move {
                 let source: syncfast::sync::Source =
-                    stdio_source();
+                    stdio_source(dest_options.max_message_length);
                 let destination: syncfast::sync::Destination =
-                    match destination.open_destination() {
+                    match destination.open_destination(&dest_options) {
                         Ok(o) => o,
                         Err(e) => {
-                            eprintln!("Failed to open destination: {}", e);
+                            send_error(&e, Some(&destination_str)).await;
                             std::process::exit(1);
                         }
                     };
-                do_sync(source, destination).await
+                if let Err(e) = do_sync(source, destination).await {
+                    send_error(&e, None).await;
+                    std::process::exit(1);
+                }
+                Ok(())
             })
         }
         _ => {
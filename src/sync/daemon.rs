@@ -0,0 +1,651 @@
+//! Standalone daemon serving directories over TCP, like rsyncd.
+//!
+//! The daemon exposes named modules, set in its configuration file. A client
+//! connects and sends a `COMMAND` message, with the arguments it would give
+//! to `remote-send` or `remote-recv` over SSH; the path starts with the name of
+//! the module. The rest of the exchange is the same as over SSH.
+
+use futures::stream::StreamExt;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use tokio::io::{BufReader, ReadHalf, WriteHalf, split};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::Error;
+use crate::sync::{
+    DEFAULT_MAX_MESSAGE_LENGTH, Destination, DestinationOptions, Source,
+    SourceOptions, do_sync,
+};
+use crate::sync::fs::{fs_destination, fs_source};
+use crate::sync::locations::DaemonLocation;
+use crate::sync::proto::{Message, read_command, write_message};
+use crate::sync::ssh::{
+    PeerCapabilities, SshSink, SshStream, destination_args,
+    parse_destination_args, parse_source_args, send_error, source_args,
+};
+
+/// Port used if none is set in the configuration or the location
+pub const DEFAULT_DAEMON_PORT: u16 = 7722;
+
+/// Maximum size of the `COMMAND` message
+const MAX_COMMAND_SIZE: usize = 64 << 10; // 64 KiB
+
+/// A directory served by the daemon
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Module {
+    pub path: PathBuf,
+    /// Clients can't send files to it (the default)
+    pub read_only: bool,
+    /// Clients can't get files from it
+    pub write_only: bool,
+}
+
+/// Configuration of the daemon
+///
+/// The file is in INI format. Global settings come first: `address` and
+/// `port` to listen on. Then each module is a section, with its name in
+/// brackets, and the settings `path`, `read only` and `write only`:
+///
+/// ```text
+/// port = 7722
+///
+/// [backups]
+/// path = /srv/backups
+/// read only = no
+/// ```
+///
+/// There is no authentication: anyone who can connect to the daemon can get
+/// files from any module, and send files to modules with `read only = no`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DaemonConfig {
+    pub address: String,
+    pub port: u16,
+    pub modules: HashMap<String, Module>,
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" | "true" | "1" => Some(true),
+        "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+impl DaemonConfig {
+    /// Read the configuration from the content of a file
+    pub fn parse(config: &str) -> Result<DaemonConfig, Error> {
+        let mut address = "0.0.0.0".to_owned();
+        let mut port = DEFAULT_DAEMON_PORT;
+        let mut modules = HashMap::new();
+        // Name of the current module, and its settings so far
+        let mut module: Option<(String, Option<PathBuf>, bool, bool)> = None;
+
+        fn add_module(
+            modules: &mut HashMap<String, Module>,
+            module: Option<(String, Option<PathBuf>, bool, bool)>,
+        ) -> Result<(), Error> {
+            if let Some((name, path, read_only, write_only)) = module {
+                let path = path.ok_or_else(|| Error::BadConfig(
+                    format!("No path for module {:?}", name),
+                ))?;
+                modules.insert(name, Module { path, read_only, write_only });
+            }
+            Ok(())
+        }
+
+        for (num, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let error = |message: &str| {
+                Error::BadConfig(format!("{} on line {}", message, num + 1))
+            };
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let name = line[1..line.len() - 1].trim();
+                if name.is_empty() || name.contains('/') {
+                    return Err(error("Invalid module name"));
+                }
+                if modules.contains_key(name) {
+                    return Err(error("Duplicate module"));
+                }
+                add_module(&mut modules, module.take())?;
+                module = Some((name.to_owned(), None, true, false));
+                continue;
+            }
+
+            let idx = line.find('=').ok_or_else(|| error("Expected key = value"))?;
+            let key = line[..idx].trim().to_ascii_lowercase();
+            let value = line[idx + 1..].trim();
+            match (&mut module, key.as_str()) {
+                (None, "address") => address = value.to_owned(),
+                (None, "port") => {
+                    port = value.parse().map_err(|_| error("Invalid port"))?;
+                }
+                (Some((_, ref mut path, _, _)), "path") => {
+                    *path = Some(PathBuf::from(value));
+                }
+                (Some((_, _, ref mut read_only, _)), "read only") => {
+                    *read_only = parse_bool(value).ok_or_else(|| error("Invalid boolean"))?;
+                }
+                (Some((_, _, _, ref mut write_only)), "write only") => {
+                    *write_only = parse_bool(value).ok_or_else(|| error("Invalid boolean"))?;
+                }
+                _ => return Err(error(&format!("Unknown setting {:?}", key))),
+            }
+        }
+        add_module(&mut modules, module)?;
+
+        Ok(DaemonConfig { address, port, modules })
+    }
+
+    /// Read the configuration file
+    pub fn read(path: &Path) -> Result<DaemonConfig, Error> {
+        DaemonConfig::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Find the directory for a path requested by a client
+    ///
+    /// `receiving` is set if the client is sending files to it.
+    fn resolve_path(&self, path: &str, receiving: bool) -> Result<PathBuf, Error> {
+        let (name, rest) = match path.find('/') {
+            Some(idx) => (&path[..idx], &path[idx + 1..]),
+            None => (path, ""),
+        };
+        let module = self.modules.get(name).ok_or_else(|| {
+            Error::Sync(format!("Unknown module {:?}", name))
+        })?;
+        if receiving && module.read_only {
+            return Err(Error::Sync(format!("Module {:?} is read-only", name)));
+        }
+        if !receiving && module.write_only {
+            return Err(Error::Sync(format!("Module {:?} is write-only", name)));
+        }
+        // Don't allow leaving the module, including through symbolic links
+        let mut resolved = module.path.clone();
+        for component in Path::new(rest).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::CurDir => continue,
+                _ => return Err(Error::Sync(format!("Invalid path {:?}", path))),
+            }
+            match std::fs::symlink_metadata(&resolved) {
+                Ok(m) if m.file_type().is_symlink() => {
+                    return Err(Error::Sync(format!("Invalid path {:?}", path)));
+                }
+                Ok(_) => {}
+                // The rest will be created
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(module.path.join(rest))
+    }
+}
+
+/// Open the location for the command sent by a client, like `remote-send` or
+/// `remote-recv`, returning the source and destination to sync
+fn open_command(
+    reader: BufReader<ReadHalf<TcpStream>>,
+    writer: WriteHalf<TcpStream>,
+    config: &DaemonConfig,
+    command: &str,
+    args: &[String],
+    path: &str,
+) -> Result<(Source, Destination), Error> {
+    let peer_capabilities = PeerCapabilities::default();
+    // The limit asked by the client is not used, it could make us use more
+    // memory
+    let stream = Box::pin(SshStream::new(
+        reader,
+        peer_capabilities.clone(),
+        DEFAULT_MAX_MESSAGE_LENGTH,
+    ));
+    match command {
+        "remote-send" => {
+            let options = parse_source_args(args)?;
+            let path = config.resolve_path(path, false)?;
+            let source = fs_source(path, &options)?;
+            let destination = Destination {
+                stream: futures::stream::unfold(stream, SshStream::stream).boxed_local(),
+                sink: Box::pin(SshSink::new(writer, peer_capabilities, options.compress_level)),
+            };
+            Ok((source, destination))
+        }
+        "remote-recv" => {
+            let options = parse_destination_args(args)?;
+            let path = config.resolve_path(path, true)?;
+            // We only send requests to the client, there is no block data to
+            // compress
+            let source = Source {
+                stream: futures::stream::unfold(stream, SshStream::stream).boxed_local(),
+                sink: Box::pin(SshSink::new(writer, peer_capabilities, 0)),
+            };
+            let destination = fs_destination(path, &options)?;
+            Ok((source, destination))
+        }
+        _ => Err(Error::Sync(format!("Unknown command {:?}", command))),
+    }
+}
+
+/// Serve a client, sending it any error
+async fn handle_connection(
+    stream: TcpStream,
+    config: Rc<DaemonConfig>,
+) -> Result<(), Error> {
+    let peer = stream.peer_addr()?;
+    // Keep a second handle to send errors, since the one used for the
+    // protocol is consumed by the sync
+    let stream = stream.into_std()?;
+    let mut error_output = TcpStream::from_std(stream.try_clone()?)?;
+    // Unlike into_split(), dropping the write half doesn't shut it down: the
+    // connection has to stay open until we are done, like the output of
+    // `remote-recv` until it exits
+    let (reader, writer) = split(TcpStream::from_std(stream)?);
+    let mut reader = BufReader::new(reader);
+
+    let args = read_command(&mut reader, MAX_COMMAND_SIZE).await.and_then(|args| {
+        args.into_iter()
+            .map(|arg| String::from_utf8(arg).map_err(|_| Error::BadFilenameEncoding))
+            .collect::<Result<Vec<String>, Error>>()
+    });
+    let args = match args {
+        Ok(args) if args.len() >= 2 => args,
+        Ok(_) => {
+            let e = Error::Sync("Invalid command".to_owned());
+            send_error(&mut error_output, &e, None).await?;
+            return Err(e);
+        }
+        Err(e) => {
+            send_error(&mut error_output, &e, None).await.ok();
+            return Err(e);
+        }
+    };
+    let (command, args, path) = (&args[0], &args[1..args.len() - 1], &args[args.len() - 1]);
+    info!("{}: {} {:?}", peer, command, path);
+
+    let (source, destination) = match open_command(reader, writer, &config, command, args, path) {
+        Ok(t) => t,
+        Err(e) => {
+            send_error(&mut error_output, &e, Some(path)).await.ok();
+            return Err(e);
+        }
+    };
+    // Errors during the sync are about some entry, not the whole location
+    match do_sync(source, destination).await {
+        Ok(()) => {
+            info!("{}: done", peer);
+            Ok(())
+        }
+        Err(e) => {
+            send_error(&mut error_output, &e, None).await.ok();
+            Err(e)
+        }
+    }
+}
+
+/// Accept connections from clients and serve them
+///
+/// This has to run in a `tokio::task::LocalSet`, since each connection is
+/// handled in its own local task. It only returns if accepting connections
+/// fails.
+///
+/// Those tasks all run on the same thread, and reading and writing files
+/// (including indexing the module when a client connects) blocks it, so
+/// clients are effectively served one at a time.
+pub async fn serve(
+    listener: TcpListener,
+    config: DaemonConfig,
+) -> Result<(), Error> {
+    info!("Listening on {}", listener.local_addr()?);
+    let config = Rc::new(config);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        info!("{}: connected", peer);
+        let config = config.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = handle_connection(stream, config).await {
+                warn!("{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Connect to a daemon and send it the command
+fn connect(
+    loc: &DaemonLocation,
+    command: &str,
+    args: Vec<String>,
+) -> Result<(OwnedReadHalf, OwnedWriteHalf), Error> {
+    let mut stream = std::net::TcpStream::connect((loc.host.as_str(), loc.port))?;
+    let mut message = Vec::new();
+    let mut command_args: Vec<&[u8]> = vec![command.as_bytes()];
+    command_args.extend(args.iter().map(|a| a.as_bytes()));
+    command_args.push(loc.path.as_bytes());
+    write_message(Message::Command(command_args), &mut message)?;
+    // Send it right away, we might not have anything else to send until the
+    // daemon answers
+    stream.write_all(&message)?;
+    stream.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(stream)?.into_split())
+}
+
+pub fn daemon_source(
+    loc: &DaemonLocation,
+    options: &SourceOptions,
+) -> Result<Source, Error> {
+    info!("Setting up source {}:{}/{}", loc.host, loc.port, loc.path);
+    let (reader, writer) = connect(loc, "remote-send", source_args(options))?;
+
+    // We only send requests to the source, there is no block data to compress
+    let peer_capabilities = PeerCapabilities::default();
+    Ok(Source {
+        stream: futures::stream::unfold(
+            Box::pin(SshStream::new(
+                reader,
+                peer_capabilities.clone(),
+                options.max_message_length,
+            )),
+            SshStream::stream,
+        ).boxed_local(),
+        sink: Box::pin(SshSink::new(writer, peer_capabilities, 0)),
+    })
+}
+
+pub fn daemon_destination(
+    loc: &DaemonLocation,
+    options: &DestinationOptions,
+) -> Result<Destination, Error> {
+    info!("Setting up destination {}:{}/{}", loc.host, loc.port, loc.path);
+    let (reader, writer) = connect(loc, "remote-recv", destination_args(options))?;
+
+    let peer_capabilities = PeerCapabilities::default();
+    Ok(Destination {
+        stream: futures::stream::unfold(
+            Box::pin(SshStream::new(
+                reader,
+                peer_capabilities.clone(),
+                options.max_message_length,
+            )),
+            SshStream::stream,
+        ).boxed_local(),
+        sink: Box::pin(SshSink::new(
+            writer,
+            peer_capabilities,
+            options.compress_level,
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use futures::future::ready;
+    use futures::sink::SinkExt;
+    use futures::stream::StreamExt;
+    use std::fs;
+    use std::future::Future;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+
+    use crate::Error;
+    use crate::sync::{
+        DestinationEvent, FileMetadata, Source, SourceEvent, do_sync,
+    };
+    use crate::sync::fs::{fs_destination, fs_source};
+    use crate::sync::locations::Location;
+    use super::{DEFAULT_DAEMON_PORT, DaemonConfig, Module, serve};
+
+    #[test]
+    fn test_config() {
+        let config = DaemonConfig::parse(
+            "# Comment\n\
+             port = 1234\n\
+             \n\
+             [data]\n\
+             path = /srv/data\n\
+             read only = no\n\
+             [ro]\n\
+             Path = /srv/ro\n",
+        ).unwrap();
+        assert_eq!(config.address, "0.0.0.0");
+        assert_eq!(config.port, 1234);
+        assert_eq!(config.modules.len(), 2);
+        assert_eq!(
+            config.modules["data"],
+            Module { path: "/srv/data".into(), read_only: false, write_only: false },
+        );
+        assert_eq!(
+            config.modules["ro"],
+            Module { path: "/srv/ro".into(), read_only: true, write_only: false },
+        );
+
+        assert_eq!(
+            config.resolve_path("data/some/file", true).unwrap(),
+            PathBuf::from("/srv/data/some/file"),
+        );
+        assert_eq!(
+            config.resolve_path("ro", false).unwrap(),
+            PathBuf::from("/srv/ro"),
+        );
+        let error = |path, receiving| {
+            config.resolve_path(path, receiving).unwrap_err().to_string()
+        };
+        assert_eq!(error("ro/file", true), "Module \"ro\" is read-only");
+        assert_eq!(error("other/file", false), "Unknown module \"other\"");
+        assert_eq!(error("data/../file", false), "Invalid path \"data/../file\"");
+        assert_eq!(error("data//etc", false), "Invalid path \"data//etc\"");
+
+        let error = |config: &str| DaemonConfig::parse(config).unwrap_err().to_string();
+        assert_eq!(
+            error("[data]\nread only = no\n"),
+            "Invalid configuration: No path for module \"data\"",
+        );
+        assert_eq!(
+            error("path = /srv\n"),
+            "Invalid configuration: Unknown setting \"path\" on line 1",
+        );
+        assert_eq!(
+            error("[data]\npath = /srv\nread only = maybe\n"),
+            "Invalid configuration: Invalid boolean on line 3",
+        );
+        assert_eq!(DaemonConfig::parse("").unwrap().port, DEFAULT_DAEMON_PORT);
+    }
+
+    #[test]
+    fn test_daemon() {
+        let data = TempDir::new().unwrap();
+        let ro = TempDir::new().unwrap();
+        fs::write(ro.path().join("file"), b"read-only file").unwrap();
+        let config = DaemonConfig::parse(&format!(
+            "[data]\npath = {}\nread only = no\n[ro]\npath = {}\n",
+            data.path().display(),
+            ro.path().display(),
+        )).unwrap();
+
+        let local = TempDir::new().unwrap();
+        fs::write(local.path().join("a"), b"first file").unwrap();
+        fs::create_dir(local.path().join("sub")).unwrap();
+        fs::write(local.path().join("sub/b"), b"second file").unwrap();
+        let output = TempDir::new().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let tasks = tokio::task::LocalSet::new();
+        tasks.block_on(&runtime, async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::task::spawn_local(serve(listener, config));
+            let location = |path: &str| {
+                Location::parse(&format!("syncfast://127.0.0.1:{}/{}", port, path)).unwrap()
+            };
+            let push = |path: &str| {
+                let source = fs_source(local.path().to_owned(), &Default::default()).unwrap();
+                let destination = location(path).open_destination(&Default::default()).unwrap();
+                do_sync(source, destination)
+            };
+
+            push("data/dir").await.unwrap();
+            assert_eq!(fs::read(data.path().join("dir/a")).unwrap(), b"first file");
+            assert_eq!(fs::read(data.path().join("dir/sub/b")).unwrap(), b"second file");
+
+            let source = location("ro").open_source(&Default::default()).unwrap();
+            let destination = fs_destination(output.path().to_owned(), &Default::default()).unwrap();
+            do_sync(source, destination).await.unwrap();
+            assert_eq!(fs::read(output.path().join("file")).unwrap(), b"read-only file");
+
+            for &(path, message) in &[
+                ("ro", "Module \"ro\" is read-only"),
+                ("nope/dir", "Unknown module \"nope\""),
+                ("data/../escape", "Invalid path \"data/../escape\""),
+            ] {
+                match push(path).await {
+                    Err(Error::Remote(e)) => {
+                        assert_eq!(e.message, message);
+                        assert_eq!(e.path.as_deref(), Some(path));
+                    }
+                    r => panic!("Unexpected result: {:?}", r),
+                }
+            }
+        });
+        assert!(!ro.path().join("a").exists());
+    }
+
+    /// Send entries to a daemon, as a client could, returning the error
+    fn send_entries(
+        port: u16,
+        path: &str,
+        events: Vec<SourceEvent>,
+    ) -> impl Future<Output=Result<(), Error>> {
+        // Only end once the daemon is done
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let done = receiver
+            .take_while(|e| ready(!matches!(e, DestinationEvent::Complete)))
+            .filter_map(|_| ready(None));
+        let source = Source {
+            stream: futures::stream::iter(events.into_iter().map(Ok))
+                .chain(done)
+                .boxed_local(),
+            sink: Box::pin(sender.sink_map_err(|_| unreachable!())),
+        };
+        let location = Location::parse(
+            &format!("syncfast://127.0.0.1:{}/{}", port, path),
+        ).unwrap();
+        let destination = location.open_destination(&Default::default()).unwrap();
+        do_sync(source, destination)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_daemon_unsafe_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let data = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        let config = DaemonConfig::parse(&format!(
+            "[data]\npath = {}\nread only = no\n",
+            data.path().join("module").display(),
+        )).unwrap();
+        let metadata = FileMetadata {
+            modified: chrono::Utc.timestamp(1500000000, 0),
+            mode: 0o755,
+        };
+        let directory = |name: &[u8]| {
+            SourceEvent::DirectoryEntry(name.to_vec(), metadata.clone())
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let tasks = tokio::task::LocalSet::new();
+        tasks.block_on(&runtime, async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::task::spawn_local(serve(listener, config));
+
+            // A symbolic link to outside the module is fine by itself
+            let target = outside.path().as_os_str().as_bytes().to_vec();
+            send_entries(port, "data", vec![
+                SourceEvent::SymlinkEntry(b"link".to_vec(), target),
+                SourceEvent::EndFiles,
+            ]).await.unwrap();
+            let link = data.path().join("module/link");
+            assert_eq!(fs::read_link(&link).unwrap(), outside.path());
+
+            // But it can't be used as the location
+            for path in &["data/link", "data/link/dir"] {
+                match send_entries(port, path, vec![directory(b"dir")]).await {
+                    Err(Error::Remote(e)) => {
+                        assert_eq!(e.message, format!("Invalid path {:?}", path));
+                    }
+                    r => panic!("Unexpected result: {:?}", r),
+                }
+            }
+            fs::write(outside.path().join("secret"), b"secret").unwrap();
+            let output = TempDir::new().unwrap();
+            let source = Location::parse(
+                &format!("syncfast://127.0.0.1:{}/data/link", port),
+            ).unwrap().open_source(&Default::default()).unwrap();
+            let destination = fs_destination(output.path().to_owned(), &Default::default()).unwrap();
+            match do_sync(source, destination).await {
+                Err(Error::Remote(e)) => {
+                    assert_eq!(e.message, "Invalid path \"data/link\"");
+                }
+                r => panic!("Unexpected result: {:?}", r),
+            }
+            assert!(!output.path().join("secret").exists());
+            fs::remove_file(outside.path().join("secret")).unwrap();
+
+            for (events, message) in vec![
+                (
+                    vec![directory(b"../escape")],
+                    "Invalid name from source \"../escape\"",
+                ),
+                (
+                    vec![directory(b"/abs")],
+                    "Invalid name from source \"/abs\"",
+                ),
+                (
+                    vec![directory(b"link/dir")],
+                    "Parent of \"link/dir\" is a symbolic link",
+                ),
+                (
+                    vec![SourceEvent::HardLinkEntry(
+                        b"hard".to_vec(),
+                        b"../file".to_vec(),
+                    )],
+                    "Invalid name from source \"../file\"",
+                ),
+                // The link is only created at the end, after the list
+                (
+                    vec![
+                        SourceEvent::SymlinkEntry(b"link2".to_vec(), b"..".to_vec()),
+                        SourceEvent::HardLinkEntry(b"hard".to_vec(), b"link2/file".to_vec()),
+                        SourceEvent::EndFiles,
+                    ],
+                    "Parent of \"link2/file\" is a symbolic link",
+                ),
+            ] {
+                match send_entries(port, "data", events).await {
+                    Err(Error::Remote(e)) => {
+                        assert_eq!(e.message, message);
+                        assert_eq!(e.path, None);
+                    }
+                    r => panic!("Unexpected result: {:?}", r),
+                }
+            }
+        });
+        assert!(!data.path().join("escape").exists());
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+        assert!(!data.path().join("module/hard").exists());
+    }
+}
@@ -4,24 +4,22 @@ use cdchunking::{Chunker, ZPAQ};
 use futures::channel::mpsc::{Receiver, channel};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
-use log::{log_enabled, debug, info};
+use log::{log_enabled, debug, info, warn};
 use log::Level::Debug;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
 use std::future::Future;
 use std::io::{Seek, SeekFrom, Write};
-use std::ops::DerefMut;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
 use std::rc::Rc;
-use std::string::FromUtf8Error;
 
-use crate::{Error, HashDigest, temp_name, untemp_name};
-use crate::index::{MAX_BLOCK_SIZE, ZPAQ_BITS, Index};
-use crate::sync::{Destination, DestinationEvent, Source, SourceEvent};
-use crate::sync::utils::{Condition, ConditionFuture, move_file};
+use crate::{Error, Filter, HashDigest, SINGLE_INDEX_PREFIX, bytes_to_path, path_to_bytes, temp_name, untemp_name};
+use crate::index::{MAX_BLOCK_SIZE, ZPAQ_BITS, Index, IndexOptions, file_blocks_hash, file_mode, zero_digest};
+use crate::sync::{BlockSummary, DRY_RUN_LOG_TARGET, DeleteMode, Destination, DestinationEvent, DestinationOptions, FileMetadata, Source, SourceEvent, SourceOptions};
+use crate::sync::utils::{Condition, ConditionFuture, PERMISSION_BITS, create_symlink, is_same_file, move_file, remove_file_if_exists, set_metadata};
 
 fn read_block(path: &Path, offset: usize) -> Result<Vec<u8>, Error> {
     let mut file = File::open(path)?;
@@ -39,23 +37,104 @@ fn read_block(path: &Path, offset: usize) -> Result<Vec<u8>, Error> {
     Ok(block)
 }
 
+/// Read a block requested by the destination, using the index to find it
+pub(crate) fn get_block_data(
+    index: &mut Index,
+    root_dir: &Path,
+    hash: HashDigest,
+) -> Result<SourceEvent, Error> {
+    let (path, offset, _size) = match index.get_block(&hash)? {
+        Some(t) => t,
+        None => return Err(Error::Sync("Requested block is unknown".to_owned())),
+    };
+    debug!("FsSource: found block in {:?} offset {}", path, offset);
+    let data = read_block(&root_dir.join(&path), offset)?;
+    Ok(SourceEvent::BlockData(hash, data))
+}
+
 fn write_block(
     name: &Path,
     offset: usize,
     block: &[u8],
 ) -> Result<(), Error> {
-    let mut file = OpenOptions::new().write(true).create(true).open(name)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(name)?;
     file.seek(SeekFrom::Start(offset as u64))?;
     file.write_all(block)?;
     Ok(())
 }
 
-pub fn fs_source(root_dir: PathBuf) -> Result<Source, Error> {
-    info!("Indexing source into {:?}...", root_dir.join(".syncfast.idx"));
-    let mut index = Index::open(&root_dir.join(".syncfast.idx"))?;
-    index.index_path(&root_dir)?;
-    index.remove_missing_files(&root_dir)?;
-    index.commit()?;
+/// Open and update the index for a location
+///
+/// If `single_file` is set, the location is a file (which might not exist
+/// yet) and its index is stored next to it, otherwise it is a directory with
+/// the index in it. This returns the index, the directory the names in the
+/// index are relative to, and the name of the file if `single_file` is set.
+///
+/// If `dry_run` is set, nothing is created or written, see
+/// `Index::open_uncommitted()`.
+pub(crate) fn open_index(
+    path: &Path,
+    single_file: bool,
+    options: &IndexOptions,
+    dry_run: bool,
+) -> Result<(Index, PathBuf, Option<PathBuf>), Error> {
+    let open = |index_path: &Path| if dry_run {
+        Index::open_uncommitted(index_path)
+    } else {
+        Index::open(index_path)
+    };
+    if single_file {
+        let name = PathBuf::from(path.file_name().ok_or(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid path"),
+        )?);
+        let root_dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let mut index_name: OsString = SINGLE_INDEX_PREFIX.into();
+        index_name.push(&name);
+        let index_path = root_dir.join(index_name);
+        info!("Indexing file into {:?}...", index_path);
+        if !dry_run {
+            std::fs::create_dir_all(&root_dir)?;
+        }
+        let mut index = open(&index_path)?;
+        if path.exists() {
+            index.index_file(path, &name)?;
+        }
+        index.remove_missing_files(&root_dir)?;
+        index.commit()?;
+        Ok((index, root_dir, Some(name)))
+    } else {
+        info!("Indexing directory into {:?}...", path.join(".syncfast.idx"));
+        if !dry_run {
+            std::fs::create_dir_all(path)?;
+        }
+        let mut index = open(&path.join(".syncfast.idx"))?;
+        if path.exists() {
+            index.index_path_with_options(path, options)?;
+        }
+        index.remove_missing_files(path)?;
+        index.commit()?;
+        Ok((index, path.to_owned(), None))
+    }
+}
+
+pub fn fs_source(
+    root_dir: PathBuf,
+    options: &SourceOptions,
+) -> Result<Source, Error> {
+    let index_options = IndexOptions {
+        symlinks: options.symlinks,
+        filter: options.filter.clone(),
+    };
+    let single_file = std::fs::metadata(&root_dir)?.is_file();
+    let (index, root_dir, single_file) =
+        open_index(&root_dir, single_file, &index_options, false)?;
 
     // The source can't handle multiple input events, so we just implement
     // a Stream, and use a channel for the Sink
@@ -67,7 +146,11 @@ pub fn fs_source(root_dir: PathBuf) -> Result<Source, Error> {
             Box::pin(FsSourceFrom {
                 index,
                 root_dir,
+                single_file,
+                filter: options.filter.clone(),
                 receiver,
+                block_summary: None,
+                pushed_blocks: HashSet::new(),
                 state: FsSourceState::ListFiles(None),
             }),
             FsSourceFrom::stream,
@@ -83,35 +166,54 @@ pub fn fs_source(root_dir: PathBuf) -> Result<Source, Error> {
 }
 
 enum FsSourceState {
-    ListFiles(Option<VecDeque<(Vec<u8>, usize, HashDigest)>>),
+    ListFiles(Option<VecDeque<SourceEvent>>),
     Respond,
-    ListBlocks(VecDeque<(HashDigest, usize)>),
+    /// Listing the blocks of a file, then sending the data of those that the
+    /// destination lacks, before `FileEnd`
+    ListBlocks(VecDeque<(HashDigest, usize)>, VecDeque<HashDigest>),
+    /// Sending the rest of a batch of requested blocks
+    SendBlocks(VecDeque<HashDigest>),
     Done,
 }
 
 struct FsSourceFrom {
     index: Index,
     root_dir: PathBuf,
+    /// Name of the file, if the source is a single file rather than a
+    /// directory. It is sent with an empty name, after `SingleFile`
+    single_file: Option<PathBuf>,
+    /// Filter applied to the listing, in case the index has other entries
+    filter: Filter,
     receiver: Receiver<DestinationEvent>,
+    /// Blocks the destination has, if it sent a summary
+    block_summary: Option<BlockSummary>,
+    /// Blocks already sent without being requested, so they are only sent once
+    pushed_blocks: HashSet<HashDigest>,
     state: FsSourceState,
 }
 
 impl FsSourceFrom {
-    fn project<'b>(self: &'b mut Pin<Box<Self>>) -> (&'b mut Index, &'b Path, Pin<&'b mut Receiver<DestinationEvent>>, &'b mut FsSourceState) {
+    #[allow(clippy::type_complexity)]
+    fn project<'b>(self: &'b mut Pin<Box<Self>>) -> (&'b mut Index, &'b Path, Option<&'b Path>, &'b Filter, Pin<&'b mut Receiver<DestinationEvent>>, &'b mut Option<BlockSummary>, &'b mut HashSet<HashDigest>, &'b mut FsSourceState) {
         unsafe { // Required for pin projection
             let s = self.as_mut().get_unchecked_mut();
             (
                 &mut s.index,
                 &mut s.root_dir,
+                s.single_file.as_deref(),
+                &s.filter,
                 Pin::new_unchecked(&mut s.receiver),
+                &mut s.block_summary,
+                &mut s.pushed_blocks,
                 &mut s.state,
             )
         }
     }
 
+    #[allow(clippy::manual_async_fn, clippy::type_complexity)]
     fn stream(mut stream: Pin<Box<FsSourceFrom>>) -> impl Future<Output=Option<(Result<SourceEvent, Error>, Pin<Box<FsSourceFrom>>)>> {
         async {
-            let (index, root_dir, mut receiver, state) = stream.project();
+            let (index, root_dir, single_file, filter, mut receiver, block_summary, pushed_blocks, state) = stream.project();
 
             macro_rules! err {
                 ($e:expr) => {
@@ -128,133 +230,286 @@ impl FsSourceFrom {
                 }
             }
 
-            match *state {
-                // Send files list
-                FsSourceState::ListFiles(ref mut list) => {
-                    // If we don't have data, fetch from database
-                    if list.is_none() {
-                        // FIXME: Don't get all files at once, iterate
-                        let files = try_!(index.list_files());
-                        let mut new_list = VecDeque::with_capacity(files.len());
-                        for (_file_id, path, _modified, size, blocks_hash) in files {
-                            let path = path
-                                .into_os_string()
-                                .into_string();
-                            let path = try_!(path.map_err(|_: OsString| Error::BadFilenameEncoding));
-                            let path = path.into_bytes();
-                            new_list.push_back((path, size as usize, blocks_hash));
-                        }
-                        debug!("FsSource: preparing to send {} files", new_list.len());
-                        *list = Some(new_list);
-                    }
-                    let list = list.as_mut().unwrap();
-                    match list.pop_front() {
-                        Some((path, size, blocks_hash)) => {
-                            if log_enabled!(Debug) {
-                                debug!("FsSource: send FileEntry({})", String::from_utf8_lossy(&path));
+            // Only loops to wait for the next request, after BlockSummary
+            loop {
+                return match *state {
+                    // Send files list
+                    FsSourceState::ListFiles(ref mut list) => {
+                        // If we don't have data, fetch from database
+                        if list.is_none() {
+                            // FIXME: Don't get all files at once, iterate
+                            let mut directories = try_!(index.list_directories());
+                            let mut files = try_!(index.list_files());
+                            let mut symlinks = try_!(index.list_symlinks());
+                            let mut hard_links: HashMap<PathBuf, PathBuf> = try_!(index.list_hard_links()).into_iter().collect();
+                            if let Some(single_file) = single_file {
+                                // Only send that file
+                                directories.clear();
+                                files.retain(|f| f.1 == single_file);
+                                symlinks.clear();
+                                hard_links.clear();
+                            }
+                            let mut new_list = VecDeque::with_capacity(directories.len() + files.len() + symlinks.len());
+                            // Directories first, so they exist before their content
+                            for (_file_id, path, modified, mode) in directories {
+                                if filter.is_path_excluded(&path, true) {
+                                    continue;
+                                }
+                                let path = try_!(path_to_bytes(&path)).to_owned();
+                                let metadata = FileMetadata { modified, mode };
+                                new_list.push_back(SourceEvent::DirectoryEntry(path, metadata));
+                            }
+                            for (_file_id, path, modified, mode, size, blocks_hash) in files {
+                                if filter.is_path_excluded(&path, false) {
+                                    continue;
+                                }
+                                // Other names for a file are sent as links to it
+                                if let Some(target) = hard_links.get(&path) {
+                                    let path = try_!(path_to_bytes(&path)).to_owned();
+                                    let target = try_!(path_to_bytes(target)).to_owned();
+                                    new_list.push_back(SourceEvent::HardLinkEntry(path, target));
+                                    continue;
+                                }
+                                let path = if single_file.is_some() {
+                                    // A single file is sent without a name
+                                    Vec::new()
+                                } else {
+                                    try_!(path_to_bytes(&path)).to_owned()
+                                };
+                                let metadata = FileMetadata { modified, mode };
+                                new_list.push_back(SourceEvent::FileEntry(path, size, blocks_hash, metadata));
+                            }
+                            for (_file_id, path, target) in symlinks {
+                                if filter.is_path_excluded(&path, false) {
+                                    continue;
+                                }
+                                let path = try_!(path_to_bytes(&path)).to_owned();
+                                let target = try_!(path_to_bytes(&target)).to_owned();
+                                new_list.push_back(SourceEvent::SymlinkEntry(path, target));
                             }
-                            Some((Ok(SourceEvent::FileEntry(path, size, blocks_hash)), stream))
+                            if let Some(single_file) = single_file {
+                                let name = try_!(path_to_bytes(single_file)).to_owned();
+                                new_list.push_front(SourceEvent::SingleFile(name));
+                            }
+                            debug!("FsSource: preparing to send {} entries", new_list.len());
+                            *list = Some(new_list);
                         }
-                        None => {
-                            debug!("FsSource: state=Respond");
-                            *state = FsSourceState::Respond;
-                            debug!("FsSource: send EndFiles");
-                            Some((Ok(SourceEvent::EndFiles), stream))
+                        let list = list.as_mut().unwrap();
+                        match list.pop_front() {
+                            Some(event) => {
+                                if log_enabled!(Debug) {
+                                    debug!("FsSource: send {:?}", event);
+                                }
+                                Some((Ok(event), stream))
+                            }
+                            None => {
+                                debug!("FsSource: state=Respond");
+                                *state = FsSourceState::Respond;
+                                debug!("FsSource: send EndFiles");
+                                Some((Ok(SourceEvent::EndFiles), stream))
+                            }
                         }
                     }
-                }
-                // Files are sent, respond to requests
-                FsSourceState::Respond => {
-                    let req = match receiver.as_mut().next().await {
-                        None => {
-                            debug!("FsSource: got end of input");
-                            return None;
-                        }
-                        Some(e) => e,
-                    };
-                    debug!("FsSource: recv {:?}", req);
-                    match req {
-                        DestinationEvent::GetFile(path) => {
-                            let path_str = try_!(
-                                String::from_utf8(path)
-                                    .map_err(|_: FromUtf8Error| Error::BadFilenameEncoding)
-                            );
-                            let (file_id, _modified, _blocks_hash) = match try_!(index.get_file(Path::new(&path_str))) {
-                                Some(t) => t,
-                                None => return err!(Error::Sync("Requested file is unknown".to_owned())),
-                            };
-                            debug!("FsSource: file_id={}", file_id);
-                            // FIXME: Don't get all blocks at once, iterate
-                            let blocks = try_!(index.list_file_blocks(file_id));
-                            let mut new_blocks = VecDeque::with_capacity(blocks.len());
-                            for (hash, _offset, size) in blocks {
-                                new_blocks.push_back((hash, size));
-                            }
-                            debug!("FsSource: state=ListBlocks");
-                            debug!("FsSource: preparing to send {} blocks", new_blocks.len());
-                            *state = FsSourceState::ListBlocks(new_blocks);
-                            debug!("FsSource: send FileStart");
-                            Some((Ok(SourceEvent::FileStart(path_str.into_bytes())), stream))
-                        }
-                        DestinationEvent::GetBlock(hash) => {
-                            let (path, offset, _size) = match try_!(index.get_block(&hash)) {
-                                Some(t) => t,
-                                None => return err!(Error::Sync("Requested block is unknown".to_owned())),
-                            };
-                            debug!("FsSource: found block in {:?} offset {}", path, offset);
-                            let data = try_!(read_block(&root_dir.join(&path), offset));
-                            debug!("FsSource: send BlockData");
-                            Some((Ok(SourceEvent::BlockData(hash, data)), stream))
-                        }
-                        DestinationEvent::Complete => {
-                            *state = FsSourceState::Done;
-                            debug!("FsSource: state=Done");
-                            None
+                    // Files are sent, respond to requests
+                    FsSourceState::Respond => {
+                        let req = match receiver.as_mut().next().await {
+                            None => {
+                                debug!("FsSource: got end of input");
+                                return None;
+                            }
+                            Some(e) => e,
+                        };
+                        debug!("FsSource: recv {:?}", req);
+                        match req {
+                            DestinationEvent::BlockSummary(summary) => {
+                                // Used when listing blocks, nothing to send back
+                                *block_summary = Some(summary);
+                                continue;
+                            }
+                            DestinationEvent::GetFile(path) => {
+                                let name = match single_file {
+                                    Some(name) if path.is_empty() => name.to_owned(),
+                                    _ => try_!(bytes_to_path(path.clone())),
+                                };
+                                let (file_id, _modified, _mode, _blocks_hash) = match try_!(index.get_file(&name)) {
+                                    Some(t) => t,
+                                    None => return err!(Error::Sync("Requested file is unknown".to_owned())),
+                                };
+                                debug!("FsSource: file_id={}", file_id);
+                                // FIXME: Don't get all blocks at once, iterate
+                                let blocks = try_!(index.list_file_blocks(file_id));
+                                let mut new_blocks = VecDeque::with_capacity(blocks.len());
+                                let mut blocks_to_push = VecDeque::new();
+                                for (hash, _offset, size) in blocks {
+                                    // Send the data of the blocks the
+                                    // destination doesn't have, except zero
+                                    // blocks which it doesn't write
+                                    if let Some(summary) = block_summary {
+                                        if !summary.contains(&hash)
+                                            && hash != zero_digest(size)
+                                            && pushed_blocks.insert(hash.clone())
+                                        {
+                                            blocks_to_push.push_back(hash.clone());
+                                        }
+                                    }
+                                    new_blocks.push_back((hash, size));
+                                }
+                                debug!("FsSource: state=ListBlocks");
+                                debug!(
+                                    "FsSource: preparing to send {} blocks, {} with data",
+                                    new_blocks.len(), blocks_to_push.len(),
+                                );
+                                *state = FsSourceState::ListBlocks(new_blocks, blocks_to_push);
+                                debug!("FsSource: send FileStart");
+                                Some((Ok(SourceEvent::FileStart(path)), stream))
+                            }
+                            DestinationEvent::GetBlocks(hashes) => {
+                                let mut hashes: VecDeque<HashDigest> = hashes.into();
+                                let hash = match hashes.pop_front() {
+                                    Some(h) => h,
+                                    None => return err!(Error::Sync("Empty block request".to_owned())),
+                                };
+                                let event = try_!(get_block_data(index, root_dir, hash));
+                                if !hashes.is_empty() {
+                                    debug!("FsSource: state=SendBlocks({} blocks)", hashes.len());
+                                    *state = FsSourceState::SendBlocks(hashes);
+                                }
+                                debug!("FsSource: send BlockData");
+                                Some((Ok(event), stream))
+                            }
+                            DestinationEvent::Complete => {
+                                *state = FsSourceState::Done;
+                                debug!("FsSource: state=Done");
+                                None
+                            }
                         }
                     }
-                }
-                // List blocks
-                FsSourceState::ListBlocks(ref mut list) => {
-                    match list.pop_front() {
-                        Some((hash, size)) => {
-                            debug!("FsSource: send FileBlock");
-                            Some((Ok(SourceEvent::FileBlock(hash, size)), stream))
+                    // List blocks
+                    FsSourceState::ListBlocks(ref mut list, ref mut blocks_to_push) => {
+                        match list.pop_front() {
+                            Some((hash, size)) => {
+                                debug!("FsSource: send FileBlock");
+                                Some((Ok(SourceEvent::FileBlock(hash, size)), stream))
+                            }
+                            None if !blocks_to_push.is_empty() => {
+                                let hash = blocks_to_push.pop_front().unwrap();
+                                let event = try_!(get_block_data(index, root_dir, hash));
+                                debug!("FsSource: send BlockData (not requested)");
+                                Some((Ok(event), stream))
+                            }
+                            None => {
+                                debug!("FsSource: out of blocks");
+                                debug!("FsSource: state=Respond");
+                                *state = FsSourceState::Respond;
+                                debug!("FsSource: send FileEnd");
+                                Some((Ok(SourceEvent::FileEnd), stream))
+                            }
                         }
-                        None => {
-                            debug!("FsSource: out of blocks");
+                    }
+                    // Send the blocks requested in a batch
+                    FsSourceState::SendBlocks(ref mut hashes) => {
+                        let hash = hashes.pop_front().unwrap();
+                        if hashes.is_empty() {
                             debug!("FsSource: state=Respond");
                             *state = FsSourceState::Respond;
-                            debug!("FsSource: send FileEnd");
-                            Some((Ok(SourceEvent::FileEnd), stream))
                         }
+                        let event = try_!(get_block_data(index, root_dir, hash));
+                        debug!("FsSource: send BlockData");
+                        Some((Ok(event), stream))
                     }
-                }
-                // Stream is done
-                FsSourceState::Done => None,
+                    // Stream is done
+                    FsSourceState::Done => None,
+                };
             }
         }
     }
 }
 
-pub fn fs_destination(root_dir: PathBuf) -> Result<Destination, Error> {
-    info!(
-        "Indexing destination into {:?}...",
-        root_dir.join(".syncfast.idx")
-    );
-    std::fs::create_dir_all(&root_dir)?;
-    let mut index = Index::open(&root_dir.join(".syncfast.idx"))?;
-    index.index_path(&root_dir)?;
-    index.remove_missing_files(&root_dir)?;
-    index.commit()?;
+/// The metadata from the source as it is applied, see `set_metadata()`
+fn applied_metadata(metadata: FileMetadata) -> FileMetadata {
+    FileMetadata {
+        mode: metadata.mode & PERMISSION_BITS,
+        ..metadata
+    }
+}
+
+/// Summary of the blocks we have, so the source can send the others without
+/// waiting for requests
+///
+/// This is `None` if disabled by the options, or in a dry run where we don't
+/// want any data.
+fn make_block_summary(
+    index: &Index,
+    options: &DestinationOptions,
+) -> Result<Option<BlockSummary>, Error> {
+    if !options.block_summary || options.dry_run {
+        return Ok(None);
+    }
+    let blocks = index.list_present_blocks()?;
+    let mut summary = BlockSummary::new(blocks.len());
+    for hash in &blocks {
+        summary.insert(hash);
+    }
+    debug!("FsDestination: summary of {} blocks", blocks.len());
+    Ok(Some(summary))
+}
+
+pub fn fs_destination(
+    root_dir: PathBuf,
+    options: &DestinationOptions,
+) -> Result<Destination, Error> {
+    // If the location is a directory or doesn't exist, we have to wait for
+    // the first entry to know whether we are receiving a directory or a
+    // single file, before indexing anything
+    let (index, root_dir, single_file, is_dir) = match std::fs::metadata(&root_dir) {
+        Ok(metadata) if metadata.is_file() => {
+            let (index, root_dir, single_file) = open_index(
+                &root_dir,
+                true,
+                &destination_index_options(options),
+                options.dry_run,
+            )?;
+            (Some(index), root_dir, single_file, false)
+        }
+        Ok(_) => (None, root_dir, None, true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            (None, root_dir, None, false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let block_summary = match index {
+        Some(ref index) => make_block_summary(index, options)?,
+        None => None,
+    };
 
     // The destination has to handle input while producing output (for
-    // example getting BlockData while sending GetBlock), so it has both a
+    // example getting BlockData while sending GetBlocks), so it has both a
     // custom Stream and Sink implementations
     // State changes are triggered by Sink
     let destination = Rc::new(RefCell::new(FsDestinationInner {
         index,
         root_dir,
-        state: FsDestinationState::FilesList { cond: Default::default() },
+        single_file,
+        is_dir,
+        got_entries: false,
+        block_summary,
+        options: options.clone(),
+        pending: Pending {
+            extraneous_files: Vec::new(),
+            directories: Vec::new(),
+            hard_links: Vec::new(),
+            report: if options.dry_run {
+                Some(Default::default())
+            } else {
+                None
+            },
+        },
+        state: FsDestinationState::FilesList {
+            cond: Default::default(),
+            listed_files: HashSet::new(),
+            temp_files: HashSet::new(),
+        },
+        source_closed: false,
     }));
     debug!("FsDestination: state=FilesList");
     Ok(Destination {
@@ -265,22 +520,172 @@ pub fn fs_destination(root_dir: PathBuf) -> Result<Destination, Error> {
         ).boxed_local(),
         // Sink handling events using FsDestination::sink
         sink: Box::pin(futures::sink::unfold(
-            destination,
+            FsDestinationSink(destination),
             FsDestinationInner::sink,
         )),
     })
 }
 
+fn destination_index_options(options: &DestinationOptions) -> IndexOptions {
+    IndexOptions {
+        filter: options.filter.clone(),
+        ..Default::default()
+    }
+}
+
+/// Get the name of an entry in the destination from the name sent by the
+/// source, which is empty if the source is a single file
+fn entry_path(single_file: Option<&Path>, path: Vec<u8>) -> Result<PathBuf, Error> {
+    match single_file {
+        Some(name) if path.is_empty() => Ok(name.to_owned()),
+        Some(_) => Err(Error::Sync(
+            "Source is a directory but destination is a file".to_owned(),
+        )),
+        None if path.is_empty() => Err(Error::Sync(
+            "Empty name from source".to_owned(),
+        )),
+        None => {
+            let path = bytes_to_path(path)?;
+            check_entry_name(&path)?;
+            Ok(path)
+        }
+    }
+}
+
+/// Check that a name from the source is relative and stays inside the
+/// destination, i.e. is not empty and has no root and no `..`
+fn check_entry_name(path: &Path) -> Result<(), Error> {
+    if path.as_os_str().is_empty() {
+        return Err(Error::Sync("Empty name from source".to_owned()));
+    }
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => {}
+            _ => return Err(Error::Sync(format!(
+                "Invalid name from source {:?}",
+                path,
+            ))),
+        }
+    }
+    Ok(())
+}
+
+/// Check that none of the parent directories of an entry are symbolic links,
+/// which would make us write outside of the destination
+fn check_parents(root_dir: &Path, path: &Path) -> Result<(), Error> {
+    let mut parent = root_dir.to_owned();
+    let mut components = path.components();
+    components.next_back();
+    for component in components {
+        parent.push(component);
+        match std::fs::symlink_metadata(&parent) {
+            Ok(m) if m.file_type().is_symlink() => {
+                return Err(Error::Sync(format!(
+                    "Parent of {:?} is a symbolic link",
+                    path,
+                )));
+            }
+            Ok(_) => {}
+            // Will be created as a directory
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// How often to commit the index while receiving blocks, so that an
+/// interrupted sync can be resumed
+const COMMIT_EVERY_BLOCKS: usize = 64;
+
+/// How many blocks to request in a single `GetBlocks`
+const GET_BLOCKS_BATCH: usize = 256;
+
+/// How many blocks can be requested but not yet received
+///
+/// This keeps requests flowing while earlier blocks are still in transit,
+/// without queueing the whole list at the source.
+const MAX_BLOCKS_IN_FLIGHT: usize = 4 * GET_BLOCKS_BATCH;
+
 struct FsDestinationInner {
-    index: Index,
+    /// The index, None until we know whether to receive a directory or a
+    /// single file
+    index: Option<Index>,
     root_dir: PathBuf,
+    /// Name of the file, if the destination is a single file rather than a
+    /// directory
+    single_file: Option<PathBuf>,
+    /// The destination is an existing directory
+    is_dir: bool,
+    /// Entries were received, so it's too late for `SingleFile`
+    got_entries: bool,
+    /// Summary of our blocks, until the stream sends it
+    block_summary: Option<BlockSummary>,
+    options: DestinationOptions,
+    pending: Pending,
     state: FsDestinationState,
+    /// The source went away before the sync was complete, the stream reports
+    /// it
+    source_closed: bool,
+}
+
+/// Handle on the destination owned by its sink
+///
+/// Dropping it means no more events will come from the source, so the stream
+/// can't be left waiting for them.
+struct FsDestinationSink(Rc<RefCell<FsDestinationInner>>);
+
+impl Drop for FsDestinationSink {
+    fn drop(&mut self) {
+        let mut inner = self.0.borrow_mut();
+        if !inner.state.is_done() {
+            debug!("FsDestination: source closed early");
+            inner.source_closed = true;
+            inner.state = FsDestinationState::done();
+        }
+    }
+}
+
+/// Changes decided from the file list, applied at the end of the sync
+struct Pending {
+    /// Files that are not in the source, to be deleted (from `EndFiles`)
+    ///
+    /// Directories come last, deepest first, so they are empty by the time
+    /// they get deleted.
+    extraneous_files: Vec<(u32, PathBuf)>,
+    /// Directories listed by the source, their metadata is applied at the
+    /// end, once their content is no longer changing
+    directories: Vec<(PathBuf, FileMetadata)>,
+    /// Hard links listed by the source `(name, target)`, created once the
+    /// files they point to are in place
+    hard_links: Vec<(PathBuf, PathBuf)>,
+    /// What would be done, if this is a dry run
+    report: Option<DryRunReport>,
+}
+
+/// Summary of the changes that a dry run would have made
+#[derive(Default)]
+struct DryRunReport {
+    files_created: usize,
+    files_updated: usize,
+    files_deleted: usize,
+    /// Bytes that would be received from the source
+    bytes_transferred: usize,
+    /// Bytes that would be copied from files already at the destination
+    bytes_reused: usize,
+    /// Blocks already counted in `bytes_transferred`
+    missing_blocks: HashSet<HashDigest>,
 }
 
 enum FsDestinationState {
     FilesList {
         /// Sink indicates state change (`SourceEvent::EndFiles`)
         cond: Condition,
+        /// Files listed by the source, only recorded if deleting
+        listed_files: HashSet<PathBuf>,
+        /// Temporary entries for the files listed by the source, others are
+        /// left from previous syncs and can be dropped
+        temp_files: HashSet<u32>,
     },
     GetFiles {
         /// List of files to request the blocks of
@@ -295,12 +700,38 @@ enum FsDestinationState {
     GetBlocks {
         /// List of blocks to request, None if we've sent `DestinationEvent::Complete`
         blocks_to_request: Option<VecDeque<HashDigest>>,
-        /// Number of blocks to receive
-        blocks_to_receive: usize,
+        /// Blocks to receive, whether they have been requested yet or not
+        blocks_to_receive: HashSet<HashDigest>,
+        /// Blocks requested but not yet received
+        in_flight: HashSet<HashDigest>,
+        /// Sink indicates room in the window (stream waits on it when
+        /// `MAX_BLOCKS_IN_FLIGHT` is reached)
+        cond: Option<Condition>,
     },
 }
 
+impl FsDestinationState {
+    /// The state once everything has been received, or after an error, where
+    /// the stream ends
+    fn done() -> FsDestinationState {
+        FsDestinationState::GetBlocks {
+            blocks_to_request: None,
+            blocks_to_receive: HashSet::new(),
+            in_flight: HashSet::new(),
+            cond: None,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self {
+            FsDestinationState::GetBlocks { blocks_to_request: None, blocks_to_receive, .. } => blocks_to_receive.is_empty(),
+            _ => false,
+        }
+    }
+}
+
 impl FsDestinationInner {
+    #[allow(clippy::manual_async_fn, clippy::type_complexity)]
     fn stream(inner: Rc<RefCell<FsDestinationInner>>) -> impl Future<Output=Option<(Result<DestinationEvent, Error>, Rc<RefCell<FsDestinationInner>>)>> {
         async move {
             loop {
@@ -309,10 +740,23 @@ impl FsDestinationInner {
                     Wait(ConditionFuture),
                     Return(DestinationEvent),
                 }
+                if inner.borrow().source_closed {
+                    inner.borrow_mut().source_closed = false;
+                    return Some((
+                        Err(Error::Sync("Source closed before the sync was complete".to_owned())),
+                        inner,
+                    ));
+                }
+                // Send the summary first, once we know what we are receiving
+                let block_summary = inner.borrow_mut().block_summary.take();
+                if let Some(summary) = block_summary {
+                    debug!("FsDestination::stream: send BlockSummary");
+                    return Some((Ok(DestinationEvent::BlockSummary(summary)), inner));
+                }
                 let what_to_do = match inner.borrow_mut().state {
-                    // Receive files list
-                    FsDestinationState::FilesList { ref mut cond } => {
-                        // Nothing to produce, wait for state change
+                    // Receive files list, nothing to produce, wait for state
+                    // change
+                    FsDestinationState::FilesList { ref mut cond, .. } => {
                         WhatToDo::Wait(cond.wait())
                     }
                     // Request blocks for files
@@ -331,17 +775,26 @@ impl FsDestinationInner {
                         }
                     }
                     // Request block data
-                    FsDestinationState::GetBlocks { ref mut blocks_to_request, .. } => {
+                    FsDestinationState::GetBlocks { ref mut blocks_to_request, ref mut in_flight, ref mut cond, .. } => {
                         match blocks_to_request {
-                            Some(ref mut l) => match l.pop_front() {
-                                Some(hash) => {
-                                    debug!("FsDestination::stream: send GetBlock({})", hash);
-                                    WhatToDo::Return(DestinationEvent::GetBlock(hash))
-                                }
-                                None => {
-                                    debug!("FsDestination::stream: no more blocks, send Complete");
-                                    *blocks_to_request = None;
-                                    WhatToDo::Return(DestinationEvent::Complete)
+                            Some(ref mut l) if l.is_empty() => {
+                                debug!("FsDestination::stream: no more blocks, send Complete");
+                                *blocks_to_request = None;
+                                WhatToDo::Return(DestinationEvent::Complete)
+                            }
+                            Some(ref mut l) => {
+                                let count = l.len().min(GET_BLOCKS_BATCH);
+                                if in_flight.len() + count > MAX_BLOCKS_IN_FLIGHT {
+                                    debug!("FsDestination::stream: {} blocks in flight, waiting...", in_flight.len());
+                                    let mut c = Condition::default();
+                                    let wait = c.wait();
+                                    *cond = Some(c);
+                                    WhatToDo::Wait(wait)
+                                } else {
+                                    let hashes: Vec<HashDigest> = l.drain(..count).collect();
+                                    in_flight.extend(hashes.iter().cloned());
+                                    debug!("FsDestination::stream: send GetBlocks({} blocks)", count);
+                                    WhatToDo::Return(DestinationEvent::GetBlocks(hashes))
                                 }
                             }
                             None => {
@@ -359,175 +812,504 @@ impl FsDestinationInner {
         }
     }
 
-    fn sink(inner: Rc<RefCell<FsDestinationInner>>, event: SourceEvent) -> impl Future<Output=Result<Rc<RefCell<FsDestinationInner>>, Error>> {
+    #[allow(clippy::manual_async_fn)]
+    fn sink(inner: FsDestinationSink, event: SourceEvent) -> impl Future<Output=Result<FsDestinationSink, Error>> {
         async move {
             {
-                let mut inner_: std::cell::RefMut<FsDestinationInner> = inner.borrow_mut();
-                let inner_: &mut FsDestinationInner = inner_.deref_mut();
-
-                // Can't mutably borrow more than once
-                let mut new_state: Option<FsDestinationState> = None;
-                let state = &mut inner_.state;
-                let index = &mut inner_.index;
-                let root_dir = &inner_.root_dir;
-
-                debug!("FsDestination::sink: recv {:?}", event);
-
-                match state {
-                    // Receive files list
-                    FsDestinationState::FilesList { ref mut cond } => {
-                        match event {
-                            SourceEvent::FileEntry(path, _size, blocks_hash) => {
-                                let path: PathBuf = String::from_utf8(path)
-                                    .map_err(|_: FromUtf8Error| Error::BadFilenameEncoding)?
-                                    .into();
-                                let file = inner_.index.get_file(&path)?;
-                                let add = match file {
-                                    Some((_file_id, _modified, recorded_blocks_hash)) => {
-                                        if blocks_hash == recorded_blocks_hash {
-                                            debug!("FsDestination::sink:  file's blocks_hash matches");
-                                            false // File is up to date, do nothing
+                let mut inner_ = inner.0.borrow_mut();
+                if let Err(e) = inner_.handle_event(event) {
+                    // End the stream too, instead of leaving it waiting
+                    inner_.state = FsDestinationState::done();
+                    return Err(e);
+                }
+            }
+            Ok(inner)
+        }
+    }
+
+    fn handle_event(&mut self, event: SourceEvent) -> Result<(), Error> {
+        if let SourceEvent::SingleFile(name) = event {
+            return self.receive_single_file(name);
+        }
+        self.got_entries = true;
+        if self.index.is_none() {
+            // Not a single file, create the directory
+            let (index, root_dir, single_file) = open_index(
+                &self.root_dir,
+                false,
+                &destination_index_options(&self.options),
+                self.options.dry_run,
+            )?;
+            self.block_summary = make_block_summary(&index, &self.options)?;
+            self.index = Some(index);
+            self.root_dir = root_dir;
+            self.single_file = single_file;
+        }
+
+        // Can't mutably borrow more than once
+        let mut new_state: Option<FsDestinationState> = None;
+        let state = &mut self.state;
+        let index = self.index.as_mut().unwrap();
+        let root_dir = &self.root_dir;
+        let single_file = self.single_file.as_deref();
+        let delete_mode = self.options.delete;
+        let pending = &mut self.pending;
+
+        debug!("FsDestination::sink: recv {:?}", event);
+
+        // Only the permission bits are applied, so only those are recorded
+        // and compared
+        let event = match event {
+            SourceEvent::FileEntry(path, size, blocks_hash, metadata) => {
+                SourceEvent::FileEntry(path, size, blocks_hash, applied_metadata(metadata))
+            }
+            SourceEvent::DirectoryEntry(path, metadata) => {
+                SourceEvent::DirectoryEntry(path, applied_metadata(metadata))
+            }
+            event => event,
+        };
+
+        match state {
+            // Receive files list
+            FsDestinationState::FilesList { ref mut cond, ref mut listed_files, ref mut temp_files, .. } => {
+                match event {
+                    SourceEvent::FileEntry(path, _size, blocks_hash, metadata) => {
+                        let path = entry_path(single_file, path)?;
+                        check_parents(root_dir, &path)?;
+                        let file = index.get_file(&path)?;
+                        let exists = file.is_some();
+                        let add = match file {
+                            Some((file_id, modified, mode, recorded_blocks_hash)) => {
+                                if blocks_hash == recorded_blocks_hash {
+                                    debug!("FsDestination::sink:  file's blocks_hash matches");
+                                    if modified != metadata.modified || mode & PERMISSION_BITS != metadata.mode {
+                                        // Content is up to date, only update metadata
+                                        debug!("FsDestination::sink: file's metadata differs");
+                                        if pending.report.is_some() {
+                                            info!(target: DRY_RUN_LOG_TARGET, "Would update metadata of {:?}", path);
                                         } else {
-                                            debug!("FsDestination::sink: file exists but blocks_hash differs");
-                                            true
+                                            set_metadata(&root_dir.join(&path), &metadata)?;
+                                            index.set_file_metadata(file_id, metadata.modified, metadata.mode)?;
                                         }
                                     }
-                                    None => {
-                                        debug!("FsDestination::sink: file doesn't exist");
-                                        true
-                                    }
-                                };
-                                if add {
-                                    // Create temporary file
-                                    inner_.index.add_temp_file(&path)?;
-                                    let temp_path = inner_.root_dir.join(temp_name(&path)?);
-                                    debug!("FsDestination::sink: creating temp file {:?}", temp_path);
-                                    if let Some(parent) = temp_path.parent() {
-                                        std::fs::create_dir_all(parent)?;
-                                    }
-                                    OpenOptions::new()
-                                        .write(true)
-                                        .truncate(true)
-                                        .create(true)
-                                        .open(temp_path)?;
+                                    false // File is up to date, do nothing
+                                } else {
+                                    debug!("FsDestination::sink: file exists but blocks_hash differs");
+                                    true
                                 }
                             }
-                            SourceEvent::EndFiles => {
-                                // FIXME: Don't get all files at once, iterate
-                                let mut files_to_request = VecDeque::new();
-                                for name in index.list_temp_files()? {
-                                    let name = untemp_name(&name)?;
-                                    let name = name
-                                        .into_os_string()
-                                        .into_string()
-                                        .map_err(|_: OsString| Error::BadFilenameEncoding)?
-                                        .into_bytes();
-                                    files_to_request.push_back(name);
+                            None => {
+                                debug!("FsDestination::sink: file doesn't exist");
+                                true
+                            }
+                        };
+                        if let (true, Some(report)) = (add, &mut pending.report) {
+                            if exists {
+                                info!(target: DRY_RUN_LOG_TARGET, "Would update file {:?}", path);
+                                report.files_updated += 1;
+                            } else {
+                                info!(target: DRY_RUN_LOG_TARGET, "Would create file {:?}", path);
+                                report.files_created += 1;
+                            }
+                            // Still record it, to get its blocks
+                            let (file_id, _resumed) = index.add_temp_file(&path, metadata.modified, metadata.mode, &blocks_hash, false)?;
+                            temp_files.insert(file_id);
+                        } else if add {
+                            // Create temporary file, or keep the one
+                            // left from an interrupted sync
+                            let temp_path = root_dir.join(temp_name(&path)?);
+                            let (file_id, resumed) = index.add_temp_file(&path, metadata.modified, metadata.mode, &blocks_hash, temp_path.is_file())?;
+                            temp_files.insert(file_id);
+                            if resumed {
+                                debug!("FsDestination::sink: resuming temp file {:?}", temp_path);
+                            } else {
+                                debug!("FsDestination::sink: creating temp file {:?}", temp_path);
+                                if let Some(parent) = temp_path.parent() {
+                                    std::fs::create_dir_all(parent)?;
                                 }
-                                if !files_to_request.is_empty() {
-                                    let files_to_receive = files_to_request.len();
-                                    debug!("FsDestination::sink: state=GetFiles({} files)", files_to_receive);
-                                    new_state = Some(FsDestinationState::GetFiles {
-                                        files_to_request,
-                                        files_to_receive,
-                                        cond: Default::default(),
-                                        file_blocks_id: None,
-                                    });
-                                } else {
-                                    debug!("FsDestination::sink: state=GetBlocks(0 blocks)");
-                                    new_state = Some(FsDestinationState::GetBlocks {
-                                        blocks_to_request: Some(VecDeque::new()),
-                                        blocks_to_receive: 0,
-                                    });
+                                OpenOptions::new()
+                                    .write(true)
+                                    .truncate(true)
+                                    .create(true)
+                                    .open(temp_path)?;
+                            }
+                        }
+                        if delete_mode != DeleteMode::Never {
+                            listed_files.insert(path);
+                        }
+                    }
+                    SourceEvent::SymlinkEntry(path, target) => {
+                        let path = entry_path(single_file, path)?;
+                        check_parents(root_dir, &path)?;
+                        let target = bytes_to_path(target)?;
+                        let up_to_date = match index.get_symlink(&path)? {
+                            Some((_file_id, recorded_target)) => recorded_target == target,
+                            None => false,
+                        };
+                        if up_to_date {
+                            debug!("FsDestination::sink: symlink is up to date");
+                        } else {
+                            debug!("FsDestination::sink: symlink needs to be created");
+                            if pending.report.is_some() {
+                                info!(target: DRY_RUN_LOG_TARGET, "Would create symlink {:?} -> {:?}", path, target);
+                            }
+                            temp_files.insert(index.add_temp_symlink(&path, &target)?);
+                        }
+                        if delete_mode != DeleteMode::Never {
+                            listed_files.insert(path);
+                        }
+                    }
+                    SourceEvent::DirectoryEntry(path, metadata) => {
+                        let path = entry_path(single_file, path)?;
+                        check_parents(root_dir, &path)?;
+                        let full_path = root_dir.join(&path);
+                        match std::fs::symlink_metadata(&full_path) {
+                            Ok(m) if m.is_dir() => {}
+                            Ok(_) if pending.report.is_some() => {
+                                info!(target: DRY_RUN_LOG_TARGET, "Would replace {:?} with a directory", path);
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound && pending.report.is_some() => {
+                                info!(target: DRY_RUN_LOG_TARGET, "Would create directory {:?}", path);
+                            }
+                            Ok(_) => {
+                                // Something else is in the way
+                                debug!("FsDestination::sink: replacing {:?} with a directory", path);
+                                std::fs::remove_file(&full_path)?;
+                                if let Some((file_id, _, _, _)) = index.get_file(&path)? {
+                                    index.remove_file(file_id)?;
+                                } else if let Some((file_id, _)) = index.get_symlink(&path)? {
+                                    index.remove_file(file_id)?;
                                 }
-                                cond.set();
+                                std::fs::create_dir_all(&full_path)?;
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                                debug!("FsDestination::sink: creating directory {:?}", path);
+                                std::fs::create_dir_all(&full_path)?;
                             }
-                            _ => return Err(Error::Sync("Unexpected message from source".to_owned())),
+                            Err(e) => return Err(e.into()),
                         }
+                        if delete_mode != DeleteMode::Never {
+                            listed_files.insert(path.clone());
+                        }
+                        pending.directories.push((path, metadata));
                     }
-                    // Receive blocks for files
-                    FsDestinationState::GetFiles { ref mut cond, ref mut file_blocks_id, ref mut files_to_receive, .. } => {
-                        *file_blocks_id = match (*file_blocks_id, event) {
-                            (None, SourceEvent::FileStart(path)) => {
-                                let path: PathBuf = String::from_utf8(path)
-                                    .map_err(|_: FromUtf8Error| Error::BadFilenameEncoding)?
-                                    .into();
-                                let (file_id, _modified) = index.get_temp_file(&path)?
-                                    .ok_or(Error::Sync(format!("Unknown file {:?}", path)))?;
-                                Some((file_id, 0))
-                            }
-                            // FIXME: Don't need to capture all of them by ref,
-                            // but necessary for Rust 1.45
-                            (Some((file_id, offset)), SourceEvent::FileBlock(ref hash, ref size)) => {
-                                // See if we have this block, to copy it right now
-                                match index.get_block(&hash)? {
-                                    Some((from_path, from_offset, _from_size)) => {
-                                        let path = index.get_file_name(file_id)?;
-                                        let path = path.ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, "File gone from index during sync"))?;
-                                        debug!("FsDestination::sink: Copying block from {:?} offset {:?}", from_path, from_offset);
-                                        let block = read_block(&root_dir.join(&from_path), from_offset)?;
-                                        write_block(&root_dir.join(&path), offset, &block)?;
-                                        index.add_block(&hash, file_id, offset, *size)?;
-                                    }
-                                    None => {
-                                        debug!("FsDestination::sink: Don't know that block");
-                                        index.add_missing_block(&hash, file_id, offset, *size)?;
-                                    }
+                    SourceEvent::HardLinkEntry(path, target) => {
+                        let path = entry_path(single_file, path)?;
+                        let target = bytes_to_path(target)?;
+                        check_entry_name(&target)?;
+                        check_parents(root_dir, &path)?;
+                        check_parents(root_dir, &target)?;
+                        if delete_mode != DeleteMode::Never {
+                            listed_files.insert(path.clone());
+                        }
+                        pending.hard_links.push((path, target));
+                    }
+                    SourceEvent::EndFiles => {
+                        if delete_mode != DeleteMode::Never {
+                            // FIXME: Don't get all files at once, iterate
+                            for (file_id, name, _modified, _mode, _size, _blocks_hash) in index.list_files()? {
+                                if !listed_files.contains(&name) {
+                                    pending.extraneous_files.push((file_id, name));
                                 }
-                                Some((file_id, offset + size))
-                            }
-                            (Some((file_id, offset)), SourceEvent::FileEnd) => {
-                                index.set_file_size_and_compute_blocks_hash(file_id, offset)?;
-                                *files_to_receive -= 1;
-                                debug!("FsDestination::sink: {} files left to receive", *files_to_receive);
-                                if *files_to_receive == 0 {
-                                    // FIXME: Don't get all files at once, iterate
-                                    let mut blocks_to_request = VecDeque::new();
-                                    for hash in index.list_missing_blocks()? {
-                                        blocks_to_request.push_back(hash);
-                                    }
-                                    let blocks_to_receive = blocks_to_request.len();
-                                    debug!("FsDestination::sink: state=GetBlocks({} blocks)", blocks_to_receive);
-                                    new_state = Some(FsDestinationState::GetBlocks {
-                                        blocks_to_request: Some(blocks_to_request),
-                                        blocks_to_receive,
-                                    });
-                                    cond.set();
+                            }
+                            for (file_id, name, _target) in index.list_symlinks()? {
+                                if !listed_files.contains(&name) {
+                                    pending.extraneous_files.push((file_id, name));
                                 }
-                                None
                             }
-                            _ => return Err(Error::Sync("Unexpected message from source".to_owned())),
+                            // Parents are listed first, reverse
+                            for (file_id, name, _modified, _mode) in index.list_directories()?.into_iter().rev() {
+                                if !listed_files.contains(&name) {
+                                    pending.extraneous_files.push((file_id, name));
+                                }
+                            }
+                            debug!("FsDestination::sink: {} extraneous files", pending.extraneous_files.len());
+                            if delete_mode == DeleteMode::Before {
+                                Self::delete_extraneous(root_dir, index, pending)?;
+                            }
+                        }
+
+                        // FIXME: Don't get all files at once, iterate
+                        let mut files_to_request = VecDeque::new();
+                        for (file_id, name, complete) in index.list_temp_files()? {
+                            if !temp_files.contains(&file_id) {
+                                // Left from a previous sync, no longer wanted
+                                if pending.report.is_none() {
+                                    debug!("FsDestination::sink: removing stale temp file {:?}", name);
+                                    remove_file_if_exists(&root_dir.join(&name))?;
+                                    index.remove_file(file_id)?;
+                                }
+                                continue;
+                            }
+                            if complete {
+                                // Resumed, we already have the list of blocks
+                                continue;
+                            }
+                            let name = if single_file.is_some() {
+                                Vec::new()
+                            } else {
+                                path_to_bytes(&untemp_name(&name)?)?.to_owned()
+                            };
+                            files_to_request.push_back(name);
+                        }
+                        for (file_id, name, _target) in index.list_temp_symlinks()? {
+                            if !temp_files.contains(&file_id) && pending.report.is_none() {
+                                debug!("FsDestination::sink: removing stale temp symlink {:?}", name);
+                                remove_file_if_exists(&root_dir.join(&name))?;
+                                index.remove_file(file_id)?;
+                            }
+                        }
+                        if pending.report.is_none() {
+                            // Record progress, in case we get interrupted
+                            index.commit()?;
                         }
+                        if !files_to_request.is_empty() {
+                            let files_to_receive = files_to_request.len();
+                            debug!("FsDestination::sink: state=GetFiles({} files)", files_to_receive);
+                            new_state = Some(FsDestinationState::GetFiles {
+                                files_to_request,
+                                files_to_receive,
+                                cond: Default::default(),
+                                file_blocks_id: None,
+                            });
+                        } else {
+                            new_state = Some(Self::get_blocks(root_dir, index, delete_mode, pending)?);
+                        }
+                        cond.set();
+                    }
+                    _ => return Err(Error::Sync("Unexpected message from source".to_owned())),
+                }
+            }
+            // Receive blocks for files
+            FsDestinationState::GetFiles { ref mut cond, ref mut file_blocks_id, ref mut files_to_receive, .. } => {
+                *file_blocks_id = match (*file_blocks_id, event) {
+                    (None, SourceEvent::FileStart(path)) => {
+                        let path = entry_path(single_file, path)?;
+                        let (file_id, _modified) = index.get_temp_file(&path)?
+                            .ok_or(Error::Sync(format!("Unknown file {:?}", path)))?;
+                        Some((file_id, 0))
                     }
-                    // Receiving block data
-                    FsDestinationState::GetBlocks { ref mut blocks_to_receive, .. } => {
-                        match event {
-                            SourceEvent::BlockData(hash, data) => {
-                                for (file_id, name, offset, _size) in index.list_block_locations(&hash)? {
-                                    debug!("FsDestination::sink: writing block to {:?} offset {}", name, offset);
-                                    write_block(&root_dir.join(&name), offset, &data)?;
-                                    index.mark_block_present(file_id, &hash, offset)?;
+                    // FIXME: Don't need to capture all of them by ref,
+                    // but necessary for Rust 1.45
+                    (Some((file_id, offset)), SourceEvent::FileBlock(ref hash, ref size)) => {
+                        if *hash == zero_digest(*size) {
+                            // Leave a hole, the file's length is set at the end
+                            debug!("FsDestination::sink: Zero block, leaving a hole");
+                            index.add_block(hash, file_id, offset, *size)?;
+                        } else if let Some(report) = &mut pending.report {
+                            // Only count what would be copied or received
+                            if index.get_block(hash)?.is_some() {
+                                report.bytes_reused += *size;
+                                index.add_block(hash, file_id, offset, *size)?;
+                            } else {
+                                if report.missing_blocks.insert(hash.clone()) {
+                                    report.bytes_transferred += *size;
+                                }
+                                index.add_missing_block(hash, file_id, offset, *size)?;
+                            }
+                        } else {
+                            // See if we have this block, to copy it right now
+                            match index.get_block(hash)? {
+                                Some((from_path, from_offset, _from_size)) => {
+                                    let path = index.get_file_name(file_id)?;
+                                    let path = path.ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, "File gone from index during sync"))?;
+                                    debug!("FsDestination::sink: Copying block from {:?} offset {:?}", from_path, from_offset);
+                                    let block = read_block(&root_dir.join(&from_path), from_offset)?;
+                                    write_block(&root_dir.join(&path), offset, &block)?;
+                                    index.add_block(hash, file_id, offset, *size)?;
                                 }
-                                *blocks_to_receive -= 1;
-                                debug!("FsDestination::sink: {} blocks left to receive", *blocks_to_receive);
-                                if *blocks_to_receive == 0 {
-                                    Self::finish(root_dir, index)?;
+                                None => {
+                                    debug!("FsDestination::sink: Don't know that block");
+                                    index.add_missing_block(hash, file_id, offset, *size)?;
                                 }
                             }
-                            _ => return Err(Error::Sync("Unexpected message from source".to_owned())),
                         }
+                        Some((file_id, offset + size))
+                    }
+                    // Data sent along with the block list, because our
+                    // summary says we don't have it
+                    (Some(position), SourceEvent::BlockData(ref hash, ref data)) if pending.report.is_none() => {
+                        debug!("FsDestination::sink: got block data without requesting it");
+                        Self::write_block_data(root_dir, index, hash, data)?;
+                        Some(position)
+                    }
+                    (Some((file_id, offset)), SourceEvent::FileEnd) => {
+                        // Set the length, in case the file ends with a hole
+                        if pending.report.is_none() {
+                            let path = index.get_file_name(file_id)?;
+                            let path = path.ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, "File gone from index during sync"))?;
+                            OpenOptions::new()
+                                .write(true)
+                                .open(root_dir.join(&path))?
+                                .set_len(offset as u64)?;
+                        }
+                        if !index.set_file_size_and_check_blocks_hash(file_id, offset)? {
+                            return Err(Error::Sync(format!(
+                                "Received block list doesn't match file {:?}",
+                                index.get_file_name(file_id)?,
+                            )));
+                        }
+                        *files_to_receive -= 1;
+                        debug!("FsDestination::sink: {} files left to receive", *files_to_receive);
+                        if pending.report.is_none() {
+                            index.commit()?;
+                        }
+                        if *files_to_receive == 0 {
+                            new_state = Some(Self::get_blocks(root_dir, index, delete_mode, pending)?);
+                            cond.set();
+                        }
+                        None
                     }
+                    _ => return Err(Error::Sync("Unexpected message from source".to_owned())),
                 }
-                if let Some(s) = new_state {
-                    *state = s;
+            }
+            // Receiving block data
+            FsDestinationState::GetBlocks { ref mut blocks_to_request, ref mut blocks_to_receive, ref mut in_flight, ref mut cond } => {
+                match event {
+                    SourceEvent::BlockData(hash, data) => {
+                        if !blocks_to_receive.remove(&hash) {
+                            return Err(Error::Sync("Received a block that wasn't requested".to_owned()));
+                        }
+                        Self::write_block_data(root_dir, index, &hash, &data)?;
+                        if !in_flight.remove(&hash) {
+                            // Sent before we asked for it, don't ask anymore
+                            debug!("FsDestination::sink: got block data without requesting it");
+                            if let Some(l) = blocks_to_request {
+                                l.retain(|h| *h != hash);
+                            }
+                        }
+                        debug!("FsDestination::sink: {} blocks left to receive", blocks_to_receive.len());
+                        // Let the stream request more if there is room now
+                        if in_flight.len() + GET_BLOCKS_BATCH <= MAX_BLOCKS_IN_FLIGHT {
+                            if let Some(mut c) = cond.take() {
+                                c.set();
+                            }
+                        }
+                        if blocks_to_receive.len() % COMMIT_EVERY_BLOCKS == 0 {
+                            // Record progress, in case we get interrupted
+                            index.commit()?;
+                        }
+                        if blocks_to_receive.is_empty() {
+                            Self::finish(root_dir, index, delete_mode, pending)?;
+                        }
+                    }
+                    _ => return Err(Error::Sync("Unexpected message from source".to_owned())),
                 }
             }
-            Ok(inner)
         }
+        if let Some(s) = new_state {
+            *state = s;
+        }
+        Ok(())
     }
 
-    fn finish(root_dir: &Path, index: &mut Index) -> Result<(), Error> {
-        for (file_id, name, missing_blocks) in index.check_temp_files()? {
+    /// Handle `SingleFile`, which comes first if the source is a single file
+    ///
+    /// Like `rsync file dest`, the file is written to the destination if that
+    /// is a file or doesn't exist, and into it if it is a directory.
+    fn receive_single_file(&mut self, name: Vec<u8>) -> Result<(), Error> {
+        if self.got_entries {
+            return Err(Error::Sync("Unexpected message from source".to_owned()));
+        }
+        if self.single_file.is_some() {
+            // The destination is an existing file, replace it
+            return Ok(());
+        }
+        let path = if self.is_dir {
+            // The destination is an existing directory
+            let name = bytes_to_path(name)?;
+            let mut components = name.components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => {}
+                _ => return Err(Error::Sync(format!("Invalid file name {:?}", name))),
+            }
+            let path = self.root_dir.join(name);
+            if path.is_dir() {
+                return Err(Error::Sync(
+                    "Source is a file but destination is a directory".to_owned(),
+                ));
+            }
+            path
+        } else {
+            self.root_dir.clone()
+        };
+        debug!("FsDestination::sink: receiving single file into {:?}", path);
+        let (index, root_dir, single_file) = open_index(
+            &path,
+            true,
+            &destination_index_options(&self.options),
+            self.options.dry_run,
+        )?;
+        self.block_summary = make_block_summary(&index, &self.options)?;
+        self.index = Some(index);
+        self.root_dir = root_dir;
+        self.single_file = single_file;
+        Ok(())
+    }
+
+    /// Write received block data everywhere it is missing
+    fn write_block_data(
+        root_dir: &Path,
+        index: &mut Index,
+        hash: &HashDigest,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        // Don't trust the source, check the data
+        if HashDigest::of(data) != *hash {
+            return Err(Error::Sync(format!(
+                "Received corrupted data for block {}",
+                hash,
+            )));
+        }
+        for (file_id, name, offset, _size) in index.list_block_locations(hash)? {
+            debug!("FsDestination::sink: writing block to {:?} offset {}", name, offset);
+            write_block(&root_dir.join(&name), offset, data)?;
+            index.mark_block_present(file_id, hash, offset)?;
+        }
+        Ok(())
+    }
+
+    /// Start receiving block data, once all the file lists have been received
+    fn get_blocks(
+        root_dir: &Path,
+        index: &mut Index,
+        delete_mode: DeleteMode,
+        pending: &mut Pending,
+    ) -> Result<FsDestinationState, Error> {
+        if delete_mode == DeleteMode::During {
+            Self::delete_extraneous(root_dir, index, pending)?;
+        }
+        // FIXME: Don't get all files at once, iterate
+        let mut blocks_to_request = VecDeque::new();
+        // Don't actually get any data in a dry run
+        if pending.report.is_none() {
+            for hash in index.list_missing_blocks()? {
+                blocks_to_request.push_back(hash);
+            }
+        }
+        let blocks_to_receive: HashSet<HashDigest> = blocks_to_request.iter().cloned().collect();
+        debug!("FsDestination::sink: state=GetBlocks({} blocks)", blocks_to_receive.len());
+        if blocks_to_receive.is_empty() {
+            Self::finish(root_dir, index, delete_mode, pending)?;
+        }
+        Ok(FsDestinationState::GetBlocks {
+            blocks_to_request: Some(blocks_to_request),
+            blocks_to_receive,
+            in_flight: HashSet::new(),
+            cond: None,
+        })
+    }
+
+    fn finish(
+        root_dir: &Path,
+        index: &mut Index,
+        delete_mode: DeleteMode,
+        pending: &mut Pending,
+    ) -> Result<(), Error> {
+        if pending.report.is_some() {
+            return Self::finish_dry_run(root_dir, index, delete_mode, pending);
+        }
+        for (file_id, name, modified, mode, blocks_hash, missing_blocks) in index.check_temp_files()? {
             if missing_blocks {
                 return Err(Error::Sync(
                     format!("Missing blocks in file {:?}", name),
@@ -535,15 +1317,999 @@ impl FsDestinationInner {
             }
 
             let final_name = untemp_name(&name)?;
+
+            // Read the assembled file again, make sure it matches
+            if Some(file_blocks_hash(&root_dir.join(&name))?) != blocks_hash {
+                warn!("Assembled file {:?} doesn't match the source, discarding", final_name);
+                remove_file_if_exists(&root_dir.join(&name))?;
+                index.remove_file(file_id)?;
+                index.commit()?;
+                return Err(Error::Sync(
+                    format!("Verification failed for file {:?}", final_name),
+                ));
+            }
             debug!("FsDestination: moving {:?} to {:?}", name, final_name);
 
             // Rename temporary file into destination
             move_file(&root_dir.join(name), &root_dir.join(&final_name))?;
+            set_metadata(
+                &root_dir.join(&final_name),
+                &FileMetadata { modified, mode },
+            )?;
 
             // Update index
             index.move_temp_file_into_place(file_id, &final_name)?;
         }
+        for (file_id, name, target) in index.list_temp_symlinks()? {
+            let final_name = untemp_name(&name)?;
+            debug!("FsDestination: creating symlink {:?} -> {:?}", final_name, target);
+
+            // Create link under temporary name, then rename into destination
+            let temp_path = root_dir.join(&name);
+            if let Some(parent) = temp_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            remove_file_if_exists(&temp_path)?;
+            create_symlink(&target, &temp_path)?;
+            std::fs::rename(&temp_path, root_dir.join(&final_name))?;
+
+            // Update index
+            index.move_temp_file_into_place(file_id, &final_name)?;
+        }
+        for (name, target) in pending.hard_links.drain(..) {
+            // Symbolic links from this sync have been created since the list
+            check_parents(root_dir, &name)?;
+            check_parents(root_dir, &target)?;
+            let path = root_dir.join(&name);
+            let target_path = root_dir.join(&target);
+            if is_same_file(&path, &target_path).unwrap_or(false) {
+                debug!("FsDestination: hard link {:?} is up to date", name);
+                continue;
+            }
+            debug!("FsDestination: creating hard link {:?} to {:?}", name, target);
+
+            // Create link under temporary name, then rename into destination
+            let temp_path = root_dir.join(temp_name(&name)?);
+            if let Some(parent) = temp_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            remove_file_if_exists(&temp_path)?;
+            std::fs::hard_link(&target_path, &temp_path)?;
+            std::fs::rename(&temp_path, &path)?;
+
+            // Update index
+            index.add_hard_link(&name, &target)?;
+        }
+        if delete_mode == DeleteMode::After {
+            Self::delete_extraneous(root_dir, index, pending)?;
+        }
+        // Changing a directory's content changes its modification time, so
+        // this is done last, deepest first
+        for (name, metadata) in pending.directories.drain(..).rev() {
+            let path = root_dir.join(&name);
+            let current = std::fs::metadata(&path)?;
+            let current = FileMetadata {
+                modified: current.modified()?.into(),
+                mode: file_mode(&current) & PERMISSION_BITS,
+            };
+            if current != metadata {
+                debug!("FsDestination: setting metadata of directory {:?}", name);
+                set_metadata(&path, &metadata)?;
+            }
+            index.add_directory(&name, metadata.modified, metadata.mode)?;
+        }
         index.commit()?;
         Ok(())
     }
+
+    /// Report the changes that weren't made, and drop the changes to the index
+    fn finish_dry_run(
+        root_dir: &Path,
+        index: &mut Index,
+        delete_mode: DeleteMode,
+        pending: &mut Pending,
+    ) -> Result<(), Error> {
+        for (name, target) in pending.hard_links.drain(..) {
+            if !is_same_file(&root_dir.join(&name), &root_dir.join(&target)).unwrap_or(false) {
+                info!(target: DRY_RUN_LOG_TARGET, "Would create hard link {:?} to {:?}", name, target);
+            }
+        }
+        if delete_mode == DeleteMode::After {
+            Self::delete_extraneous(root_dir, index, pending)?;
+        }
+        for (name, metadata) in pending.directories.drain(..) {
+            if let Ok(current) = std::fs::metadata(root_dir.join(&name)) {
+                let current = FileMetadata {
+                    modified: current.modified()?.into(),
+                    mode: file_mode(&current),
+                };
+                if current != metadata {
+                    info!(target: DRY_RUN_LOG_TARGET, "Would update metadata of directory {:?}", name);
+                }
+            }
+        }
+        let report = pending.report.as_ref().unwrap();
+        info!(
+            target: DRY_RUN_LOG_TARGET,
+            "Would create {} files, update {} files, delete {} files; \
+             {} bytes to transfer, {} bytes reused from local files",
+            report.files_created,
+            report.files_updated,
+            report.files_deleted,
+            report.bytes_transferred,
+            report.bytes_reused,
+        );
+        index.rollback()?;
+        Ok(())
+    }
+
+    fn delete_extraneous(
+        root_dir: &Path,
+        index: &mut Index,
+        pending: &mut Pending,
+    ) -> Result<(), Error> {
+        if let Some(report) = &mut pending.report {
+            for (_file_id, name) in pending.extraneous_files.drain(..) {
+                info!(target: DRY_RUN_LOG_TARGET, "Would delete {:?}", name);
+                report.files_deleted += 1;
+            }
+            return Ok(());
+        }
+        for (file_id, name) in pending.extraneous_files.drain(..) {
+            let path = root_dir.join(&name);
+            let is_dir = std::fs::symlink_metadata(&path)
+                .map(|m| m.is_dir())
+                .unwrap_or(false);
+            if is_dir {
+                info!("Deleting extraneous directory {:?}", name);
+                // Might not be empty, if it contains files that we don't know
+                if let Err(e) = std::fs::remove_dir(&path) {
+                    warn!("Can't delete directory {:?}: {}", name, e);
+                    continue;
+                }
+            } else {
+                info!("Deleting extraneous file {:?}", name);
+                remove_file_if_exists(&path)?;
+            }
+            index.remove_file(file_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use filetime::FileTime;
+    use std::fs;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+
+    use crate::sync::{
+        DeleteMode, DestinationOptions, SourceOptions, do_sync,
+    };
+    use crate::sync::test_utils::big_file;
+    use super::{fs_destination, fs_source};
+
+    fn sync(source: &Path, destination: &Path, options: &DestinationOptions) {
+        sync_with(source, &Default::default(), destination, options)
+    }
+
+    fn sync_with(
+        source: &Path,
+        source_options: &SourceOptions,
+        destination: &Path,
+        options: &DestinationOptions,
+    ) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let source = fs_source(source.to_owned(), source_options)
+                .expect("source");
+            let destination = fs_destination(destination.to_owned(), options)
+                .expect("destination");
+            do_sync(source, destination).await.expect("sync");
+        });
+    }
+
+    fn list_files(root: &Path) -> Vec<PathBuf> {
+        fn rec(root: &Path, rel: &Path, files: &mut Vec<PathBuf>) {
+            for entry in root.join(rel).read_dir().unwrap() {
+                let entry = entry.unwrap();
+                let rel = rel.join(entry.file_name());
+                if entry.file_type().unwrap().is_dir() {
+                    rec(root, &rel, files);
+                } else if entry.file_name() != ".syncfast.idx" {
+                    files.push(rel);
+                }
+            }
+        }
+        let mut files = Vec::new();
+        rec(root, Path::new(""), &mut files);
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_delete() {
+        for &delete in &[
+            DeleteMode::Never,
+            DeleteMode::Before,
+            DeleteMode::During,
+            DeleteMode::After,
+        ] {
+            let source = TempDir::new().unwrap();
+            fs::create_dir(source.path().join("sub")).unwrap();
+            fs::write(source.path().join("a"), b"first file").unwrap();
+            fs::write(source.path().join("sub/b"), b"second file").unwrap();
+            let destination = TempDir::new().unwrap();
+            fs::create_dir(destination.path().join("old")).unwrap();
+            fs::write(destination.path().join("old/c"), b"stale").unwrap();
+            // Renamed file, blocks can be re-used unless deleted first
+            fs::write(destination.path().join("d"), b"second file").unwrap();
+
+            sync(
+                source.path(),
+                destination.path(),
+                &DestinationOptions { delete, ..Default::default() },
+            );
+
+            let mut expected: Vec<PathBuf> = vec!["a".into(), "sub/b".into()];
+            if delete == DeleteMode::Never {
+                expected.push("d".into());
+                expected.push("old/c".into());
+                expected.sort();
+            }
+            assert_eq!(list_files(destination.path()), expected);
+            assert_eq!(
+                fs::read(destination.path().join("sub/b")).unwrap(),
+                b"second file",
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::Index;
+
+        let source = TempDir::new().unwrap();
+        let file = source.path().join("file");
+        fs::write(&file, b"some content").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        let mtime = FileTime::from_unix_time(1500000000, 123456789);
+        filetime::set_file_mtime(&file, mtime).unwrap();
+        let destination = TempDir::new().unwrap();
+        let dest_file = destination.path().join("file");
+
+        let check = |mode: u32, mtime: FileTime| {
+            let metadata = fs::metadata(&dest_file).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode);
+            assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+        };
+
+        sync(source.path(), destination.path(), &Default::default());
+        check(0o640, mtime);
+
+        // Change only the metadata
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        let mtime = FileTime::from_unix_time(1600000000, 5);
+        filetime::set_file_mtime(&file, mtime).unwrap();
+        sync(source.path(), destination.path(), &Default::default());
+        check(0o600, mtime);
+        assert_eq!(fs::read(&dest_file).unwrap(), b"some content");
+
+        // Setuid, setgid and sticky bits are not applied, nor recorded
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o7750)).unwrap();
+        let dir = source.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o2750)).unwrap();
+        sync(source.path(), destination.path(), &Default::default());
+        check(0o750, mtime);
+        let index = Index::open(&destination.path().join(".syncfast.idx")).unwrap();
+        assert_eq!(index.get_file(Path::new("file")).unwrap().unwrap().2, 0o750);
+        let directories = index.list_directories().unwrap();
+        assert_eq!(directories.len(), 1);
+        assert_eq!(directories[0].3, 0o750);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::symlink;
+
+        // Latin-1 names, which are not valid UTF-8
+        let dir = Path::new(OsStr::from_bytes(b"r\xe9pertoire"));
+        let file = dir.join(OsStr::from_bytes(b"caf\xe9"));
+        let link = Path::new(OsStr::from_bytes(b"lien\xff"));
+
+        let source = TempDir::new().unwrap();
+        fs::create_dir(source.path().join(dir)).unwrap();
+        fs::write(source.path().join(&file), b"content").unwrap();
+        symlink(&file, source.path().join(link)).unwrap();
+        let destination = TempDir::new().unwrap();
+
+        sync(source.path(), destination.path(), &Default::default());
+
+        assert_eq!(
+            fs::read(destination.path().join(&file)).unwrap(),
+            b"content",
+        );
+        assert_eq!(
+            fs::read_link(destination.path().join(link)).unwrap(),
+            file,
+        );
+
+        // Changes are picked up on the next sync
+        fs::write(source.path().join(&file), b"new content").unwrap();
+        sync(source.path(), destination.path(), &Default::default());
+        assert_eq!(
+            fs::read(destination.path().join(&file)).unwrap(),
+            b"new content",
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        use crate::SymlinkPolicy;
+        use crate::sync::utils::is_same_file;
+
+        let source = TempDir::new().unwrap();
+        fs::create_dir(source.path().join("sub")).unwrap();
+        fs::write(source.path().join("sub/file"), b"content").unwrap();
+        symlink("sub/file", source.path().join("link")).unwrap();
+        symlink("../../outside", source.path().join("sub/unsafe")).unwrap();
+        // Loop, that must not hang when following links
+        symlink("..", source.path().join("sub/parent")).unwrap();
+
+        // Links are preserved by default
+        let destination = TempDir::new().unwrap();
+        sync(source.path(), destination.path(), &Default::default());
+        assert_eq!(
+            fs::read_link(destination.path().join("link")).unwrap(),
+            Path::new("sub/file"),
+        );
+        assert_eq!(
+            fs::read_link(destination.path().join("sub/unsafe")).unwrap(),
+            Path::new("../../outside"),
+        );
+
+        // Unsafe links are skipped
+        let destination = TempDir::new().unwrap();
+        sync_with(
+            source.path(),
+            &SourceOptions {
+                symlinks: SymlinkPolicy::SkipUnsafe,
+                ..Default::default()
+            },
+            destination.path(),
+            &Default::default(),
+        );
+        let exists = |name: &str| {
+            destination.path().join(name).symlink_metadata().is_ok()
+        };
+        assert!(exists("link"));
+        assert!(exists("sub/parent"));
+        assert!(!exists("sub/unsafe"));
+
+        // Links are followed, the loop is broken
+        fs::remove_file(source.path().join("sub/unsafe")).unwrap();
+        let destination = TempDir::new().unwrap();
+        sync_with(
+            source.path(),
+            &SourceOptions {
+                symlinks: SymlinkPolicy::Copy,
+                ..Default::default()
+            },
+            destination.path(),
+            &Default::default(),
+        );
+        let link = destination.path().join("link");
+        assert!(link.symlink_metadata().unwrap().file_type().is_file());
+        assert_eq!(fs::read(&link).unwrap(), b"content");
+        // The copy is not a hard link to its target
+        assert!(!is_same_file(&link, &destination.path().join("sub/file")).unwrap());
+    }
+
+    #[test]
+    fn test_directories() {
+        let source = TempDir::new().unwrap();
+        fs::create_dir_all(source.path().join("empty/nested")).unwrap();
+        fs::create_dir(source.path().join("full")).unwrap();
+        fs::write(source.path().join("full/file"), b"content").unwrap();
+        let mtime = FileTime::from_unix_time(1500000000, 0);
+        filetime::set_file_mtime(source.path().join("full"), mtime).unwrap();
+        let destination = TempDir::new().unwrap();
+        fs::create_dir_all(destination.path().join("old/sub")).unwrap();
+
+        sync(
+            source.path(),
+            destination.path(),
+            &DestinationOptions {
+                delete: DeleteMode::After,
+                ..Default::default()
+            },
+        );
+
+        assert!(destination.path().join("empty/nested").is_dir());
+        assert!(!destination.path().join("old").exists());
+        let metadata = fs::metadata(destination.path().join("full")).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+        assert_eq!(list_files(destination.path()), vec![PathBuf::from("full/file")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        let source = TempDir::new().unwrap();
+        fs::create_dir(source.path().join("sub")).unwrap();
+        fs::write(source.path().join("a"), b"shared content").unwrap();
+        fs::hard_link(source.path().join("a"), source.path().join("sub/b"))
+            .unwrap();
+        fs::write(source.path().join("c"), b"shared content").unwrap();
+        let destination = TempDir::new().unwrap();
+
+        let inode = |name: &str| {
+            fs::metadata(destination.path().join(name)).unwrap().ino()
+        };
+
+        for _ in 0 .. 2 {
+            sync(source.path(), destination.path(), &Default::default());
+            assert_eq!(
+                list_files(destination.path()),
+                vec![
+                    PathBuf::from("a"),
+                    PathBuf::from("c"),
+                    PathBuf::from("sub/b"),
+                ],
+            );
+            assert_eq!(inode("a"), inode("sub/b"));
+            assert_ne!(inode("a"), inode("c"));
+            assert_eq!(
+                fs::read(destination.path().join("sub/b")).unwrap(),
+                b"shared content",
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sparse() {
+        use std::os::unix::fs::MetadataExt;
+
+        const LEN: u64 = 4 << 20;
+
+        let source = TempDir::new().unwrap();
+        let file = source.path().join("sparse");
+        {
+            let mut file = fs::File::create(&file).unwrap();
+            file.seek(SeekFrom::Start(LEN / 2)).unwrap();
+            file.write_all(b"some data in the middle").unwrap();
+            file.set_len(LEN).unwrap();
+        }
+        let destination = TempDir::new().unwrap();
+
+        sync(source.path(), destination.path(), &Default::default());
+
+        let dest_file = destination.path().join("sparse");
+        assert_eq!(fs::read(&dest_file).unwrap(), fs::read(&file).unwrap());
+        let metadata = fs::metadata(&dest_file).unwrap();
+        assert_eq!(metadata.len(), LEN);
+        assert!(metadata.blocks() * 512 < LEN / 2);
+    }
+
+    #[test]
+    fn test_many_blocks() {
+        // More blocks than can be in flight at once
+        let count = super::MAX_BLOCKS_IN_FLIGHT + super::GET_BLOCKS_BATCH / 2;
+        let source = TempDir::new().unwrap();
+        for i in 0..count {
+            fs::write(
+                source.path().join(format!("file{}", i)),
+                format!("content {}", i),
+            ).unwrap();
+        }
+        let destination = TempDir::new().unwrap();
+
+        // Without the summary, so that all blocks are requested
+        let options = DestinationOptions {
+            block_summary: false,
+            ..Default::default()
+        };
+        sync(source.path(), destination.path(), &options);
+
+        for i in 0..count {
+            assert_eq!(
+                fs::read(destination.path().join(format!("file{}", i))).unwrap(),
+                format!("content {}", i).into_bytes(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_block_summary() {
+        let source = TempDir::new().unwrap();
+        let destination = TempDir::new().unwrap();
+        let old = big_file();
+        fs::write(destination.path().join("old"), &old).unwrap();
+        // Some blocks are already at the destination, some aren't
+        let mut new = old.clone();
+        new.extend_from_slice(b"more content");
+        new.splice(400000..400000, b"inserted".iter().cloned());
+        fs::write(source.path().join("old"), &old).unwrap();
+        fs::write(source.path().join("new"), &new).unwrap();
+        fs::write(source.path().join("copy"), &new).unwrap();
+
+        sync(source.path(), destination.path(), &Default::default());
+
+        assert_eq!(fs::read(destination.path().join("old")).unwrap(), old);
+        assert_eq!(fs::read(destination.path().join("new")).unwrap(), new);
+        assert_eq!(fs::read(destination.path().join("copy")).unwrap(), new);
+    }
+
+    #[test]
+    fn test_unrequested_block() {
+        use chrono::TimeZone;
+        use futures::sink::SinkExt;
+        use futures::stream::StreamExt;
+
+        use crate::HashDigest;
+        use crate::index::file_blocks_hash;
+        use crate::sync::{FileMetadata, Source, SourceEvent};
+
+        let source = TempDir::new().unwrap();
+        fs::write(source.path().join("file"), b"some content").unwrap();
+        let blocks_hash = file_blocks_hash(&source.path().join("file")).unwrap();
+        let hash = HashDigest::of(b"some content");
+        let destination = TempDir::new().unwrap();
+
+        // The data comes right after the block list, before the destination
+        // requested it
+        let events = vec![
+            SourceEvent::FileEntry(
+                b"file".to_vec(),
+                12,
+                blocks_hash,
+                FileMetadata {
+                    modified: chrono::Utc.timestamp(1500000000, 0),
+                    mode: 0o644,
+                },
+            ),
+            SourceEvent::EndFiles,
+            SourceEvent::FileStart(b"file".to_vec()),
+            SourceEvent::FileBlock(hash.clone(), 12),
+            SourceEvent::FileEnd,
+            SourceEvent::BlockData(hash, b"some content".to_vec()),
+        ];
+        // Only end once the destination is done
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let source = Source {
+            stream: futures::stream::iter(events.into_iter().map(Ok))
+                .chain(receiver.filter_map(|_| futures::future::ready(None)))
+                .boxed_local(),
+            sink: Box::pin(sender.sink_map_err(|_| unreachable!())),
+        };
+        let options = DestinationOptions {
+            block_summary: false,
+            ..Default::default()
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let destination = fs_destination(
+                destination.path().to_owned(),
+                &options,
+            ).expect("destination");
+            do_sync(source, destination).await.expect("sync");
+        });
+        assert_eq!(
+            fs::read(destination.path().join("file")).unwrap(),
+            b"some content",
+        );
+    }
+
+    #[test]
+    fn test_duplicate_blocks() {
+        let source = TempDir::new().unwrap();
+        for name in &["one", "two", "three"] {
+            fs::write(source.path().join(name), b"same content").unwrap();
+        }
+        let destination = TempDir::new().unwrap();
+
+        sync(source.path(), destination.path(), &Default::default());
+
+        for name in &["one", "two", "three"] {
+            assert_eq!(
+                fs::read(destination.path().join(name)).unwrap(),
+                b"same content",
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        use crate::Filter;
+
+        let source = TempDir::new().unwrap();
+        fs::create_dir_all(source.path().join("target/debug")).unwrap();
+        fs::write(source.path().join("target/debug/out"), b"built").unwrap();
+        fs::create_dir(source.path().join("src")).unwrap();
+        fs::write(source.path().join("src/main.rs"), b"code").unwrap();
+        fs::write(source.path().join("src/main.rs.tmp"), b"temp").unwrap();
+        fs::write(source.path().join("src/.syncfastignore"), b"*.log\n")
+            .unwrap();
+        fs::write(source.path().join("src/build.log"), b"log").unwrap();
+        fs::write(source.path().join("top.log"), b"log").unwrap();
+        let destination = TempDir::new().unwrap();
+        fs::write(destination.path().join("local.tmp"), b"keep").unwrap();
+
+        let mut filter = Filter::new();
+        filter.exclude("/target/").unwrap();
+        filter.exclude("*.tmp").unwrap();
+
+        // Index without the filter first, entries should get dropped
+        sync(source.path(), TempDir::new().unwrap().path(), &Default::default());
+        sync_with(
+            source.path(),
+            &SourceOptions { filter: filter.clone(), ..Default::default() },
+            destination.path(),
+            &DestinationOptions {
+                delete: DeleteMode::After,
+                filter,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            list_files(destination.path()),
+            vec![
+                PathBuf::from("local.tmp"),
+                PathBuf::from("src/.syncfastignore"),
+                PathBuf::from("src/main.rs"),
+                PathBuf::from("top.log"),
+            ],
+        );
+        assert!(!destination.path().join("target").exists());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        // Every entry under a directory, with the content of files
+        fn snapshot(root: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+            fn rec(root: &Path, rel: &Path, entries: &mut Vec<(PathBuf, Option<Vec<u8>>)>) {
+                for entry in root.join(rel).read_dir().unwrap() {
+                    let entry = entry.unwrap();
+                    let rel = rel.join(entry.file_name());
+                    if entry.file_type().unwrap().is_dir() {
+                        entries.push((rel.clone(), None));
+                        rec(root, &rel, entries);
+                    } else {
+                        entries.push((rel.clone(), Some(fs::read(root.join(&rel)).unwrap())));
+                    }
+                }
+            }
+            let mut entries = Vec::new();
+            rec(root, Path::new(""), &mut entries);
+            entries.sort();
+            entries
+        }
+
+        let source = TempDir::new().unwrap();
+        fs::create_dir(source.path().join("sub")).unwrap();
+        fs::write(source.path().join("sub/new"), b"new file").unwrap();
+        fs::write(source.path().join("changed"), b"new content").unwrap();
+        let destination = TempDir::new().unwrap();
+        fs::write(destination.path().join("changed"), b"old content").unwrap();
+        fs::write(destination.path().join("extra"), b"extra").unwrap();
+
+        let options = DestinationOptions {
+            delete: DeleteMode::During,
+            dry_run: true,
+            ..Default::default()
+        };
+        let dry_run = |destination: &Path| {
+            let before = snapshot(destination);
+            sync(source.path(), destination, &options);
+            assert_eq!(snapshot(destination), before);
+        };
+
+        // Nothing changed, not even an index got created
+        dry_run(destination.path());
+        assert!(!destination.path().join(".syncfast.idx").exists());
+
+        // A missing destination doesn't get created
+        sync(source.path(), &destination.path().join("missing"), &options);
+        assert!(!destination.path().join("missing").exists());
+
+        // A real run works
+        let real = DestinationOptions { dry_run: false, ..options.clone() };
+        sync(source.path(), destination.path(), &real);
+        assert_eq!(
+            list_files(destination.path()),
+            vec![PathBuf::from("changed"), PathBuf::from("sub/new")],
+        );
+        assert_eq!(
+            fs::read(destination.path().join("changed")).unwrap(),
+            b"new content",
+        );
+
+        // The existing index is left alone too
+        fs::write(source.path().join("changed"), b"newer content").unwrap();
+        fs::write(source.path().join("sub/other"), b"other file").unwrap();
+        fs::write(destination.path().join("extra"), b"extra").unwrap();
+        dry_run(destination.path());
+        sync(source.path(), destination.path(), &real);
+        assert_eq!(
+            list_files(destination.path()),
+            vec![
+                PathBuf::from("changed"),
+                PathBuf::from("sub/new"),
+                PathBuf::from("sub/other"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        use futures::stream::StreamExt;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        use crate::sync::{Source, SourceEvent};
+        use super::COMMIT_EVERY_BLOCKS;
+
+        // Run a sync, stopping after receiving `limit` blocks from the source.
+        // Returns the number of blocks received
+        fn partial_sync(
+            source: &Path,
+            destination: &Path,
+            limit: Option<usize>,
+        ) -> usize {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let received = Rc::new(Cell::new(0));
+            let counter = received.clone();
+            runtime.block_on(async {
+                let Source { stream, sink } =
+                    fs_source(source.to_owned(), &Default::default())
+                        .expect("source");
+                let stream = stream.take_while(move |event| {
+                    if let Ok(SourceEvent::BlockData(..)) = event {
+                        counter.set(counter.get() + 1);
+                    }
+                    let stop = match limit {
+                        Some(limit) => counter.get() > limit,
+                        None => false,
+                    };
+                    futures::future::ready(!stop)
+                }).boxed_local();
+                // Without a summary, the blocks are requested once the file
+                // list is complete, which is what can be resumed; blocks
+                // pushed with an unfinished list are sent again
+                let destination = fs_destination(
+                    destination.to_owned(),
+                    &DestinationOptions {
+                        block_summary: false,
+                        ..Default::default()
+                    },
+                ).expect("destination");
+                let result = do_sync(Source { stream, sink }, destination)
+                    .await;
+                // The interrupted sync is expected to fail
+                if limit.is_none() {
+                    result.expect("sync");
+                }
+            });
+            received.get().min(limit.unwrap_or(usize::MAX))
+        }
+
+        let source = TempDir::new().unwrap();
+        let mut content = Vec::with_capacity(3 << 20);
+        let mut x: u32 = 12345;
+        for _ in 0..(3 << 20) {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            content.push((x >> 16) as u8);
+        }
+        fs::write(source.path().join("file"), &content).unwrap();
+
+        // Count the blocks in a full sync
+        let full = TempDir::new().unwrap();
+        let total = partial_sync(source.path(), full.path(), None);
+        assert!(total > 2 * COMMIT_EVERY_BLOCKS);
+
+        // Interrupt a sync half-way
+        let destination = TempDir::new().unwrap();
+        fs::write(source.path().join("stale"), b"removed from source").unwrap();
+        partial_sync(source.path(), destination.path(), Some(total / 2));
+        assert!(!destination.path().join("file").exists());
+        assert!(destination.path().join(".syncfast_tmp_file").exists());
+        assert!(destination.path().join(".syncfast_tmp_stale").exists());
+        fs::remove_file(source.path().join("stale")).unwrap();
+
+        // Resume it, only getting the missing blocks
+        let resumed = partial_sync(source.path(), destination.path(), None);
+        assert!(resumed < total - COMMIT_EVERY_BLOCKS);
+        assert_eq!(fs::read(destination.path().join("file")).unwrap(), content);
+        assert_eq!(list_files(destination.path()), vec![PathBuf::from("file")]);
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        use futures::stream::StreamExt;
+
+        use crate::Error;
+        use crate::sync::{Source, SourceEvent};
+
+        let source = TempDir::new().unwrap();
+        fs::write(source.path().join("file"), b"some content").unwrap();
+        let destination = TempDir::new().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(async {
+            let Source { stream, sink } =
+                fs_source(source.path().to_owned(), &Default::default())
+                    .expect("source");
+            // Flip a bit in the block data
+            let stream = stream.map(|event| match event {
+                Ok(SourceEvent::BlockData(hash, mut data)) => {
+                    data[0] ^= 1;
+                    Ok(SourceEvent::BlockData(hash, data))
+                }
+                e => e,
+            }).boxed_local();
+            let destination = fs_destination(
+                destination.path().to_owned(),
+                &Default::default(),
+            ).expect("destination");
+            do_sync(Source { stream, sink }, destination).await
+        });
+        match result {
+            Err(Error::Sync(e)) => assert!(e.contains("corrupted")),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(!destination.path().join("file").exists());
+    }
+
+    #[test]
+    fn test_source_error() {
+        use futures::stream::StreamExt;
+
+        use crate::{Error, RemoteError};
+        use crate::sync::{Source, SourceEvent};
+
+        let source = TempDir::new().unwrap();
+        fs::write(source.path().join("file"), b"some content").unwrap();
+        let destination = TempDir::new().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(async {
+            let Source { stream, sink } =
+                fs_source(source.path().to_owned(), &Default::default())
+                    .expect("source");
+            // Fail in the middle of the file list, like a remote would
+            let stream = stream.map(|event| match event {
+                Ok(SourceEvent::EndFiles) => Err(Error::Remote(RemoteError {
+                    kind: "io".to_owned(),
+                    path: None,
+                    message: "disk on fire".to_owned(),
+                })),
+                e => e,
+            }).boxed_local();
+            let destination = fs_destination(
+                destination.path().to_owned(),
+                &Default::default(),
+            ).expect("destination");
+            do_sync(Source { stream, sink }, destination).await
+        });
+        match result {
+            Err(Error::Remote(e)) => assert_eq!(e.message, "disk on fire"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        use futures::stream::StreamExt;
+
+        use crate::Error;
+        use crate::sync::{Source, SourceEvent};
+
+        let source = TempDir::new().unwrap();
+        fs::write(source.path().join("copy"), b"some content").unwrap();
+        let destination = TempDir::new().unwrap();
+        fs::write(destination.path().join("orig"), b"some content").unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(async {
+            let Source { stream, sink } =
+                fs_source(source.path().to_owned(), &Default::default())
+                    .expect("source");
+            // Change the file after it was indexed, once the file list was
+            // received, the block copied from it will be wrong
+            let orig = destination.path().join("orig");
+            let stream = stream.inspect(move |event| {
+                if let Ok(SourceEvent::FileStart(..)) = event {
+                    fs::write(&orig, b"other content").unwrap();
+                }
+            }).boxed_local();
+            let dest = fs_destination(
+                destination.path().to_owned(),
+                &Default::default(),
+            ).expect("destination");
+            do_sync(Source { stream, sink }, dest).await
+        });
+        match result {
+            Err(Error::Sync(e)) => assert!(e.contains("Verification failed")),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(list_files(destination.path()), vec![PathBuf::from("orig")]);
+    }
+
+    #[test]
+    fn test_single_file() {
+        let source = TempDir::new().unwrap();
+        let file = source.path().join("disk.img");
+        fs::write(&file, b"image content").unwrap();
+        let destination = TempDir::new().unwrap();
+        let dest_file = destination.path().join("sub/copy.img");
+
+        // To a new file
+        sync(&file, &dest_file, &Default::default());
+        assert_eq!(fs::read(&dest_file).unwrap(), b"image content");
+
+        // To an existing file
+        fs::write(&file, b"new image content").unwrap();
+        sync(&file, &dest_file, &Default::default());
+        assert_eq!(fs::read(&dest_file).unwrap(), b"new image content");
+
+        // Into an existing directory, like `rsync file dir/`
+        let dest_dir = destination.path().join("dir");
+        fs::create_dir(&dest_dir).unwrap();
+        fs::write(dest_dir.join("other"), b"other file").unwrap();
+        sync(&file, &dest_dir, &DestinationOptions {
+            delete: DeleteMode::After,
+            ..Default::default()
+        });
+        assert_eq!(fs::read(dest_dir.join("disk.img")).unwrap(), b"new image content");
+        assert_eq!(fs::read(dest_dir.join("other")).unwrap(), b"other file");
+        // Only the file is indexed, not the directory
+        assert!(dest_dir.join(".syncfast_idx_disk.img").exists());
+        assert!(!dest_dir.join(".syncfast.idx").exists());
+        fs::remove_dir_all(&dest_dir).unwrap();
+
+        // The indexes are next to the files
+        assert_eq!(
+            list_files(destination.path()),
+            vec![
+                PathBuf::from("sub/.syncfast_idx_copy.img"),
+                PathBuf::from("sub/copy.img"),
+            ],
+        );
+        assert_eq!(
+            list_files(source.path()),
+            vec![
+                PathBuf::from(".syncfast_idx_disk.img"),
+                PathBuf::from("disk.img"),
+            ],
+        );
+    }
 }
@@ -0,0 +1,764 @@
+//! Synchronization from a static HTTP server.
+//!
+//! The server only has to serve files, including the manifest written when
+//! publishing the directory (see the `manifest` module). The file list and
+//! block lists come from the manifest, and block data is fetched with HTTP
+//! range requests.
+
+use futures::channel::mpsc::{Receiver, channel};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::{Error, Filter, HashDigest, IndexOptions, MANIFEST_NAME, temp_name};
+use crate::sync::{DestinationEvent, Source, SourceEvent, SourceOptions};
+use crate::sync::fs::open_index;
+use crate::sync::manifest::{Manifest, write_manifest};
+
+/// Index a directory and write its manifest, so that any HTTP server can
+/// serve it as a source
+///
+/// The index is kept in the directory, so only the files that changed get
+/// read again the next time. The manifest is only replaced if it changed.
+pub fn publish(root_dir: &Path, options: &IndexOptions) -> Result<(), Error> {
+    let (index, root_dir, _) = open_index(root_dir, false, options, false)?;
+    let mut manifest = Vec::new();
+    write_manifest(&index, &options.filter, &mut manifest)?;
+
+    let path = root_dir.join(MANIFEST_NAME);
+    if std::fs::read(&path).ok().as_ref() == Some(&manifest) {
+        info!("Manifest {:?} is up to date", path);
+        return Ok(());
+    }
+    // Write under a temporary name first, so it is never served incomplete
+    let temp_path = root_dir.join(temp_name(Path::new(MANIFEST_NAME))?);
+    std::fs::write(&temp_path, &manifest)?;
+    std::fs::rename(&temp_path, &path)?;
+    info!("Wrote manifest {:?}, {} bytes", path, manifest.len());
+    Ok(())
+}
+
+/// Parts of an `http://` URL
+#[derive(Debug, PartialEq, Eq)]
+struct HttpUrl {
+    host: String,
+    port: u16,
+    /// Path, without the trailing slash
+    path: String,
+}
+
+fn parse_url(url: &str) -> Result<HttpUrl, Error> {
+    let rest = if let Some(rest) = url.strip_prefix("http://") {
+        rest
+    } else if url.starts_with("https://") {
+        return Err(Error::UnsupportedForLocation("HTTPS is not supported"));
+    } else {
+        return Err(Error::Sync(format!("Invalid URL {:?}", url)));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, ""),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(idx) if !authority.ends_with(']') => {
+            let port = authority[idx + 1..].parse()
+                .map_err(|_| Error::Sync(format!("Invalid port in URL {:?}", url)))?;
+            (&authority[..idx], port)
+        }
+        _ => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(Error::Sync(format!("Invalid URL {:?}", url)));
+    }
+    Ok(HttpUrl {
+        host: host.to_owned(),
+        port,
+        path: path.trim_end_matches('/').to_owned(),
+    })
+}
+
+/// Build the path of a file on the server, escaping the name
+fn url_path(base: &str, name: &[u8]) -> String {
+    let mut path = base.to_owned();
+    path.push('/');
+    for &byte in name {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            path.push(byte as char);
+        } else {
+            write!(path, "%{:02X}", byte).unwrap();
+        }
+    }
+    path
+}
+
+/// Minimal HTTP/1.1 client, keeping the connection open between requests
+struct HttpClient {
+    host: String,
+    port: u16,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+/// Read a line of the response head, without the line ending
+#[allow(clippy::manual_async_fn)]
+fn read_line<'a>(
+    connection: &'a mut BufReader<TcpStream>,
+) -> impl Future<Output=Result<String, Error>> + 'a {
+    async move {
+        let mut line = String::new();
+        if connection.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed by HTTP server",
+            ).into());
+        }
+        Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    }
+}
+
+fn invalid_response() -> Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Invalid response from HTTP server",
+    ).into()
+}
+
+/// Read a response, returns the status, whether the connection can be
+/// reused, and the body
+#[allow(clippy::manual_async_fn)]
+fn read_response<'a>(
+    connection: &'a mut BufReader<TcpStream>,
+) -> impl Future<Output=Result<(u16, bool, Vec<u8>), Error>> + 'a {
+    async move {
+        let status_line = read_line(connection).await?;
+        let mut parts = status_line.split(' ');
+        let version = parts.next().unwrap_or("");
+        if !version.starts_with("HTTP/1.") {
+            return Err(invalid_response());
+        }
+        let status: u16 = parts.next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid_response)?;
+        let mut keep_alive = version != "HTTP/1.0";
+        let mut content_length = None;
+        let mut chunked = false;
+        loop {
+            let line = read_line(connection).await?;
+            if line.is_empty() {
+                break;
+            }
+            let idx = line.find(':').ok_or_else(invalid_response)?;
+            let name = line[..idx].trim().to_ascii_lowercase();
+            let value = line[idx + 1..].trim().to_ascii_lowercase();
+            match &name as &str {
+                "content-length" => {
+                    content_length = Some(value.parse::<usize>().map_err(|_| invalid_response())?);
+                }
+                "transfer-encoding" => chunked = value.contains("chunked"),
+                "connection" if value == "close" => keep_alive = false,
+                "connection" if value == "keep-alive" => keep_alive = true,
+                _ => {}
+            }
+        }
+
+        let mut body = Vec::new();
+        if chunked {
+            loop {
+                let line = read_line(connection).await?;
+                let size = line.split(';').next().unwrap().trim();
+                let size = usize::from_str_radix(size, 16)
+                    .map_err(|_| invalid_response())?;
+                if size == 0 {
+                    // Skip trailers
+                    while !read_line(connection).await?.is_empty() {}
+                    break;
+                }
+                let start = body.len();
+                body.resize(start + size, 0);
+                connection.read_exact(&mut body[start..]).await?;
+                if !read_line(connection).await?.is_empty() {
+                    return Err(invalid_response());
+                }
+            }
+        } else if let Some(length) = content_length {
+            body.resize(length, 0);
+            connection.read_exact(&mut body).await?;
+        } else {
+            // The end of the body is the end of the connection
+            connection.read_to_end(&mut body).await?;
+            keep_alive = false;
+        }
+        Ok((status, keep_alive, body))
+    }
+}
+
+impl HttpClient {
+    fn new(host: String, port: u16) -> HttpClient {
+        HttpClient {
+            host,
+            port,
+            connection: None,
+        }
+    }
+
+    /// Send a GET request, returns the status and body
+    #[allow(clippy::manual_async_fn)]
+    fn request<'a>(
+        &'a mut self,
+        path: &'a str,
+        range: Option<(u64, u64)>,
+    ) -> impl Future<Output=Result<(u16, Vec<u8>), Error>> + 'a {
+        async move {
+            // The server might have closed a kept-alive connection, retry
+            // once on a new one
+            if self.connection.is_some() {
+                match self.request_once(path, range).await {
+                    Ok(r) => return Ok(r),
+                    Err(e) => debug!("http: request failed on kept-alive connection, retrying: {}", e),
+                }
+            }
+            self.request_once(path, range).await
+        }
+    }
+
+    #[allow(clippy::manual_async_fn)]
+    fn request_once<'a>(
+        &'a mut self,
+        path: &'a str,
+        range: Option<(u64, u64)>,
+    ) -> impl Future<Output=Result<(u16, Vec<u8>), Error>> + 'a {
+        async move {
+            let mut connection = match self.connection.take() {
+                Some(c) => c,
+                None => {
+                    debug!("http: connecting to {}:{}", self.host, self.port);
+                    let stream = TcpStream::connect((&self.host as &str, self.port)).await?;
+                    BufReader::new(stream)
+                }
+            };
+            let mut request = String::new();
+            write!(request, "GET {} HTTP/1.1\r\n", path).unwrap();
+            if self.port == 80 {
+                write!(request, "Host: {}\r\n", self.host).unwrap();
+            } else {
+                write!(request, "Host: {}:{}\r\n", self.host, self.port).unwrap();
+            }
+            write!(request, "User-Agent: syncfast/{}\r\n", env!("CARGO_PKG_VERSION")).unwrap();
+            if let Some((start, end)) = range {
+                write!(request, "Range: bytes={}-{}\r\n", start, end - 1).unwrap();
+            }
+            request.push_str("\r\n");
+            debug!("http: GET {} {:?}", path, range);
+            connection.get_mut().write_all(request.as_bytes()).await?;
+            let (status, keep_alive, body) = read_response(&mut connection).await?;
+            debug!("http: status {}, {} bytes", status, body.len());
+            if keep_alive {
+                self.connection = Some(connection);
+            }
+            Ok((status, body))
+        }
+    }
+
+    /// Get a whole file
+    #[allow(clippy::manual_async_fn)]
+    fn get<'a>(&'a mut self, path: &'a str) -> impl Future<Output=Result<Vec<u8>, Error>> + 'a {
+        async move {
+            match self.request(path, None).await? {
+                (200, body) => Ok(body),
+                (status, _) => Err(Error::Sync(format!(
+                    "HTTP server returned status {} for {}",
+                    status, path,
+                ))),
+            }
+        }
+    }
+
+    /// Get the bytes from `start` to `end` (excluded) of a file
+    #[allow(clippy::manual_async_fn)]
+    fn get_range<'a>(
+        &'a mut self,
+        path: &'a str,
+        start: u64,
+        end: u64,
+    ) -> impl Future<Output=Result<Vec<u8>, Error>> + 'a {
+        async move {
+            let length = (end - start) as usize;
+            match self.request(path, Some((start, end))).await? {
+                (206, body) if body.len() == length => Ok(body),
+                (200, mut body) if body.len() as u64 >= end => {
+                    // The server ignored the range and sent the whole file
+                    warn!("HTTP server doesn't support ranges, getting whole file {}", path);
+                    body.truncate(end as usize);
+                    body.drain(..start as usize);
+                    Ok(body)
+                }
+                (200, _) | (206, _) => Err(Error::Sync(format!(
+                    "HTTP server returned unexpected length for {}",
+                    path,
+                ))),
+                (status, _) => Err(Error::Sync(format!(
+                    "HTTP server returned status {} for {}",
+                    status, path,
+                ))),
+            }
+        }
+    }
+}
+
+pub fn http_source(
+    url: &str,
+    options: &SourceOptions,
+) -> Result<Source, Error> {
+    let HttpUrl { host, port, path } = parse_url(url)?;
+    info!("Setting up HTTP source {}:{}{}", host, port, path);
+
+    // Like FsSource, handle requests in the Stream, with a channel for the Sink
+    let (sender, receiver) = channel(1);
+    Ok(Source {
+        stream: futures::stream::unfold(
+            HttpSourceFrom {
+                client: HttpClient::new(host, port),
+                base_path: path,
+                filter: options.filter.clone(),
+                receiver,
+                files: HashMap::new(),
+                blocks: HashMap::new(),
+                state: HttpSourceState::Start,
+            },
+            HttpSourceFrom::stream,
+        ).boxed_local(),
+        sink: Box::pin(futures::sink::unfold((), move |(), event: DestinationEvent| {
+            let mut sender = sender.clone();
+            async move {
+                sender.send(event).await.map_err(|_| Error::Io(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "HttpSource channel is closed")))
+            }
+        })),
+    })
+}
+
+enum HttpSourceState {
+    /// Manifest not yet fetched
+    Start,
+    ListFiles(VecDeque<SourceEvent>),
+    Respond,
+    ListBlocks(VecDeque<(HashDigest, usize)>),
+    /// Sending the rest of a batch of requested blocks
+    SendBlocks(VecDeque<SourceEvent>),
+}
+
+struct HttpSourceFrom {
+    client: HttpClient,
+    /// Path of the published directory on the server
+    base_path: String,
+    filter: Filter,
+    receiver: Receiver<DestinationEvent>,
+    /// Blocks of each file, from the manifest
+    files: HashMap<Vec<u8>, Vec<(HashDigest, usize)>>,
+    /// Where to get each block: file, offset, and size
+    blocks: HashMap<HashDigest, (Vec<u8>, u64, usize)>,
+    state: HttpSourceState,
+}
+
+/// Fetch a batch of blocks, merging adjacent blocks into a single request
+#[allow(clippy::manual_async_fn)]
+fn fetch_blocks<'a>(
+    client: &'a mut HttpClient,
+    base_path: &'a str,
+    blocks: &'a HashMap<HashDigest, (Vec<u8>, u64, usize)>,
+    hashes: Vec<HashDigest>,
+) -> impl Future<Output=Result<VecDeque<SourceEvent>, Error>> + 'a {
+    async move {
+        let mut locations = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let (name, offset, size) = blocks.get(&hash)
+                .ok_or_else(|| Error::Sync("Requested block is unknown".to_owned()))?;
+            locations.push((name, *offset, *size, hash));
+        }
+        locations.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+        let mut events = VecDeque::with_capacity(locations.len());
+        let mut remaining = &locations[..];
+        while let Some(&(name, start, size, _)) = remaining.first() {
+            let mut end = start + size as u64;
+            let mut count = 1;
+            while count < remaining.len()
+                && remaining[count].0 == name
+                && remaining[count].1 == end
+            {
+                end += remaining[count].2 as u64;
+                count += 1;
+            }
+            debug!("HttpSource: fetching {} blocks from {:?}", count, String::from_utf8_lossy(name));
+            let data = client.get_range(&url_path(base_path, name), start, end).await?;
+            for &(_, offset, size, ref hash) in &remaining[..count] {
+                let pos = (offset - start) as usize;
+                events.push_back(SourceEvent::BlockData(hash.clone(), data[pos..pos + size].to_owned()));
+            }
+            remaining = &remaining[count..];
+        }
+        Ok(events)
+    }
+}
+
+impl HttpSourceFrom {
+    #[allow(clippy::manual_async_fn)]
+    fn stream(mut source: HttpSourceFrom) -> impl Future<Output=Option<(Result<SourceEvent, Error>, HttpSourceFrom)>> {
+        async move {
+            macro_rules! err {
+                ($e:expr) => {
+                    Some((Err($e), source))
+                }
+            }
+            // FIXME: Replace by try_block when supported by Rust
+            macro_rules! try_ {
+                ($v:expr) => {
+                    match $v {
+                        Ok(r) => r,
+                        Err(e) => return err!(e),
+                    }
+                }
+            }
+
+            loop {
+                return match source.state {
+                    // Get the manifest
+                    HttpSourceState::Start => {
+                        let path = url_path(&source.base_path, MANIFEST_NAME.as_bytes());
+                        info!("Getting manifest {}", path);
+                        let data = try_!(source.client.get(&path).await);
+                        let mut manifest = try_!(Manifest::parse(&data));
+                        try_!(manifest.apply_filter(&source.filter));
+                        let entries: VecDeque<SourceEvent> = manifest.entries.into_iter().collect();
+                        for (name, blocks) in &manifest.files {
+                            let mut offset = 0;
+                            for &(ref hash, size) in blocks {
+                                source.blocks.entry(hash.clone())
+                                    .or_insert_with(|| (name.clone(), offset, size));
+                                offset += size as u64;
+                            }
+                        }
+                        source.files = manifest.files;
+                        debug!("HttpSource: preparing to send {} entries", entries.len());
+                        source.state = HttpSourceState::ListFiles(entries);
+                        continue;
+                    }
+                    // Send files list
+                    HttpSourceState::ListFiles(ref mut entries) => {
+                        match entries.pop_front() {
+                            Some(event) => {
+                                debug!("HttpSource: send {:?}", event);
+                                Some((Ok(event), source))
+                            }
+                            None => {
+                                debug!("HttpSource: state=Respond");
+                                source.state = HttpSourceState::Respond;
+                                debug!("HttpSource: send EndFiles");
+                                Some((Ok(SourceEvent::EndFiles), source))
+                            }
+                        }
+                    }
+                    // Files are sent, respond to requests
+                    HttpSourceState::Respond => {
+                        let req = match source.receiver.next().await {
+                            None => {
+                                debug!("HttpSource: got end of input");
+                                return None;
+                            }
+                            Some(e) => e,
+                        };
+                        debug!("HttpSource: recv {:?}", req);
+                        match req {
+                            // Blocks are only fetched once requested
+                            DestinationEvent::BlockSummary(_) => continue,
+                            DestinationEvent::GetFile(name) => {
+                                let blocks = match source.files.get(&name) {
+                                    Some(b) => b.iter().cloned().collect(),
+                                    None => return err!(Error::Sync("Requested file is unknown".to_owned())),
+                                };
+                                debug!("HttpSource: state=ListBlocks");
+                                source.state = HttpSourceState::ListBlocks(blocks);
+                                debug!("HttpSource: send FileStart");
+                                Some((Ok(SourceEvent::FileStart(name)), source))
+                            }
+                            DestinationEvent::GetBlocks(hashes) => {
+                                if hashes.is_empty() {
+                                    return err!(Error::Sync("Empty block request".to_owned()));
+                                }
+                                let events = try_!(fetch_blocks(
+                                    &mut source.client,
+                                    &source.base_path,
+                                    &source.blocks,
+                                    hashes,
+                                ).await);
+                                debug!("HttpSource: state=SendBlocks({} blocks)", events.len());
+                                source.state = HttpSourceState::SendBlocks(events);
+                                continue;
+                            }
+                            DestinationEvent::Complete => {
+                                // The stream ends here, with the source
+                                debug!("HttpSource: done");
+                                None
+                            }
+                        }
+                    }
+                    // List blocks
+                    HttpSourceState::ListBlocks(ref mut list) => {
+                        match list.pop_front() {
+                            Some((hash, size)) => {
+                                debug!("HttpSource: send FileBlock");
+                                Some((Ok(SourceEvent::FileBlock(hash, size)), source))
+                            }
+                            None => {
+                                debug!("HttpSource: state=Respond");
+                                source.state = HttpSourceState::Respond;
+                                debug!("HttpSource: send FileEnd");
+                                Some((Ok(SourceEvent::FileEnd), source))
+                            }
+                        }
+                    }
+                    // Send the blocks fetched for a request
+                    HttpSourceState::SendBlocks(ref mut events) => {
+                        let event = events.pop_front().unwrap();
+                        if events.is_empty() {
+                            debug!("HttpSource: state=Respond");
+                            source.state = HttpSourceState::Respond;
+                        }
+                        debug!("HttpSource: send BlockData");
+                        Some((Ok(event), source))
+                    }
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use filetime::FileTime;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::MANIFEST_NAME;
+    use crate::sync::{SourceOptions, do_sync};
+    use crate::sync::fs::fs_destination;
+    use crate::sync::manifest::Manifest;
+    use crate::sync::test_utils::{big_file, change_middle};
+    use crate::sync::utils::is_same_file;
+    use super::{HttpUrl, http_source, parse_url, publish, url_path};
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://example.org:8080/some/dir/").unwrap(),
+            HttpUrl {
+                host: "example.org".into(),
+                port: 8080,
+                path: "/some/dir".into(),
+            },
+        );
+        assert_eq!(
+            parse_url("http://example.org").unwrap(),
+            HttpUrl {
+                host: "example.org".into(),
+                port: 80,
+                path: "".into(),
+            },
+        );
+        assert!(parse_url("https://example.org/").is_err());
+        assert!(parse_url("http://example.org:port/").is_err());
+        assert_eq!(
+            url_path("/dir", b"sub dir/file%.txt"),
+            "/dir/sub%20dir/file%25.txt",
+        );
+    }
+
+    /// Serve the files in `root` under `/tree`, logging the requests
+    async fn serve(
+        listener: TcpListener,
+        root: PathBuf,
+        requests: Arc<Mutex<Vec<String>>>,
+    ) {
+        loop {
+            let (connection, _) = listener.accept().await.unwrap();
+            tokio::spawn(serve_connection(connection, root.clone(), requests.clone()));
+        }
+    }
+
+    async fn serve_connection(
+        connection: TcpStream,
+        root: PathBuf,
+        requests: Arc<Mutex<Vec<String>>>,
+    ) {
+        let mut connection = BufReader::new(connection);
+        loop {
+            let mut line = String::new();
+            if connection.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let path = line.split(' ').nth(1).unwrap().to_owned();
+            let mut range: Option<(usize, usize)> = None;
+            loop {
+                let mut header = String::new();
+                connection.read_line(&mut header).await.unwrap();
+                let header = header.trim().to_ascii_lowercase();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(r) = header.strip_prefix("range: bytes=") {
+                    let mut r = r.split('-').map(|n| n.parse().unwrap());
+                    range = Some((r.next().unwrap(), r.next().unwrap()));
+                }
+            }
+            requests.lock().unwrap().push(match range {
+                Some((start, end)) => format!("{} {}-{}", path, start, end),
+                None => path.clone(),
+            });
+            let name = path.strip_prefix("/tree/").unwrap().replace("%20", " ");
+            let response = match (fs::read(root.join(name)), range) {
+                (Ok(data), Some((start, end))) => {
+                    let mut response = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                        start, end, data.len(), end + 1 - start,
+                    ).into_bytes();
+                    response.extend_from_slice(&data[start..end + 1]);
+                    response
+                }
+                (Ok(data), None) => {
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                        data.len(),
+                    ).into_bytes();
+                    response.extend_from_slice(&data);
+                    response
+                }
+                (Err(_), _) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            };
+            connection.get_mut().write_all(&response).await.unwrap();
+        }
+    }
+
+    /// Run a sync from the server to a local directory, returns the requests
+    fn sync(root: &Path, destination: &Path, options: &SourceOptions) -> Vec<String> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/tree/", listener.local_addr().unwrap());
+            tokio::spawn(serve(listener, root.to_owned(), requests.clone()));
+
+            let source = http_source(&url, options).unwrap();
+            let destination = fs_destination(destination.to_owned(), &Default::default()).unwrap();
+            do_sync(source, destination).await.unwrap();
+        });
+        let requests = requests.lock().unwrap();
+        requests.clone()
+    }
+
+    #[test]
+    fn test_http_source() {
+        let root = TempDir::new().unwrap();
+        let big = big_file();
+        fs::write(root.path().join("big"), &big).unwrap();
+        fs::create_dir(root.path().join("sub dir")).unwrap();
+        fs::write(root.path().join("sub dir/small"), b"small file").unwrap();
+        publish(root.path(), &Default::default()).unwrap();
+        let destination = TempDir::new().unwrap();
+
+        let requests = sync(root.path(), destination.path(), &Default::default());
+
+        assert_eq!(fs::read(destination.path().join("big")).unwrap(), big);
+        assert_eq!(
+            fs::read(destination.path().join("sub dir/small")).unwrap(),
+            b"small file",
+        );
+        // Adjacent blocks are fetched in a single request
+        assert_eq!(
+            requests,
+            vec![
+                "/tree/.syncfast.manifest".to_owned(),
+                "/tree/big 0-799999".to_owned(),
+                "/tree/sub%20dir/small 0-9".to_owned(),
+            ],
+        );
+
+        // Change the middle of the file, only that part gets fetched
+        let changed = change_middle(&big);
+        fs::write(root.path().join("big"), &changed).unwrap();
+        publish(root.path(), &Default::default()).unwrap();
+
+        let requests = sync(root.path(), destination.path(), &Default::default());
+
+        assert_eq!(fs::read(destination.path().join("big")).unwrap(), changed);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0], "/tree/.syncfast.manifest");
+        let range = requests[1].strip_prefix("/tree/big ").unwrap();
+        let mut range = range.split('-').map(|n| n.parse::<usize>().unwrap());
+        let (start, end) = (range.next().unwrap(), range.next().unwrap());
+        assert!(start <= 400000 && end >= 400007);
+        assert!(end - start < 100000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_link_filter() {
+        let root = TempDir::new().unwrap();
+        fs::write(root.path().join("a"), b"linked file").unwrap();
+        fs::hard_link(root.path().join("a"), root.path().join("b")).unwrap();
+        fs::hard_link(root.path().join("a"), root.path().join("c")).unwrap();
+        publish(root.path(), &Default::default()).unwrap();
+        let destination = TempDir::new().unwrap();
+
+        // The file the others link to is excluded, not its other names
+        let mut options = SourceOptions::default();
+        options.filter.exclude("a").unwrap();
+        sync(root.path(), destination.path(), &options);
+
+        assert!(!destination.path().join("a").exists());
+        assert_eq!(fs::read(destination.path().join("b")).unwrap(), b"linked file");
+        assert_eq!(fs::read(destination.path().join("c")).unwrap(), b"linked file");
+        assert!(is_same_file(
+            &destination.path().join("b"),
+            &destination.path().join("c"),
+        ).unwrap());
+    }
+
+    #[test]
+    fn test_publish() {
+        let root = TempDir::new().unwrap();
+        fs::write(root.path().join("file"), b"content").unwrap();
+        publish(root.path(), &Default::default()).unwrap();
+
+        let manifest_path = root.path().join(MANIFEST_NAME);
+        let read = || Manifest::parse(&fs::read(&manifest_path).unwrap()).unwrap();
+        let manifest = read();
+        // Neither the index nor the manifest itself are listed
+        assert_eq!(manifest.entries.len(), 1);
+        assert_eq!(manifest.files.keys().collect::<Vec<_>>(), vec![b"file"]);
+
+        // Not rewritten if nothing changed
+        let old_time = FileTime::from_unix_time(1000000000, 0);
+        filetime::set_file_mtime(&manifest_path, old_time).unwrap();
+        publish(root.path(), &Default::default()).unwrap();
+        let modified = fs::metadata(&manifest_path).unwrap().modified().unwrap();
+        assert_eq!(FileTime::from_system_time(modified), old_time);
+
+        // Updated when the tree changes
+        fs::write(root.path().join("other"), b"more content").unwrap();
+        publish(root.path(), &Default::default()).unwrap();
+        let manifest = read();
+        assert_eq!(manifest.entries.len(), 2);
+        assert!(manifest.files.contains_key(b"other" as &[u8]));
+    }
+}
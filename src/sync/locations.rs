@@ -3,8 +3,10 @@
 use std::path::PathBuf;
 
 use crate::Error;
-use crate::sync::{Destination, Source};
+use crate::sync::{Destination, DestinationOptions, Source, SourceOptions};
+use crate::sync::daemon::{DEFAULT_DAEMON_PORT, daemon_destination, daemon_source};
 use crate::sync::fs::{fs_destination, fs_source};
+use crate::sync::http::http_source;
 use crate::sync::ssh::{ssh_destination, ssh_source};
 
 /// SSH remote path, with user and host
@@ -18,6 +20,16 @@ pub struct SshLocation {
     pub path: String,
 }
 
+/// Path on a syncfast daemon, see the `daemon` module
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DaemonLocation {
+    /// Daemon host name
+    pub host: String,
+    pub port: u16,
+    /// Name of the module, followed by the path in it
+    pub path: String,
+}
+
 /// A location, possible remote, that can be specified by the user
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Location {
@@ -27,10 +39,13 @@ pub enum Location {
     Ssh(SshLocation),
     /// Remote HTTP server
     Http(String),
+    /// Module of a syncfast daemon
+    Daemon(DaemonLocation),
 }
 
 impl Location {
     /// Parse a string into a location
+    #[allow(clippy::manual_strip)]
     pub fn parse(s: &str) -> Option<Location> {
         if s.starts_with("http://") || s.starts_with("https://") {
             Some(Location::Http(s.into()))
@@ -53,6 +68,33 @@ impl Location {
                 host: host.into(),
                 path: path.into(),
             }))
+        } else if s.starts_with("syncfast://") {
+            let idx_slash = match s[11 ..].find('/') {
+                Some(i) => i + 11,
+                None => return None,
+            };
+            let host = &s[11 .. idx_slash];
+            let path = &s[idx_slash + 1 ..];
+            if path.is_empty() {
+                return None;
+            }
+            // The port is optional, IPv6 addresses are in brackets
+            let (host, port) = match host.rfind(':') {
+                Some(idx_colon) if !host[idx_colon ..].contains(']') => {
+                    (&host[.. idx_colon], host[idx_colon + 1 ..].parse().ok()?)
+                }
+                _ => (host, DEFAULT_DAEMON_PORT),
+            };
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if host.is_empty() {
+                return None;
+            }
+
+            Some(Location::Daemon(DaemonLocation {
+                host: host.into(),
+                port,
+                path: path.into(),
+            }))
         } else if s.starts_with("file:///") {
             // FIXME: Unquote path?
             Some(Location::Local(s[7 ..].into()))
@@ -73,10 +115,14 @@ impl Location {
     }
 
     /// Create a `Destination` to sync to this location
-    pub fn open_destination(&self) -> Result<Destination, Error> {
+    pub fn open_destination(
+        &self,
+        options: &DestinationOptions,
+    ) -> Result<Destination, Error> {
         let w: Destination = match self {
-            Location::Local(path) => fs_destination(path.to_owned())?,
-            Location::Ssh(ssh) => ssh_destination(ssh)?,
+            Location::Local(path) => fs_destination(path.to_owned(), options)?,
+            Location::Ssh(ssh) => ssh_destination(ssh, options)?,
+            Location::Daemon(daemon) => daemon_destination(daemon, options)?,
             Location::Http(_url) => {
                 // Shouldn't happen, caught in main.rs
                 return Err(Error::UnsupportedForLocation("Can't write to HTTP location"));
@@ -86,11 +132,15 @@ impl Location {
     }
 
     /// Create a `Source` to sync from this location
-    pub fn open_source(&self) -> Result<Source, Error> {
+    pub fn open_source(
+        &self,
+        options: &SourceOptions,
+    ) -> Result<Source, Error> {
         let w: Source = match self {
-            Location::Local(path) => fs_source(path.to_owned())?,
-            Location::Ssh(ssh) => ssh_source(ssh)?,
-            Location::Http(_url) => unimplemented!(), // TODO: HTTP
+            Location::Local(path) => fs_source(path.to_owned(), options)?,
+            Location::Ssh(ssh) => ssh_source(ssh, options)?,
+            Location::Http(url) => http_source(url, options)?,
+            Location::Daemon(daemon) => daemon_source(daemon, options)?,
         };
         Ok(w)
     }
@@ -98,7 +148,8 @@ impl Location {
 
 #[cfg(test)]
 mod tests {
-    use super::{Location, SshLocation};
+    use super::{DaemonLocation, Location, SshLocation};
+    use crate::sync::daemon::DEFAULT_DAEMON_PORT;
 
     #[test]
     fn test_parse() {
@@ -141,5 +192,23 @@ mod tests {
             })),
         );
         assert_eq!(Location::parse("ssh://host"), None);
+        assert_eq!(
+            Location::parse("syncfast://host:1234/module/some/path"),
+            Some(Location::Daemon(DaemonLocation {
+                host: "host".into(),
+                port: 1234,
+                path: "module/some/path".into(),
+            })),
+        );
+        assert_eq!(
+            Location::parse("syncfast://[::1]/module"),
+            Some(Location::Daemon(DaemonLocation {
+                host: "::1".into(),
+                port: DEFAULT_DAEMON_PORT,
+                path: "module".into(),
+            })),
+        );
+        assert_eq!(Location::parse("syncfast://host:port/module"), None);
+        assert_eq!(Location::parse("syncfast://host/"), None);
     }
 }
@@ -0,0 +1,293 @@
+//! Manifest describing a published directory, for the HTTP source.
+//!
+//! The manifest starts with `MANIFEST_MAGIC` and the format version as a
+//! single byte, followed by messages encoded as in the network protocol: the
+//! entries as sent by a source, `END_FILES`, then for each file a `FILE_START`,
+//! its `FILE_BLOCK`s in order, and `FILE_END`. The offsets of the blocks are
+//! the sums of the sizes of the blocks before them.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::{Error, Filter, HashDigest, Index, bytes_to_path, path_to_bytes};
+use crate::streaming_iterator::StreamingIterator;
+use crate::sync::{FileMetadata, SourceEvent};
+use crate::sync::proto::{Message, OwnedMessage, Parser, write_message};
+
+/// Magic bytes at the start of a manifest
+pub const MANIFEST_MAGIC: &[u8] = b"syncfast manifest\n";
+
+/// Version of the manifest format, written after the magic bytes
+///
+/// This has to change if the encoding of the messages changes.
+pub const MANIFEST_VERSION: u8 = 1;
+
+/// The content of a manifest
+pub struct Manifest {
+    /// The entries, in the order a source sends them, without `EndFiles`
+    pub entries: Vec<SourceEvent>,
+    /// The blocks of each file, with their size
+    pub files: HashMap<Vec<u8>, Vec<(HashDigest, usize)>>,
+}
+
+fn invalid(kind: &str, message: &str) -> Error {
+    Error::Sync(format!("Invalid {}: {}", kind, message))
+}
+
+/// Check the magic bytes and format version of a file, return the rest
+///
+/// `kind` is the name of the format, for error messages.
+pub(crate) fn read_header<'a>(
+    data: &'a [u8],
+    kind: &str,
+    magic: &[u8],
+    version: u8,
+) -> Result<&'a [u8], Error> {
+    if !data.starts_with(magic) {
+        return Err(invalid(kind, &format!("not a syncfast {}", kind)));
+    }
+    let data = &data[magic.len()..];
+    match data.first() {
+        Some(&v) if v == version => Ok(&data[1..]),
+        Some(v) => Err(Error::Sync(format!(
+            "This {} uses format version {}, we use version {}",
+            kind, v, version,
+        ))),
+        None => Err(invalid(kind, "truncated")),
+    }
+}
+
+/// Read the messages following the header of a manifest
+///
+/// If `block_data` is set, `BLOCK_DATA` messages are accepted after the
+/// files, and their content is added to it.
+#[allow(clippy::type_complexity)]
+pub(crate) fn read_listing(
+    data: &[u8],
+    kind: &str,
+    mut block_data: Option<&mut HashMap<HashDigest, Vec<u8>>>,
+) -> Result<Manifest, Error> {
+    let mut entries = Vec::new();
+    let mut files = HashMap::new();
+    let mut end_files = false;
+    // Name and blocks of the file being read, between FileStart and
+    // FileEnd
+    let mut current: Option<(Vec<u8>, Vec<(HashDigest, usize)>)> = None;
+
+    let mut parser = Parser::default();
+    let mut messages = parser.parse(data);
+    loop {
+        let message: OwnedMessage = match messages.next() {
+            Some(Ok(msg)) => msg.into(),
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        };
+        let event = SourceEvent::try_from(message)
+            .map_err(|()| invalid(kind, "unexpected message"))?;
+        match (end_files, &mut current, event) {
+            (false, _, SourceEvent::EndFiles) => end_files = true,
+            (false, _, event @ SourceEvent::FileEntry(..))
+            | (false, _, event @ SourceEvent::SymlinkEntry(..))
+            | (false, _, event @ SourceEvent::DirectoryEntry(..))
+            | (false, _, event @ SourceEvent::HardLinkEntry(..)) => {
+                entries.push(event);
+            }
+            (true, None, SourceEvent::FileStart(name)) => {
+                current = Some((name, Vec::new()));
+            }
+            (true, Some((_, ref mut blocks)), SourceEvent::FileBlock(hash, size)) => {
+                blocks.push((hash, size));
+            }
+            (true, Some(_), SourceEvent::FileEnd) => {
+                let (name, blocks) = current.take().unwrap();
+                files.insert(name, blocks);
+            }
+            (true, None, SourceEvent::BlockData(hash, data)) if block_data.is_some() => {
+                block_data.as_mut().unwrap().insert(hash, data);
+            }
+            _ => return Err(invalid(kind, "unexpected message")),
+        }
+    }
+    if !messages.is_done() || !end_files || current.is_some() {
+        return Err(invalid(kind, "truncated"));
+    }
+    Ok(Manifest { entries, files })
+}
+
+impl Manifest {
+    /// Read a manifest
+    pub fn parse(data: &[u8]) -> Result<Manifest, Error> {
+        let data = read_header(data, "manifest", MANIFEST_MAGIC, MANIFEST_VERSION)?;
+        read_listing(data, "manifest", None)
+    }
+
+    /// Remove the entries excluded by the filter
+    ///
+    /// If a file is excluded but some other names for it are not, the first
+    /// of those is listed as the file instead of a link to it, and the others
+    /// as links to that one.
+    pub(crate) fn apply_filter(&mut self, filter: &Filter) -> Result<(), Error> {
+        let mut excluded = HashMap::new();
+        for event in &self.entries {
+            if let SourceEvent::FileEntry(ref path, size, ref hash, ref metadata) = *event {
+                if is_entry_excluded(filter, event)? {
+                    excluded.insert(path.clone(), (size, hash.clone(), metadata.clone()));
+                }
+            }
+        }
+        // Name replacing an excluded file, once a link to it is kept
+        let mut renamed: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for event in std::mem::take(&mut self.entries) {
+            if is_entry_excluded(filter, &event)? {
+                continue;
+            }
+            let event = match event {
+                SourceEvent::HardLinkEntry(path, target) => {
+                    if let Some(new_target) = renamed.get(&target) {
+                        SourceEvent::HardLinkEntry(path, new_target.clone())
+                    } else if let Some((size, hash, metadata)) = excluded.remove(&target) {
+                        if let Some(blocks) = self.files.get(&target).cloned() {
+                            self.files.insert(path.clone(), blocks);
+                        }
+                        renamed.insert(target, path.clone());
+                        SourceEvent::FileEntry(path, size, hash, metadata)
+                    } else {
+                        SourceEvent::HardLinkEntry(path, target)
+                    }
+                }
+                event => event,
+            };
+            entries.push(event);
+        }
+        self.entries = entries;
+        Ok(())
+    }
+}
+
+/// Whether an entry from a manifest is excluded by the filter
+#[allow(clippy::needless_borrowed_reference)]
+fn is_entry_excluded(filter: &Filter, event: &SourceEvent) -> Result<bool, Error> {
+    let (path, is_dir) = match event {
+        &SourceEvent::FileEntry(ref path, ..)
+        | &SourceEvent::SymlinkEntry(ref path, _)
+        | &SourceEvent::HardLinkEntry(ref path, _) => (path, false),
+        &SourceEvent::DirectoryEntry(ref path, _) => (path, true),
+        _ => return Ok(false),
+    };
+    Ok(filter.is_path_excluded(&bytes_to_path(path.clone())?, is_dir))
+}
+
+/// Write the manifest of an indexed directory
+///
+/// The entries are listed like `FsSource` does, excluding the ones that don't
+/// match `filter`.
+pub fn write_manifest<W: Write>(
+    index: &Index,
+    filter: &Filter,
+    mut writer: W,
+) -> Result<(), Error> {
+    writer.write_all(MANIFEST_MAGIC)?;
+    writer.write_all(&[MANIFEST_VERSION])?;
+    write_listing(index, filter, writer)?;
+    Ok(())
+}
+
+/// Write the messages of a manifest, without the header
+///
+/// This returns the blocks of the files that were listed, in order.
+pub(crate) fn write_listing<W: Write>(
+    index: &Index,
+    filter: &Filter,
+    mut writer: W,
+) -> Result<Vec<(HashDigest, usize)>, Error> {
+    let hard_links: HashMap<PathBuf, PathBuf> = index.list_hard_links()?.into_iter().collect();
+    // Directories first, so they exist before their content
+    for (_file_id, path, modified, mode) in index.list_directories()? {
+        if filter.is_path_excluded(&path, true) {
+            continue;
+        }
+        let metadata = FileMetadata { modified, mode };
+        write_message(Message::DirectoryEntry(path_to_bytes(&path)?, metadata), &mut writer)?;
+    }
+    let mut files = Vec::new();
+    for (file_id, path, modified, mode, size, blocks_hash) in index.list_files()? {
+        if filter.is_path_excluded(&path, false) {
+            continue;
+        }
+        // Other names for a file are listed as links to it
+        if let Some(target) = hard_links.get(&path) {
+            write_message(Message::HardLinkEntry(path_to_bytes(&path)?, path_to_bytes(target)?), &mut writer)?;
+            continue;
+        }
+        let metadata = FileMetadata { modified, mode };
+        write_message(Message::FileEntry(path_to_bytes(&path)?, size, blocks_hash, metadata), &mut writer)?;
+        files.push((file_id, path));
+    }
+    for (_file_id, path, target) in index.list_symlinks()? {
+        if filter.is_path_excluded(&path, false) {
+            continue;
+        }
+        write_message(Message::SymlinkEntry(path_to_bytes(&path)?, path_to_bytes(&target)?), &mut writer)?;
+    }
+    write_message(Message::EndFiles, &mut writer)?;
+
+    // FIXME: Don't get all blocks at once, iterate
+    let mut all_blocks = Vec::new();
+    for (file_id, path) in files {
+        write_message(Message::FileStart(path_to_bytes(&path)?), &mut writer)?;
+        for (hash, _offset, size) in index.list_file_blocks(file_id)? {
+            write_message(Message::FileBlock(hash.clone(), size), &mut writer)?;
+            all_blocks.push((hash, size));
+        }
+        write_message(Message::FileEnd, &mut writer)?;
+    }
+    Ok(all_blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HashDigest;
+    use crate::sync::proto::{Message, write_message};
+    use super::{MANIFEST_MAGIC, MANIFEST_VERSION, Manifest};
+
+    fn error(data: &[u8]) -> String {
+        match Manifest::parse(data) {
+            Ok(_) => panic!("Invalid manifest was accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut data = MANIFEST_MAGIC.to_owned();
+        data.push(MANIFEST_VERSION);
+        write_message(Message::EndFiles, &mut data).unwrap();
+        write_message(Message::FileStart(b"file"), &mut data).unwrap();
+        let hash = HashDigest(*b"12345678901234567890");
+        write_message(Message::FileBlock(hash.clone(), 42), &mut data).unwrap();
+
+        // Missing FileEnd
+        assert_eq!(error(&data), "Invalid manifest: truncated");
+        assert_eq!(error(&data[..data.len() - 1]), "Invalid manifest: truncated");
+
+        write_message(Message::FileEnd, &mut data).unwrap();
+        let manifest = Manifest::parse(&data).unwrap();
+        assert!(manifest.entries.is_empty());
+        assert_eq!(manifest.files.get(b"file" as &[u8]), Some(&vec![(hash, 42)]));
+
+        // Entries come before EndFiles
+        write_message(Message::HardLinkEntry(b"a", b"b"), &mut data).unwrap();
+        assert_eq!(error(&data), "Invalid manifest: unexpected message");
+
+        assert_eq!(error(b"not a manifest"), "Invalid manifest: not a syncfast manifest");
+        let mut data = MANIFEST_MAGIC.to_owned();
+        data.push(99);
+        assert_eq!(
+            error(&data),
+            format!("This manifest uses format version 99, we use version {}", MANIFEST_VERSION),
+        );
+    }
+}
@@ -1,8 +1,17 @@
 //! This module contains the transfer protocol handlers.
 
+pub mod daemon;
 pub mod fs;
+pub mod http;
 pub mod locations;
+mod manifest;
+pub mod patch;
+mod proto;
+pub mod signature;
 pub mod ssh;
+mod summary;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
 use log::info;
@@ -11,32 +20,72 @@ use futures::sink::Sink;
 use futures::stream::{LocalBoxStream, StreamExt};
 use std::pin::Pin;
 
-use crate::{Error, HashDigest};
+use crate::{Error, Filter, HashDigest, SymlinkPolicy};
+
+pub use self::summary::BlockSummary;
+
+/// Metadata of a file, sent along with its entry and applied at the
+/// destination
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// Modification time
+    pub modified: chrono::DateTime<chrono::Utc>,
+    /// Permission bits
+    pub mode: u32,
+}
 
 pub enum SourceEvent {
-    FileEntry(Vec<u8>, usize, HashDigest),
+    FileEntry(Vec<u8>, usize, HashDigest, FileMetadata),
+    SymlinkEntry(Vec<u8>, Vec<u8>),
+    DirectoryEntry(Vec<u8>, FileMetadata),
+    HardLinkEntry(Vec<u8>, Vec<u8>),
     EndFiles,
     FileStart(Vec<u8>),
     FileBlock(HashDigest, usize),
     FileEnd,
     BlockData(HashDigest, Vec<u8>),
+    /// Name of the file, sent first if the source is a single file rather
+    /// than a directory; its entry then has an empty name
+    SingleFile(Vec<u8>),
 }
 
 impl std::fmt::Debug for SourceEvent {
+    #[allow(clippy::match_ref_pats, clippy::needless_borrowed_reference)]
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            &SourceEvent::FileEntry(ref path, size, ref hash) => write!(
+            &SourceEvent::FileEntry(ref path, size, ref hash, ref metadata) => write!(
                 f,
-                "FileEntry({}, {}, {})",
-                String::from_utf8_lossy(&path),
+                "FileEntry({}, {}, {}, {}, {:o})",
+                String::from_utf8_lossy(path),
                 size,
                 hash,
+                metadata.modified,
+                metadata.mode,
+            ),
+            &SourceEvent::SymlinkEntry(ref path, ref target) => write!(
+                f,
+                "SymlinkEntry({}, {})",
+                String::from_utf8_lossy(path),
+                String::from_utf8_lossy(target),
+            ),
+            &SourceEvent::DirectoryEntry(ref path, ref metadata) => write!(
+                f,
+                "DirectoryEntry({}, {}, {:o})",
+                String::from_utf8_lossy(path),
+                metadata.modified,
+                metadata.mode,
+            ),
+            &SourceEvent::HardLinkEntry(ref path, ref target) => write!(
+                f,
+                "HardLinkEntry({}, {})",
+                String::from_utf8_lossy(path),
+                String::from_utf8_lossy(target),
             ),
             &SourceEvent::EndFiles => write!(f, "EndFiles"),
             &SourceEvent::FileStart(ref path) => write!(
                 f,
                 "FileStart({})",
-                String::from_utf8_lossy(&path),
+                String::from_utf8_lossy(path),
             ),
             &SourceEvent::FileBlock(ref hash, size) => write!(
                 f,
@@ -51,30 +100,150 @@ impl std::fmt::Debug for SourceEvent {
                 hash,
                 data.len(),
             ),
+            &SourceEvent::SingleFile(ref name) => write!(
+                f,
+                "SingleFile({})",
+                String::from_utf8_lossy(name),
+            ),
         }
     }
 }
 
 pub enum DestinationEvent {
+    /// Blocks the destination already has, sent before any request, so the
+    /// source can send the others along with the file's block list
+    BlockSummary(BlockSummary),
     GetFile(Vec<u8>),
-    GetBlock(HashDigest),
+    GetBlocks(Vec<HashDigest>),
     Complete,
 }
 
 impl std::fmt::Debug for DestinationEvent {
+    #[allow(clippy::match_ref_pats, clippy::needless_borrowed_reference)]
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            &DestinationEvent::BlockSummary(ref summary) => write!(
+                f,
+                "BlockSummary(<{} bytes>)",
+                summary.bits().len(),
+            ),
             &DestinationEvent::GetFile(ref path) => write!(
                 f,
                 "GetFile({})",
-                String::from_utf8_lossy(&path),
+                String::from_utf8_lossy(path),
+            ),
+            &DestinationEvent::GetBlocks(ref hashes) => write!(
+                f,
+                "GetBlocks(<{} blocks>)",
+                hashes.len(),
             ),
-            &DestinationEvent::GetBlock(ref hash) => write!(f, "GetBlock({})", hash),
             &DestinationEvent::Complete => write!(f, "Complete"),
         }
     }
 }
 
+/// When to delete files from the destination that are not in the source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteMode {
+    /// Never delete extraneous files
+    Never,
+    /// Delete extraneous files once the source's file list has been
+    /// received, before requesting any file's block list
+    ///
+    /// This frees their space first, but their blocks can't be re-used.
+    Before,
+    /// Delete extraneous files once all the files' block lists have been
+    /// received, before receiving block data
+    ///
+    /// Extraneous files can still be used as a source of blocks, for example
+    /// if a file was renamed, but their space is freed before the bulk of the
+    /// transfer.
+    During,
+    /// Delete extraneous files once the transfer is complete
+    After,
+}
+
+impl Default for DeleteMode {
+    fn default() -> DeleteMode {
+        DeleteMode::Never
+    }
+}
+
+/// Log target for the report of a dry run, see `DestinationOptions::dry_run`
+pub const DRY_RUN_LOG_TARGET: &str = "syncfast::dry_run";
+
+/// Default compression level for block data sent over the network
+pub const DEFAULT_COMPRESS_LEVEL: u32 = 6;
+
+/// Default limit on the length of strings in messages received over the
+/// network, such as file names and block data
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1 << 20; // 1 MiB
+
+/// Options for the source side of a sync
+#[derive(Clone, Debug)]
+pub struct SourceOptions {
+    /// What to do with symbolic links
+    pub symlinks: SymlinkPolicy,
+    /// Which files to send
+    pub filter: Filter,
+    /// Compression level (1-9) for block data sent by a remote source, 0 to
+    /// disable compression
+    pub compress_level: u32,
+    /// Limit on the length of strings in messages received from a remote
+    /// destination
+    pub max_message_length: usize,
+}
+
+impl Default for SourceOptions {
+    fn default() -> SourceOptions {
+        SourceOptions {
+            symlinks: Default::default(),
+            filter: Default::default(),
+            compress_level: DEFAULT_COMPRESS_LEVEL,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+        }
+    }
+}
+
+/// Options for the destination side of a sync
+#[derive(Clone, Debug)]
+pub struct DestinationOptions {
+    /// Whether and when to delete files that are not in the source
+    pub delete: DeleteMode,
+    /// Which files to consider; excluded files are not used and not deleted
+    pub filter: Filter,
+    /// Only report what would be done, without changing any file
+    ///
+    /// The changes are logged at the `Info` level, with the target
+    /// `DRY_RUN_LOG_TARGET`.
+    pub dry_run: bool,
+    /// Compression level (1-9) for block data sent to a remote destination,
+    /// 0 to disable compression
+    pub compress_level: u32,
+    /// Send a summary of the blocks we have to the source, so it can send the
+    /// missing ones without waiting for them to be requested
+    ///
+    /// This saves a round trip, at the cost of sending about 10 bits per
+    /// block we have.
+    pub block_summary: bool,
+    /// Limit on the length of strings in messages received from a remote
+    /// source
+    pub max_message_length: usize,
+}
+
+impl Default for DestinationOptions {
+    fn default() -> DestinationOptions {
+        DestinationOptions {
+            delete: Default::default(),
+            filter: Default::default(),
+            dry_run: false,
+            compress_level: DEFAULT_COMPRESS_LEVEL,
+            block_summary: true,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+        }
+    }
+}
+
 /// The source, representing where the files are coming from.
 ///
 /// This is relative to a single process, e.g. the sending side has a source
@@ -109,6 +278,10 @@ pub async fn do_sync(
         source_from.forward(destination_to),
         destination_from.forward(source_to),
     );
+    // An error from the remote side explains the other one (e.g. closed pipe)
+    if let Err(Error::Remote(..)) = r2 {
+        return r2;
+    }
     r1?;
     r2?;
     info!("Sync complete");
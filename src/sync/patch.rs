@@ -0,0 +1,353 @@
+//! Offline synchronization, through a patch file.
+//!
+//! A patch is computed from the index or signature of the old version of a
+//! directory, and the new version. It has the same content as a manifest (see
+//! the `manifest` module) with `PATCH_MAGIC` and its own version, followed by
+//! a `BLOCK_DATA` message for each block the old version doesn't have. It is
+//! applied by using it as a source, which only needs the patch and no
+//! connection to the new version.
+
+use futures::channel::mpsc::{Receiver, channel};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use log::{debug, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+
+use crate::{Error, HashDigest, Index, IndexOptions};
+use crate::index::zero_digest;
+use crate::sync::{DestinationEvent, Source, SourceEvent, SourceOptions};
+use crate::sync::fs::{get_block_data, open_index};
+use crate::sync::manifest::{read_header, read_listing, write_listing};
+use crate::sync::proto::{OwnedMessage, write_message};
+use crate::sync::signature::Signature;
+use crate::sync::utils::write_file_atomic;
+
+/// Magic bytes at the start of a patch
+pub const PATCH_MAGIC: &[u8] = b"syncfast patch\n";
+
+/// Version of the patch format, written after the magic bytes
+pub const PATCH_VERSION: u8 = 1;
+
+/// Write a patch from the old version of a directory, described by its
+/// signature, to the current content of `new_dir`
+///
+/// Only the data of the blocks that are not present in `old` is included. The
+/// new directory is indexed first, like a source would.
+pub fn write_patch<W: Write>(
+    old: &Signature,
+    new_dir: &Path,
+    options: &IndexOptions,
+    mut writer: W,
+) -> Result<(), Error> {
+    let (mut index, root_dir, _) = open_index(new_dir, false, options, false)?;
+
+    writer.write_all(PATCH_MAGIC)?;
+    writer.write_all(&[PATCH_VERSION])?;
+    let blocks = write_listing(&index, &options.filter, &mut writer)?;
+
+    let mut written = HashSet::new();
+    for (hash, size) in blocks {
+        // Blocks of zeros are never requested, the destination creates them
+        if old.has_block(&hash)
+            || hash == zero_digest(size)
+            || written.contains(&hash)
+        {
+            continue;
+        }
+        written.insert(hash.clone());
+        let event = get_block_data(&mut index, &root_dir, hash)?;
+        write_message(&OwnedMessage::from(event), &mut writer)?;
+    }
+    info!("Patch includes {} blocks", written.len());
+    Ok(())
+}
+
+/// Write a patch file, see `write_patch()`
+///
+/// `old` is the signature or index file of the old version, or the directory
+/// the index is in.
+pub fn diff(
+    old: &Path,
+    new_dir: &Path,
+    options: &IndexOptions,
+    output: &Path,
+) -> Result<(), Error> {
+    let old = if old.is_dir() {
+        old.join(".syncfast.idx")
+    } else {
+        old.to_owned()
+    };
+    // Index::open() would create an empty index
+    if !old.is_file() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Signature or index {:?} doesn't exist", old),
+        )));
+    }
+    let old = if Signature::is_signature(&old)? {
+        Signature::open(&old)?
+    } else {
+        Signature::from_index(&Index::open(&old)?)?
+    };
+
+    write_file_atomic(output, |writer| {
+        write_patch(&old, new_dir, options, writer)
+    })?;
+    info!("Wrote patch {:?}", output);
+    Ok(())
+}
+
+/// Use a patch file as a source, so it can be applied to a destination
+///
+/// The destination has to be in the state described by the index the patch
+/// was computed from, otherwise it might need blocks that are not in the
+/// patch.
+pub fn patch_source(
+    path: &Path,
+    options: &SourceOptions,
+) -> Result<Source, Error> {
+    info!("Reading patch {:?}", path);
+    // FIXME: Don't load all the block data in memory
+    let data = std::fs::read(path)?;
+    let data = read_header(&data, "patch", PATCH_MAGIC, PATCH_VERSION)?;
+    let mut blocks = HashMap::new();
+    let mut patch = read_listing(data, "patch", Some(&mut blocks))?;
+    patch.apply_filter(&options.filter)?;
+    let entries: VecDeque<SourceEvent> = patch.entries.into_iter().collect();
+    debug!("PatchSource: preparing to send {} entries", entries.len());
+
+    // Like FsSource, handle requests in the Stream, with a channel for the Sink
+    let (sender, receiver) = channel(1);
+    Ok(Source {
+        stream: futures::stream::unfold(
+            PatchSourceFrom {
+                receiver,
+                files: patch.files,
+                blocks,
+                state: PatchSourceState::ListFiles(entries),
+            },
+            PatchSourceFrom::stream,
+        ).boxed_local(),
+        sink: Box::pin(futures::sink::unfold((), move |(), event: DestinationEvent| {
+            let mut sender = sender.clone();
+            async move {
+                sender.send(event).await.map_err(|_| Error::Io(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "PatchSource channel is closed")))
+            }
+        })),
+    })
+}
+
+enum PatchSourceState {
+    ListFiles(VecDeque<SourceEvent>),
+    Respond,
+    ListBlocks(VecDeque<(HashDigest, usize)>),
+    /// Sending the rest of a batch of requested blocks
+    SendBlocks(VecDeque<HashDigest>),
+}
+
+struct PatchSourceFrom {
+    receiver: Receiver<DestinationEvent>,
+    /// Blocks of each file
+    files: HashMap<Vec<u8>, Vec<(HashDigest, usize)>>,
+    /// Data of the blocks included in the patch
+    blocks: HashMap<HashDigest, Vec<u8>>,
+    state: PatchSourceState,
+}
+
+impl PatchSourceFrom {
+    fn get_block_data(&self, hash: HashDigest) -> Result<SourceEvent, Error> {
+        match self.blocks.get(&hash) {
+            Some(data) => Ok(SourceEvent::BlockData(hash, data.clone())),
+            None => Err(Error::Sync(
+                "Requested block is not in the patch, the destination \
+                 changed since the index the patch was computed from"
+                    .to_owned(),
+            )),
+        }
+    }
+
+    #[allow(clippy::manual_async_fn)]
+    fn stream(mut source: PatchSourceFrom) -> impl Future<Output=Option<(Result<SourceEvent, Error>, PatchSourceFrom)>> {
+        async move {
+            macro_rules! err {
+                ($e:expr) => {
+                    Some((Err($e), source))
+                }
+            }
+            // FIXME: Replace by try_block when supported by Rust
+            macro_rules! try_ {
+                ($v:expr) => {
+                    match $v {
+                        Ok(r) => r,
+                        Err(e) => return err!(e),
+                    }
+                }
+            }
+
+            loop {
+                return match source.state {
+                    // Send files list
+                    PatchSourceState::ListFiles(ref mut entries) => {
+                        match entries.pop_front() {
+                            Some(event) => {
+                                debug!("PatchSource: send {:?}", event);
+                                Some((Ok(event), source))
+                            }
+                            None => {
+                                debug!("PatchSource: state=Respond");
+                                source.state = PatchSourceState::Respond;
+                                debug!("PatchSource: send EndFiles");
+                                Some((Ok(SourceEvent::EndFiles), source))
+                            }
+                        }
+                    }
+                    // Files are sent, respond to requests
+                    PatchSourceState::Respond => {
+                        let req = match source.receiver.next().await {
+                            None => {
+                                debug!("PatchSource: got end of input");
+                                return None;
+                            }
+                            Some(e) => e,
+                        };
+                        debug!("PatchSource: recv {:?}", req);
+                        match req {
+                            // The patch has what we can send
+                            DestinationEvent::BlockSummary(_) => continue,
+                            DestinationEvent::GetFile(name) => {
+                                let blocks = match source.files.get(&name) {
+                                    Some(b) => b.iter().cloned().collect(),
+                                    None => return err!(Error::Sync("Requested file is unknown".to_owned())),
+                                };
+                                debug!("PatchSource: state=ListBlocks");
+                                source.state = PatchSourceState::ListBlocks(blocks);
+                                debug!("PatchSource: send FileStart");
+                                Some((Ok(SourceEvent::FileStart(name)), source))
+                            }
+                            DestinationEvent::GetBlocks(hashes) => {
+                                let mut hashes: VecDeque<HashDigest> = hashes.into();
+                                let hash = match hashes.pop_front() {
+                                    Some(h) => h,
+                                    None => return err!(Error::Sync("Empty block request".to_owned())),
+                                };
+                                let event = try_!(source.get_block_data(hash));
+                                if !hashes.is_empty() {
+                                    debug!("PatchSource: state=SendBlocks({} blocks)", hashes.len());
+                                    source.state = PatchSourceState::SendBlocks(hashes);
+                                }
+                                debug!("PatchSource: send BlockData");
+                                Some((Ok(event), source))
+                            }
+                            DestinationEvent::Complete => {
+                                // The stream ends here, with the source
+                                debug!("PatchSource: done");
+                                None
+                            }
+                        }
+                    }
+                    // List blocks
+                    PatchSourceState::ListBlocks(ref mut list) => {
+                        match list.pop_front() {
+                            Some((hash, size)) => {
+                                debug!("PatchSource: send FileBlock");
+                                Some((Ok(SourceEvent::FileBlock(hash, size)), source))
+                            }
+                            None => {
+                                debug!("PatchSource: state=Respond");
+                                source.state = PatchSourceState::Respond;
+                                debug!("PatchSource: send FileEnd");
+                                Some((Ok(SourceEvent::FileEnd), source))
+                            }
+                        }
+                    }
+                    // Send the blocks requested in a batch
+                    PatchSourceState::SendBlocks(ref mut hashes) => {
+                        let hash = hashes.pop_front().unwrap();
+                        if hashes.is_empty() {
+                            debug!("PatchSource: state=Respond");
+                            source.state = PatchSourceState::Respond;
+                        }
+                        let event = try_!(source.get_block_data(hash));
+                        debug!("PatchSource: send BlockData");
+                        Some((Ok(event), source))
+                    }
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use crate::Error;
+    use crate::sync::do_sync;
+    use crate::sync::fs::{fs_destination, open_index};
+    use crate::sync::signature::signature;
+    use crate::sync::test_utils::{big_file, change_middle};
+    use super::{diff, patch_source};
+
+    fn apply(patch: &Path, destination: &Path) -> Result<(), Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let source = patch_source(patch, &Default::default())?;
+            let destination = fs_destination(destination.to_owned(), &Default::default())?;
+            do_sync(source, destination).await
+        })
+    }
+
+    #[test]
+    fn test_patch() {
+        let big = big_file();
+        let old = TempDir::new().unwrap();
+        fs::write(old.path().join("big"), &big).unwrap();
+        fs::write(old.path().join("small"), b"small file").unwrap();
+        let new = TempDir::new().unwrap();
+        let changed = change_middle(&big);
+        fs::write(new.path().join("big"), &changed).unwrap();
+        fs::write(new.path().join("small"), b"small file").unwrap();
+        fs::create_dir(new.path().join("dir")).unwrap();
+        fs::write(new.path().join("dir/added"), b"new file").unwrap();
+        let output = TempDir::new().unwrap();
+        let patch = output.path().join("patch");
+
+        // No index for the old version
+        match diff(old.path(), new.path(), &Default::default(), &patch) {
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            _ => panic!("Missing index was accepted"),
+        }
+
+        open_index(old.path(), false, &Default::default(), false).unwrap();
+        let old_index = old.path().join(".syncfast.idx");
+        diff(&old_index, new.path(), &Default::default(), &patch).unwrap();
+        // Only the changed blocks are included
+        let size = fs::metadata(&patch).unwrap().len();
+        assert!(size < 100000, "patch is {} bytes", size);
+        assert_eq!(output.path().read_dir().unwrap().count(), 1);
+
+        // Applying it to a different tree fails
+        let other = TempDir::new().unwrap();
+        let error = apply(&patch, other.path()).unwrap_err().to_string();
+        assert!(error.contains("not in the patch"), "{}", error);
+
+        // The same blocks are included when using a signature
+        let sig = output.path().join("sig");
+        signature(old.path(), &Default::default(), true, &sig).unwrap();
+        let patch_from_sig = output.path().join("patch2");
+        diff(&sig, new.path(), &Default::default(), &patch_from_sig).unwrap();
+        assert_eq!(fs::read(&patch_from_sig).unwrap(), fs::read(&patch).unwrap());
+
+        apply(&patch, old.path()).unwrap();
+        assert_eq!(fs::read(old.path().join("big")).unwrap(), changed);
+        assert_eq!(fs::read(old.path().join("small")).unwrap(), b"small file");
+        assert_eq!(fs::read(old.path().join("dir/added")).unwrap(), b"new file");
+    }
+}
@@ -0,0 +1,983 @@
+use chrono::TimeZone;
+use log::warn;
+use std::convert::{TryFrom, TryInto};
+use std::io::{Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::HashDigest;
+use crate::HASH_DIGEST_LEN;
+use crate::streaming_iterator::StreamingIterator;
+use crate::sync::{
+    BlockSummary, DEFAULT_MAX_MESSAGE_LENGTH, DestinationEvent, FileMetadata,
+    SourceEvent,
+};
+
+#[derive(Debug)]
+pub struct Error(pub &'static str);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> crate::Error {
+        crate::Error::Protocol(Box::new(e))
+    }
+}
+
+/// Version of the protocol, sent in the `HELLO` message
+///
+/// Both sides have to use the same version.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message<'a> {
+    /// Protocol version and capabilities (separated by spaces), sent first
+    Hello(u32, &'a [u8]),
+    FileEntry(&'a [u8], usize, HashDigest, FileMetadata),
+    SymlinkEntry(&'a [u8], &'a [u8]),
+    DirectoryEntry(&'a [u8], FileMetadata),
+    HardLinkEntry(&'a [u8], &'a [u8]),
+    EndFiles,
+    GetFile(&'a [u8]),
+    FileStart(&'a [u8]),
+    FileBlock(HashDigest, usize),
+    FileEnd,
+    /// Request for a batch of blocks
+    GetBlocks(Vec<HashDigest>),
+    BlockData(HashDigest, &'a [u8]),
+    /// Block data compressed with deflate, if the peer has the capability
+    CompressedBlockData(HashDigest, &'a [u8]),
+    Complete,
+    /// Kind, path (may be empty) and message, sent before aborting
+    Error(&'a [u8], &'a [u8], &'a [u8]),
+    /// Blocks the destination has, sent before any request
+    BlockSummary(BlockSummary),
+    /// Arguments of `remote-send` or `remote-recv`, sent to a daemon before
+    /// `HELLO`
+    Command(Vec<&'a [u8]>),
+    /// Name of the file, sent first if the source is a single file
+    SingleFile(&'a [u8]),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OwnedMessage {
+    Hello(u32, Vec<u8>),
+    FileEntry(Vec<u8>, usize, HashDigest, FileMetadata),
+    SymlinkEntry(Vec<u8>, Vec<u8>),
+    DirectoryEntry(Vec<u8>, FileMetadata),
+    HardLinkEntry(Vec<u8>, Vec<u8>),
+    EndFiles,
+    GetFile(Vec<u8>),
+    FileStart(Vec<u8>),
+    FileBlock(HashDigest, usize),
+    FileEnd,
+    GetBlocks(Vec<HashDigest>),
+    BlockData(HashDigest, Vec<u8>),
+    CompressedBlockData(HashDigest, Vec<u8>),
+    Complete,
+    Error(Vec<u8>, Vec<u8>, Vec<u8>),
+    BlockSummary(BlockSummary),
+    Command(Vec<Vec<u8>>),
+    SingleFile(Vec<u8>),
+}
+
+impl<'a> From<Message<'a>> for OwnedMessage {
+    fn from(msg: Message<'a>) -> OwnedMessage {
+        match msg {
+            Message::Hello(version, capabilities) => OwnedMessage::Hello(version, capabilities.to_owned()),
+            Message::FileEntry(name, size, digest, metadata) => OwnedMessage::FileEntry(name.to_owned(), size, digest, metadata),
+            Message::SymlinkEntry(name, target) => OwnedMessage::SymlinkEntry(name.to_owned(), target.to_owned()),
+            Message::DirectoryEntry(name, metadata) => OwnedMessage::DirectoryEntry(name.to_owned(), metadata),
+            Message::HardLinkEntry(name, target) => OwnedMessage::HardLinkEntry(name.to_owned(), target.to_owned()),
+            Message::EndFiles => OwnedMessage::EndFiles,
+            Message::GetFile(name) => OwnedMessage::GetFile(name.to_owned()),
+            Message::FileStart(name) => OwnedMessage::FileStart(name.to_owned()),
+            Message::FileBlock(digest, size) => OwnedMessage::FileBlock(digest, size),
+            Message::FileEnd => OwnedMessage::FileEnd,
+            Message::GetBlocks(digests) => OwnedMessage::GetBlocks(digests),
+            Message::BlockData(digest, data) => OwnedMessage::BlockData(digest, data.to_owned()),
+            Message::CompressedBlockData(digest, data) => OwnedMessage::CompressedBlockData(digest, data.to_owned()),
+            Message::Complete => OwnedMessage::Complete,
+            Message::Error(kind, path, message) => OwnedMessage::Error(kind.to_owned(), path.to_owned(), message.to_owned()),
+            Message::BlockSummary(summary) => OwnedMessage::BlockSummary(summary),
+            Message::Command(args) => OwnedMessage::Command(args.into_iter().map(|a| a.to_owned()).collect()),
+            Message::SingleFile(name) => OwnedMessage::SingleFile(name.to_owned()),
+        }
+    }
+}
+
+impl<'a> From<&'a OwnedMessage> for Message<'a> {
+    #[allow(clippy::match_ref_pats, clippy::needless_borrowed_reference)]
+    fn from(msg: &'a OwnedMessage) -> Message<'a> {
+        match msg {
+            &OwnedMessage::Hello(version, ref capabilities) => Message::Hello(version, capabilities),
+            &OwnedMessage::FileEntry(ref name, size, ref digest, ref metadata) => Message::FileEntry(name, size, digest.clone(), metadata.clone()),
+            &OwnedMessage::SymlinkEntry(ref name, ref target) => Message::SymlinkEntry(name, target),
+            &OwnedMessage::DirectoryEntry(ref name, ref metadata) => Message::DirectoryEntry(name, metadata.clone()),
+            &OwnedMessage::HardLinkEntry(ref name, ref target) => Message::HardLinkEntry(name, target),
+            &OwnedMessage::EndFiles => Message::EndFiles,
+            &OwnedMessage::GetFile(ref name) => Message::GetFile(name),
+            &OwnedMessage::FileStart(ref name) => Message::FileStart(name),
+            &OwnedMessage::FileBlock(ref digest, size) => Message::FileBlock(digest.clone(), size),
+            &OwnedMessage::FileEnd => Message::FileEnd,
+            &OwnedMessage::GetBlocks(ref digests) => Message::GetBlocks(digests.clone()),
+            &OwnedMessage::BlockData(ref digest, ref data) => Message::BlockData(digest.clone(), data),
+            &OwnedMessage::CompressedBlockData(ref digest, ref data) => Message::CompressedBlockData(digest.clone(), data),
+            &OwnedMessage::Complete => Message::Complete,
+            &OwnedMessage::Error(ref kind, ref path, ref message) => Message::Error(kind, path, message),
+            &OwnedMessage::BlockSummary(ref summary) => Message::BlockSummary(summary.clone()),
+            &OwnedMessage::Command(ref args) => Message::Command(args.iter().map(|a| a.as_slice()).collect()),
+            &OwnedMessage::SingleFile(ref name) => Message::SingleFile(name),
+        }
+    }
+}
+
+impl From<SourceEvent> for OwnedMessage {
+    fn from(event: SourceEvent) -> OwnedMessage {
+        match event {
+            SourceEvent::FileEntry(name, size, hash, metadata) => OwnedMessage::FileEntry(name, size, hash, metadata),
+            SourceEvent::SymlinkEntry(name, target) => OwnedMessage::SymlinkEntry(name, target),
+            SourceEvent::DirectoryEntry(name, metadata) => OwnedMessage::DirectoryEntry(name, metadata),
+            SourceEvent::HardLinkEntry(name, target) => OwnedMessage::HardLinkEntry(name, target),
+            SourceEvent::EndFiles => OwnedMessage::EndFiles,
+            SourceEvent::FileStart(name) => OwnedMessage::FileStart(name),
+            SourceEvent::FileBlock(hash, size) => OwnedMessage::FileBlock(hash, size),
+            SourceEvent::FileEnd => OwnedMessage::FileEnd,
+            SourceEvent::BlockData(hash, data) => OwnedMessage::BlockData(hash, data),
+            SourceEvent::SingleFile(name) => OwnedMessage::SingleFile(name),
+        }
+    }
+}
+
+impl From<DestinationEvent> for OwnedMessage {
+    fn from(event: DestinationEvent) -> OwnedMessage {
+        match event {
+            DestinationEvent::BlockSummary(summary) => OwnedMessage::BlockSummary(summary),
+            DestinationEvent::GetFile(name) => OwnedMessage::GetFile(name),
+            DestinationEvent::GetBlocks(digests) => OwnedMessage::GetBlocks(digests),
+            DestinationEvent::Complete => OwnedMessage::Complete,
+        }
+    }
+}
+
+impl TryFrom<OwnedMessage> for SourceEvent {
+    type Error = ();
+
+    fn try_from(message: OwnedMessage) -> Result<SourceEvent, ()> {
+        Ok(match message {
+            OwnedMessage::FileEntry(name, size, hash, metadata) => SourceEvent::FileEntry(name, size, hash, metadata),
+            OwnedMessage::SymlinkEntry(name, target) => SourceEvent::SymlinkEntry(name, target),
+            OwnedMessage::DirectoryEntry(name, metadata) => SourceEvent::DirectoryEntry(name, metadata),
+            OwnedMessage::HardLinkEntry(name, target) => SourceEvent::HardLinkEntry(name, target),
+            OwnedMessage::EndFiles => SourceEvent::EndFiles,
+            OwnedMessage::FileStart(name) => SourceEvent::FileStart(name),
+            OwnedMessage::FileBlock(hash, size) => SourceEvent::FileBlock(hash, size),
+            OwnedMessage::FileEnd => SourceEvent::FileEnd,
+            OwnedMessage::BlockData(hash, data) => SourceEvent::BlockData(hash, data),
+            OwnedMessage::SingleFile(name) => SourceEvent::SingleFile(name),
+            _ => return Err(()),
+        })
+    }
+}
+
+impl TryFrom<OwnedMessage> for DestinationEvent {
+    type Error = ();
+
+    fn try_from(message: OwnedMessage) -> Result<DestinationEvent, ()> {
+        Ok(match message {
+            OwnedMessage::BlockSummary(summary) => DestinationEvent::BlockSummary(summary),
+            OwnedMessage::GetFile(name) => DestinationEvent::GetFile(name),
+            OwnedMessage::GetBlocks(digests) => DestinationEvent::GetBlocks(digests),
+            OwnedMessage::Complete => DestinationEvent::Complete,
+            _ => return Err(()),
+        })
+    }
+}
+
+// Each message is a tag byte followed by its fields. Integers are unsigned
+// LEB128 varints, timestamps are a zigzag-encoded varint of seconds followed
+// by a varint of nanoseconds, digests are written as their raw bytes, and
+// strings (file names, link targets, block data) are a varint length followed
+// by the bytes.
+
+const TAG_HELLO: u8 = 1;
+const TAG_FILE_ENTRY: u8 = 2;
+const TAG_SYMLINK_ENTRY: u8 = 3;
+const TAG_DIRECTORY_ENTRY: u8 = 4;
+const TAG_HARDLINK_ENTRY: u8 = 5;
+const TAG_END_FILES: u8 = 6;
+const TAG_GET_FILE: u8 = 7;
+const TAG_FILE_START: u8 = 8;
+const TAG_FILE_BLOCK: u8 = 9;
+const TAG_FILE_END: u8 = 10;
+const TAG_GET_BLOCKS: u8 = 11;
+const TAG_BLOCK_DATA: u8 = 12;
+const TAG_COMPLETE: u8 = 13;
+const TAG_COMPRESSED_BLOCK_DATA: u8 = 14;
+const TAG_ERROR: u8 = 15;
+const TAG_BLOCK_SUMMARY: u8 = 16;
+const TAG_COMMAND: u8 = 17;
+const TAG_SINGLE_FILE: u8 = 18;
+
+fn write_varint<W: Write>(mut writer: W, mut value: u64) -> std::io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&buf[..len])
+}
+
+fn write_bytes<W: Write>(mut writer: W, bytes: &[u8]) -> std::io::Result<()> {
+    write_varint(&mut writer, bytes.len() as u64)?;
+    writer.write_all(bytes)
+}
+
+fn write_metadata<W: Write>(mut writer: W, metadata: &FileMetadata) -> std::io::Result<()> {
+    let secs = metadata.modified.timestamp();
+    // Zigzag encoding, so that negative timestamps stay short
+    write_varint(&mut writer, ((secs << 1) ^ (secs >> 63)) as u64)?;
+    write_varint(&mut writer, metadata.modified.timestamp_subsec_nanos() as u64)?;
+    write_varint(&mut writer, metadata.mode as u64)
+}
+
+pub fn write_message<'a, M: Into<Message<'a>>, W: Write>(message: M, mut writer: W) -> std::io::Result<()> {
+    let message = message.into();
+    match message {
+        Message::Hello(version, capabilities) => {
+            writer.write_all(&[TAG_HELLO])?;
+            write_varint(&mut writer, version as u64)?;
+            write_bytes(&mut writer, capabilities)?;
+        }
+        Message::FileEntry(name, size, digest, metadata) => {
+            writer.write_all(&[TAG_FILE_ENTRY])?;
+            write_bytes(&mut writer, name)?;
+            write_varint(&mut writer, size as u64)?;
+            writer.write_all(&digest.0)?;
+            write_metadata(&mut writer, &metadata)?;
+        }
+        Message::SymlinkEntry(name, target) => {
+            writer.write_all(&[TAG_SYMLINK_ENTRY])?;
+            write_bytes(&mut writer, name)?;
+            write_bytes(&mut writer, target)?;
+        }
+        Message::DirectoryEntry(name, metadata) => {
+            writer.write_all(&[TAG_DIRECTORY_ENTRY])?;
+            write_bytes(&mut writer, name)?;
+            write_metadata(&mut writer, &metadata)?;
+        }
+        Message::HardLinkEntry(name, target) => {
+            writer.write_all(&[TAG_HARDLINK_ENTRY])?;
+            write_bytes(&mut writer, name)?;
+            write_bytes(&mut writer, target)?;
+        }
+        Message::EndFiles => {
+            writer.write_all(&[TAG_END_FILES])?;
+        }
+        Message::GetFile(name) => {
+            writer.write_all(&[TAG_GET_FILE])?;
+            write_bytes(&mut writer, name)?;
+        }
+        Message::FileStart(name) => {
+            writer.write_all(&[TAG_FILE_START])?;
+            write_bytes(&mut writer, name)?;
+        }
+        Message::FileBlock(digest, size) => {
+            writer.write_all(&[TAG_FILE_BLOCK])?;
+            writer.write_all(&digest.0)?;
+            write_varint(&mut writer, size as u64)?;
+        }
+        Message::FileEnd => {
+            writer.write_all(&[TAG_FILE_END])?;
+        }
+        Message::GetBlocks(digests) => {
+            writer.write_all(&[TAG_GET_BLOCKS])?;
+            write_varint(&mut writer, digests.len() as u64)?;
+            for digest in digests {
+                writer.write_all(&digest.0)?;
+            }
+        }
+        Message::BlockData(digest, data) => {
+            writer.write_all(&[TAG_BLOCK_DATA])?;
+            writer.write_all(&digest.0)?;
+            write_bytes(&mut writer, data)?;
+        }
+        Message::CompressedBlockData(digest, data) => {
+            writer.write_all(&[TAG_COMPRESSED_BLOCK_DATA])?;
+            writer.write_all(&digest.0)?;
+            write_bytes(&mut writer, data)?;
+        }
+        Message::Complete => {
+            writer.write_all(&[TAG_COMPLETE])?;
+        }
+        Message::Error(kind, path, message) => {
+            writer.write_all(&[TAG_ERROR])?;
+            write_bytes(&mut writer, kind)?;
+            write_bytes(&mut writer, path)?;
+            write_bytes(&mut writer, message)?;
+        }
+        Message::BlockSummary(summary) => {
+            writer.write_all(&[TAG_BLOCK_SUMMARY])?;
+            write_varint(&mut writer, summary.num_hashes() as u64)?;
+            write_bytes(&mut writer, summary.bits())?;
+        }
+        Message::Command(args) => {
+            writer.write_all(&[TAG_COMMAND])?;
+            write_varint(&mut writer, args.len() as u64)?;
+            for arg in args {
+                write_bytes(&mut writer, arg)?;
+            }
+        }
+        Message::SingleFile(name) => {
+            writer.write_all(&[TAG_SINGLE_FILE])?;
+            write_bytes(&mut writer, name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compress block data with deflate
+///
+/// Returns `None` if the data doesn't shrink, in which case it should be sent
+/// uncompressed.
+pub fn compress_block(data: &[u8], level: u32) -> Option<Vec<u8>> {
+    let mut encoder = flate2::write::DeflateEncoder::new(
+        Vec::new(),
+        flate2::Compression::new(level.min(9)),
+    );
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    if compressed.len() < data.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+/// Decompress block data, refusing blocks larger than `max_length` bytes
+pub fn decompress_block(data: &[u8], max_length: usize) -> Result<Vec<u8>, Error> {
+    let mut decoder = flate2::read::DeflateDecoder::new(data)
+        .take(max_length as u64 + 1);
+    let mut output = Vec::new();
+    decoder.read_to_end(&mut output)
+        .map_err(|_| Error("Invalid compressed block data"))?;
+    if output.len() > max_length {
+        return Err(Error("Invalid compressed block data"));
+    }
+    Ok(output)
+}
+
+pub struct Parser {
+    buffer: Vec<u8>,
+    pos: usize,
+    max_length: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::with_max_length(DEFAULT_MAX_MESSAGE_LENGTH)
+    }
+}
+
+use std::future::Future;
+
+impl Parser {
+    /// Create a parser refusing strings longer than `max_length` bytes
+    ///
+    /// This includes file names and block data, and prevents the other side
+    /// from making us allocate arbitrary amounts of memory.
+    pub fn with_max_length(max_length: usize) -> Parser {
+        Parser {
+            buffer: Vec::new(),
+            pos: 0,
+            max_length,
+        }
+    }
+
+    /// The maximum length of strings and block data
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    #[allow(dead_code)]
+    pub fn receive<'a, E, F>(&'a mut self, func: F) -> Result<Messages<'a>, E>
+    where
+        F: FnOnce(&mut Vec<u8>) -> Result<(), E>
+    {
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+
+        func(&mut self.buffer)?;
+        Ok(Messages {
+            buffer: &mut self.buffer,
+            pos: &mut self.pos,
+            max_length: self.max_length,
+        })
+    }
+
+    #[allow(clippy::manual_async_fn)]
+    pub fn read_async<'a, R: AsyncRead + Unpin>(
+        &'a mut self,
+        mut reader: R,
+    ) -> impl Future<Output=Result<(Messages<'a>, bool), std::io::Error>> {
+        async move {
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+
+            let read_len = reader.read_buf(&mut self.buffer).await?;
+            let end = read_len == 0;
+            Ok((Messages {
+                buffer: &mut self.buffer,
+                pos: &mut self.pos,
+                max_length: self.max_length,
+            }, end))
+        }
+    }
+
+    pub fn parse<'a>(&'a mut self, input: &[u8]) -> Messages<'a> {
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+        self.buffer.extend_from_slice(input);
+        Messages {
+            buffer: &mut self.buffer,
+            pos: &mut self.pos,
+            max_length: self.max_length,
+        }
+    }
+}
+
+/// Read a varint from an async reader, for `read_command()`
+#[allow(clippy::manual_async_fn)]
+fn read_varint_async<'a, R: AsyncRead + Unpin>(
+    reader: &'a mut R,
+    remaining: &'a mut usize,
+) -> impl Future<Output=Result<u64, crate::Error>> + 'a {
+    async move {
+        let mut value: u64 = 0;
+        for i in 0..10 {
+            if *remaining == 0 {
+                return Err(crate::Error::Sync("Command is too long".to_owned()));
+            }
+            *remaining -= 1;
+            let byte = reader.read_u8().await?;
+            if i == 9 && byte > 1 {
+                break;
+            }
+            value |= ((byte & 0x7F) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error("Invalid command").into())
+    }
+}
+
+/// Read a `COMMAND` message of at most `max_size` bytes, without reading past
+/// it
+///
+/// Unlike `Parser`, this reads each field as it arrives rather than parsing
+/// the message again every time more data comes in, and leaves what follows
+/// in the reader.
+#[allow(clippy::manual_async_fn)]
+pub fn read_command<'a, R: AsyncRead + Unpin>(
+    reader: &'a mut R,
+    max_size: usize,
+) -> impl Future<Output=Result<Vec<Vec<u8>>, crate::Error>> + 'a {
+    async move {
+        let mut remaining = max_size - 1;
+        if reader.read_u8().await? != TAG_COMMAND {
+            return Err(crate::Error::Sync("Expected a command".to_owned()));
+        }
+        let count = read_varint_async(reader, &mut remaining).await?;
+        // Each argument takes at least a byte
+        if count > remaining as u64 {
+            return Err(crate::Error::Sync("Command is too long".to_owned()));
+        }
+        let mut args = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = read_varint_async(reader, &mut remaining).await?;
+            if len > remaining as u64 {
+                return Err(crate::Error::Sync("Command is too long".to_owned()));
+            }
+            remaining -= len as usize;
+            let mut arg = vec![0; len as usize];
+            reader.read_exact(&mut arg).await?;
+            args.push(arg);
+        }
+        Ok(args)
+    }
+}
+
+pub struct Messages<'a> {
+    buffer: &'a mut Vec<u8>,
+    pos: &'a mut usize,
+    max_length: usize,
+}
+
+impl<'a> Messages<'a> {
+    /// Whether all the input has been parsed, with no partial message left
+    pub fn is_done(&self) -> bool {
+        *self.pos == self.buffer.len()
+    }
+}
+
+/// A position in the input buffer
+///
+/// The read methods return `Ok(None)` if the input is incomplete.
+struct View<'a> {
+    slice: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> View<'a> {
+    fn new(slice: &'a [u8]) -> View<'a> {
+        View {
+            slice,
+            pos: 0,
+        }
+    }
+
+    fn read_exact(&mut self, size: usize) -> Option<&'a [u8]> {
+        if self.slice.len() - self.pos >= size {
+            let value = &self.slice[self.pos..self.pos + size];
+            self.pos += size;
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn read_varint(&mut self, error: &'static str) -> Result<Option<u64>, Error> {
+        let mut value: u64 = 0;
+        for i in 0..10 {
+            let byte = match self.read_exact(1) {
+                Some(b) => b[0],
+                None => return Ok(None),
+            };
+            if i == 9 && byte > 1 {
+                return Err(Error(error));
+            }
+            value |= ((byte & 0x7F) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+        Err(Error(error))
+    }
+
+    fn read_usize(&mut self, error: &'static str) -> Result<Option<usize>, Error> {
+        match self.read_varint(error)? {
+            Some(v) => v.try_into().map(Some).map_err(|_| Error(error)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_bytes(
+        &mut self,
+        max_length: usize,
+        error: &'static str,
+    ) -> Result<Option<&'a [u8]>, Error> {
+        let len = match self.read_usize(error)? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        if len > max_length {
+            return Err(Error(error));
+        }
+        Ok(self.read_exact(len))
+    }
+
+    fn read_digest(&mut self) -> Option<HashDigest> {
+        self.read_exact(HASH_DIGEST_LEN)
+            .map(|d| HashDigest(d.try_into().unwrap()))
+    }
+
+    fn read_metadata(&mut self) -> Result<Option<FileMetadata>, Error> {
+        let secs = match self.read_varint("Invalid modification time")? {
+            Some(v) => ((v >> 1) as i64) ^ -((v & 1) as i64),
+            None => return Ok(None),
+        };
+        let nanos = match self.read_varint("Invalid modification time")? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let modified = nanos.try_into().ok()
+            .and_then(|nanos| chrono::Utc.timestamp_opt(secs, nanos).single())
+            .ok_or(Error("Invalid modification time"))?;
+        let mode = match self.read_varint("Invalid mode")? {
+            Some(v) => v.try_into().map_err(|_| Error("Invalid mode"))?,
+            None => return Ok(None),
+        };
+        Ok(Some(FileMetadata { modified, mode }))
+    }
+}
+
+impl<'a, 'b: 'a> StreamingIterator<'a> for Messages<'b> {
+    type Item = Result<Message<'a>, Error>;
+
+    fn next(&'a mut self) -> Option<Result<Message<'a>, Error>> {
+        let mut buffer = View::new(&self.buffer[*self.pos..]);
+        let max_length = self.max_length;
+
+        // Get the value from a read method, or return if the message is
+        // incomplete or invalid
+        macro_rules! read {
+            ($e:expr) => {
+                match $e {
+                    Err(e) => return Some(Err(e)),
+                    Ok(Some(v)) => v,
+                    Ok(None) => return None,
+                }
+            }
+        }
+        macro_rules! read_exact {
+            ($e:expr) => {
+                match $e {
+                    Some(v) => v,
+                    None => return None,
+                }
+            }
+        }
+
+        let tag = read_exact!(buffer.read_exact(1))[0];
+        let ret = match tag {
+            TAG_HELLO => {
+                let version = read!(buffer.read_varint("Invalid protocol version"));
+                let version = match version.try_into() {
+                    Ok(v) => v,
+                    Err(_) => return Some(Err(Error("Invalid protocol version"))),
+                };
+                let capabilities = read!(buffer.read_bytes(max_length, "Invalid capabilities"));
+                Message::Hello(version, capabilities)
+            }
+            TAG_FILE_ENTRY => {
+                let filename = read!(buffer.read_bytes(max_length, "Invalid filename"));
+                let size = read!(buffer.read_usize("Invalid file size"));
+                let digest = read_exact!(buffer.read_digest());
+                let metadata = read!(buffer.read_metadata());
+                Message::FileEntry(filename, size, digest, metadata)
+            }
+            TAG_SYMLINK_ENTRY => {
+                let filename = read!(buffer.read_bytes(max_length, "Invalid filename"));
+                let target = read!(buffer.read_bytes(max_length, "Invalid link target"));
+                Message::SymlinkEntry(filename, target)
+            }
+            TAG_DIRECTORY_ENTRY => {
+                let filename = read!(buffer.read_bytes(max_length, "Invalid filename"));
+                let metadata = read!(buffer.read_metadata());
+                Message::DirectoryEntry(filename, metadata)
+            }
+            TAG_HARDLINK_ENTRY => {
+                let filename = read!(buffer.read_bytes(max_length, "Invalid filename"));
+                let target = read!(buffer.read_bytes(max_length, "Invalid link target"));
+                Message::HardLinkEntry(filename, target)
+            }
+            TAG_END_FILES => Message::EndFiles,
+            TAG_GET_FILE => {
+                let filename = read!(buffer.read_bytes(max_length, "Invalid filename"));
+                Message::GetFile(filename)
+            }
+            TAG_FILE_START => {
+                let filename = read!(buffer.read_bytes(max_length, "Invalid filename"));
+                Message::FileStart(filename)
+            }
+            TAG_FILE_BLOCK => {
+                let digest = read_exact!(buffer.read_digest());
+                let size = read!(buffer.read_usize("Invalid block size"));
+                Message::FileBlock(digest, size)
+            }
+            TAG_FILE_END => Message::FileEnd,
+            TAG_GET_BLOCKS => {
+                let count = read!(buffer.read_usize("Invalid block count"));
+                if count > max_length / HASH_DIGEST_LEN {
+                    return Some(Err(Error("Invalid block count")));
+                }
+                let mut digests = Vec::with_capacity(count);
+                for _ in 0..count {
+                    digests.push(read_exact!(buffer.read_digest()));
+                }
+                Message::GetBlocks(digests)
+            }
+            TAG_BLOCK_DATA => {
+                let digest = read_exact!(buffer.read_digest());
+                let data = read!(buffer.read_bytes(max_length, "Invalid block data"));
+                Message::BlockData(digest, data)
+            }
+            TAG_COMPRESSED_BLOCK_DATA => {
+                let digest = read_exact!(buffer.read_digest());
+                let data = read!(buffer.read_bytes(max_length, "Invalid block data"));
+                Message::CompressedBlockData(digest, data)
+            }
+            TAG_COMPLETE => Message::Complete,
+            TAG_ERROR => {
+                let kind = read!(buffer.read_bytes(max_length, "Invalid error kind"));
+                let path = read!(buffer.read_bytes(max_length, "Invalid error path"));
+                let message = read!(buffer.read_bytes(max_length, "Invalid error message"));
+                Message::Error(kind, path, message)
+            }
+            TAG_BLOCK_SUMMARY => {
+                let num_hashes = read!(buffer.read_varint("Invalid block summary"));
+                let bits = read!(buffer.read_bytes(max_length, "Invalid block summary"));
+                let summary = num_hashes.try_into().ok()
+                    .and_then(|n| BlockSummary::from_parts(n, bits.to_owned()));
+                match summary {
+                    Some(s) => Message::BlockSummary(s),
+                    None => return Some(Err(Error("Invalid block summary"))),
+                }
+            }
+            TAG_COMMAND => {
+                let count = read!(buffer.read_usize("Invalid command"));
+                if count > max_length {
+                    return Some(Err(Error("Invalid command")));
+                }
+                let mut args = Vec::with_capacity(count);
+                for _ in 0..count {
+                    args.push(read!(buffer.read_bytes(max_length, "Invalid command")));
+                }
+                Message::Command(args)
+            }
+            TAG_SINGLE_FILE => {
+                let filename = read!(buffer.read_bytes(max_length, "Invalid filename"));
+                Message::SingleFile(filename)
+            }
+            _ => {
+                warn!("Unknown message type: {}", tag);
+                return Some(Err(Error("Unknown message type")));
+            }
+        };
+
+        *self.pos += buffer.pos;
+        Some(Ok(ret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::{
+        OwnedMessage, Parser, Message, Messages, compress_block,
+        decompress_block, read_command, write_message,
+    };
+    use crate::HashDigest;
+    use crate::streaming_iterator::StreamingIterator;
+    use crate::sync::{BlockSummary, FileMetadata};
+
+    fn metadata() -> FileMetadata {
+        FileMetadata {
+            modified: chrono::Utc.timestamp(1600000000, 5000),
+            mode: 0o644,
+        }
+    }
+
+    fn compare<'a>(mut iterator: Messages<'a>, expected: &[Message<'static>]) {
+        let mut expected = expected.iter();
+        loop {
+            match (iterator.next(), expected.next()) {
+                (None, None) => break,
+                (Some(msg), Some(e)) => {
+                    let msg = msg.unwrap();
+                    assert_eq!(&msg, e);
+                }
+                (Some(msg), None) => {
+                    let msg = msg.unwrap();
+                    panic!("More messages than expected: {:?}", msg);
+                }
+                (None, Some(e)) => panic!("Fewer messages than expected: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        // Messages split at arbitrary points
+        let inputs: &[&[u8]] = &[
+            b"\x01\x02\x09some caps\x02",
+            b"\x08filename\x0c12345678901234567890",
+            b"\x80\xc0\xf0\xf5\x0b",
+            b"\x88\x27\xa4\x03",
+            b"\x0d",
+        ];
+        let expected: &[&[Message<'static>]] = &[
+            &[Message::Hello(2, b"some caps")],
+            &[],
+            &[],
+            &[Message::FileEntry(
+                b"filename", 12, HashDigest(*b"12345678901234567890"),
+                metadata(),
+            )],
+            &[Message::Complete],
+        ];
+        let mut parser: Parser = Default::default();
+        for (bytes, expected_messages) in inputs.iter().zip(expected) {
+            compare(
+                parser.receive::<(), _>(|buf| { buf.extend_from_slice(bytes); Ok(()) }).unwrap(),
+                expected_messages,
+            );
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let mut output = Vec::new();
+        write_message(Message::Hello(2, b""), &mut output).unwrap();
+        write_message(
+            Message::FileEntry(
+                b"filename", 12, HashDigest(*b"12345678901234567890"),
+                metadata(),
+            ),
+            &mut output,
+        ).unwrap();
+        write_message(
+            Message::DirectoryEntry(b"dir", metadata()),
+            &mut output,
+        ).unwrap();
+        write_message(
+            &OwnedMessage::EndFiles,
+            &mut output,
+        ).unwrap();
+        write_message(
+            Message::Error(b"io", b"", b"Oops"),
+            &mut output,
+        ).unwrap();
+        // FIXME: Casts to &[u8] required for Rust < 1.47
+        assert_eq!(
+            &output as &[u8],
+            b"\x01\x02\x00\
+              \x02\x08filename\x0c12345678901234567890\
+              \x80\xc0\xf0\xf5\x0b\x88\x27\xa4\x03\
+              \x04\x03dir\x80\xc0\xf0\xf5\x0b\x88\x27\xa4\x03\
+              \x06\
+              \x0f\x02io\x00\x04Oops" as &[u8],
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        // Long names, names with newlines
+        static LONG_NAME: [u8; 1000] = [b'a'; 1000];
+        fn messages() -> [Message<'static>; 4] {
+            [
+                Message::GetFile(&LONG_NAME),
+                Message::HardLinkEntry(b"new\nline", b"\n"),
+                Message::FileStart(b""),
+                Message::SingleFile(b"disk.img"),
+            ]
+        }
+        let mut output = Vec::new();
+        for message in messages().iter().cloned() {
+            write_message(message, &mut output).unwrap();
+        }
+        let mut parser: Parser = Default::default();
+        compare(parser.parse(&output), &messages());
+
+        // Names over the limit are refused
+        let mut parser = Parser::with_max_length(999);
+        match parser.parse(&output).next() {
+            Some(Err(e)) => assert_eq!(e.0, "Invalid filename"),
+            _ => panic!("Long name was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let data = b"hello hello hello hello hello hello hello hello".repeat(20);
+        let compressed = compress_block(&data, 6).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress_block(&compressed, data.len()).unwrap(), data);
+
+        // Blocks that would decompress over the limit are refused
+        assert!(decompress_block(&compressed, data.len() - 1).is_err());
+        assert!(decompress_block(b"garbage", 100).is_err());
+
+        // Blocks that don't shrink are not compressed
+        assert_eq!(compress_block(b"abc", 6), None);
+    }
+
+    #[test]
+    fn test_get_blocks() {
+        let hashes = vec![
+            HashDigest(*b"12345678901234567890"),
+            HashDigest(*b"abcdefghijabcdefghij"),
+        ];
+        let mut output = Vec::new();
+        write_message(Message::GetBlocks(hashes.clone()), &mut output).unwrap();
+        assert_eq!(
+            &output as &[u8],
+            b"\x0b\x0212345678901234567890abcdefghijabcdefghij" as &[u8],
+        );
+        let mut parser: Parser = Default::default();
+        compare(parser.parse(&output), &[Message::GetBlocks(hashes)]);
+
+        // Batches over the limit are refused
+        let mut parser = Parser::with_max_length(39);
+        match parser.parse(&output).next() {
+            Some(Err(e)) => assert_eq!(e.0, "Invalid block count"),
+            _ => panic!("Large batch was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_block_summary() {
+        let mut summary = BlockSummary::new(10);
+        summary.insert(&HashDigest(*b"12345678901234567890"));
+        let mut output = Vec::new();
+        write_message(Message::BlockSummary(summary.clone()), &mut output).unwrap();
+        assert_eq!(output[0], 16);
+        let mut parser: Parser = Default::default();
+        compare(parser.parse(&output), &[Message::BlockSummary(summary)]);
+
+        // Invalid number of hash functions
+        let mut parser: Parser = Default::default();
+        match parser.parse(b"\x10\x00\x01\x00").next() {
+            Some(Err(e)) => assert_eq!(e.0, "Invalid block summary"),
+            _ => panic!("Invalid summary was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_command() {
+        let command = Message::Command(vec![b"remote-recv", b"--dry-run", b"mod/path"]);
+        let mut output = Vec::new();
+        write_message(command.clone(), &mut output).unwrap();
+        assert_eq!(&output[..3], b"\x11\x03\x0b");
+        let mut parser: Parser = Default::default();
+        compare(parser.parse(&output), &[command]);
+
+        // Read from a stream, leaving the rest
+        output.extend_from_slice(b"next");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut input = &output[..];
+            let args = read_command(&mut input, 100).await.unwrap();
+            assert_eq!(args, vec![b"remote-recv".to_vec(), b"--dry-run".to_vec(), b"mod/path".to_vec()]);
+            assert_eq!(input, b"next");
+
+            let mut input = &output[..];
+            match read_command(&mut input, 20).await {
+                Err(crate::Error::Sync(e)) => assert_eq!(e, "Command is too long"),
+                r => panic!("Unexpected result: {:?}", r),
+            }
+            let mut input = &b"\x06"[..];
+            match read_command(&mut input, 20).await {
+                Err(crate::Error::Sync(e)) => assert_eq!(e, "Expected a command"),
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        });
+    }
+}
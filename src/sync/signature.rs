@@ -0,0 +1,220 @@
+//! Signature of a directory, describing what a destination already has.
+//!
+//! A signature starts with `SIGNATURE_MAGIC`, the format version and a mode
+//! byte. A full signature then has the same content as a manifest (see the
+//! `manifest` module): the entries, and the blocks of each file. A
+//! blocks-only signature has no paths, only the digests of the blocks that
+//! are present, as raw bytes.
+
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Filter, HASH_DIGEST_LEN, HashDigest, Index, IndexOptions, bytes_to_path};
+use crate::sync::fs::open_index;
+use crate::sync::manifest::{read_header, read_listing, write_listing};
+use crate::sync::utils::write_file_atomic;
+
+/// Magic bytes at the start of a signature
+pub const SIGNATURE_MAGIC: &[u8] = b"syncfast signature\n";
+
+/// Version of the signature format, written after the magic bytes
+pub const SIGNATURE_VERSION: u8 = 1;
+
+const MODE_FULL: u8 = 0;
+const MODE_BLOCKS_ONLY: u8 = 1;
+
+/// Write the signature of an indexed directory
+///
+/// If `blocks_only` is set, only the digests of the blocks are written,
+/// otherwise it also has the files, excluding the ones that don't match
+/// `filter`.
+pub fn write_signature<W: Write>(
+    index: &Index,
+    filter: &Filter,
+    blocks_only: bool,
+    mut writer: W,
+) -> Result<(), Error> {
+    writer.write_all(SIGNATURE_MAGIC)?;
+    writer.write_all(&[SIGNATURE_VERSION])?;
+    if blocks_only {
+        writer.write_all(&[MODE_BLOCKS_ONLY])?;
+        for hash in index.list_present_blocks()? {
+            writer.write_all(&hash.0)?;
+        }
+    } else {
+        writer.write_all(&[MODE_FULL])?;
+        write_listing(index, filter, writer)?;
+    }
+    Ok(())
+}
+
+/// Index a directory and write its signature to a file
+///
+/// See `write_signature()`.
+pub fn signature(
+    root_dir: &Path,
+    options: &IndexOptions,
+    blocks_only: bool,
+    output: &Path,
+) -> Result<(), Error> {
+    let (index, _, _) = open_index(root_dir, false, options, false)?;
+    write_file_atomic(output, |writer| {
+        write_signature(&index, &options.filter, blocks_only, writer)
+    })?;
+    info!("Wrote signature {:?}", output);
+    Ok(())
+}
+
+/// The state of a directory, read from a signature or an index
+///
+/// This is a read-only view, giving the same information as `Index` about
+/// the blocks, to compute what needs to be sent to that directory.
+pub struct Signature {
+    /// The blocks of each file, unless only blocks were exported
+    files: Option<HashMap<PathBuf, Vec<(HashDigest, usize)>>>,
+    blocks: HashSet<HashDigest>,
+}
+
+impl Signature {
+    /// Read a signature
+    pub fn parse(data: &[u8]) -> Result<Signature, Error> {
+        let data = read_header(data, "signature", SIGNATURE_MAGIC, SIGNATURE_VERSION)?;
+        match data.first() {
+            Some(&MODE_FULL) => {
+                let listing = read_listing(&data[1..], "signature", None)?;
+                let mut files = HashMap::with_capacity(listing.files.len());
+                let mut blocks = HashSet::new();
+                for (name, file_blocks) in listing.files {
+                    blocks.extend(file_blocks.iter().map(|(hash, _)| hash.clone()));
+                    files.insert(bytes_to_path(name)?, file_blocks);
+                }
+                Ok(Signature { files: Some(files), blocks })
+            }
+            Some(&MODE_BLOCKS_ONLY) => {
+                let data = &data[1..];
+                if data.len() % HASH_DIGEST_LEN != 0 {
+                    return Err(Error::Sync("Invalid signature: truncated".to_owned()));
+                }
+                let mut blocks = HashSet::with_capacity(data.len() / HASH_DIGEST_LEN);
+                for digest in data.chunks(HASH_DIGEST_LEN) {
+                    let mut hash = [0; HASH_DIGEST_LEN];
+                    hash.copy_from_slice(digest);
+                    blocks.insert(HashDigest(hash));
+                }
+                Ok(Signature { files: None, blocks })
+            }
+            Some(_) => Err(Error::Sync("Invalid signature: unknown mode".to_owned())),
+            None => Err(Error::Sync("Invalid signature: truncated".to_owned())),
+        }
+    }
+
+    /// Read a signature file
+    pub fn open(path: &Path) -> Result<Signature, Error> {
+        Signature::parse(&std::fs::read(path)?)
+    }
+
+    /// Whether a file is a signature, rather than an index
+    pub fn is_signature(path: &Path) -> Result<bool, Error> {
+        let mut magic = Vec::with_capacity(SIGNATURE_MAGIC.len());
+        File::open(path)?
+            .take(SIGNATURE_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        Ok(magic == SIGNATURE_MAGIC)
+    }
+
+    /// The blocks that are present in an index, without the files
+    pub fn from_index(index: &Index) -> Result<Signature, Error> {
+        Ok(Signature {
+            files: None,
+            blocks: index.list_present_blocks()?.into_iter().collect(),
+        })
+    }
+
+    /// Whether the signature only has blocks, and no files
+    pub fn is_blocks_only(&self) -> bool {
+        self.files.is_none()
+    }
+
+    /// Whether a block is present
+    pub fn has_block(&self, hash: &HashDigest) -> bool {
+        self.blocks.contains(hash)
+    }
+
+    /// List the present blocks, like `Index::list_present_blocks()`
+    pub fn list_present_blocks(&self) -> Vec<HashDigest> {
+        self.blocks.iter().cloned().collect()
+    }
+
+    /// List the files, empty if the signature only has blocks
+    pub fn list_files(&self) -> Vec<&Path> {
+        match self.files {
+            Some(ref files) => files.keys().map(|p| p.as_path()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Get the blocks of a file, with their sizes
+    pub fn list_file_blocks(&self, path: &Path) -> Option<&[(HashDigest, usize)]> {
+        self.files.as_ref()
+            .and_then(|files| files.get(path))
+            .map(|blocks| blocks.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use crate::HashDigest;
+    use crate::sync::fs::open_index;
+    use super::{SIGNATURE_MAGIC, SIGNATURE_VERSION, Signature, write_signature};
+
+    #[test]
+    fn test_signature() {
+        let root = TempDir::new().unwrap();
+        fs::write(root.path().join("a"), b"first file").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir/b"), b"second file").unwrap();
+        let (index, _, _) = open_index(root.path(), false, &Default::default(), false).unwrap();
+        let hash_a = HashDigest::of(b"first file");
+        let hash_b = HashDigest::of(b"second file");
+
+        let mut data = Vec::new();
+        write_signature(&index, &Default::default(), false, &mut data).unwrap();
+        let signature = Signature::parse(&data).unwrap();
+        assert!(!signature.is_blocks_only());
+        assert!(signature.has_block(&hash_a));
+        assert!(signature.has_block(&hash_b));
+        assert!(!signature.has_block(&HashDigest::of(b"other")));
+        let mut files = signature.list_files();
+        files.sort();
+        assert_eq!(files, vec![Path::new("a"), Path::new("dir/b")]);
+        assert_eq!(
+            signature.list_file_blocks(Path::new("dir/b")),
+            Some(&[(hash_b.clone(), 11usize)][..]),
+        );
+
+        let mut data = Vec::new();
+        write_signature(&index, &Default::default(), true, &mut data).unwrap();
+        assert_eq!(data.len(), SIGNATURE_MAGIC.len() + 2 + 2 * 20);
+        let signature = Signature::parse(&data).unwrap();
+        assert!(signature.is_blocks_only());
+        assert!(signature.has_block(&hash_a));
+        assert!(signature.has_block(&hash_b));
+        assert!(signature.list_files().is_empty());
+        assert_eq!(signature.list_file_blocks(Path::new("a")), None);
+
+        match Signature::parse(&data[..data.len() - 1]) {
+            Err(e) => assert_eq!(e.to_string(), "Invalid signature: truncated"),
+            Ok(_) => panic!("Truncated signature was accepted"),
+        }
+        let mut data = SIGNATURE_MAGIC.to_owned();
+        data.extend_from_slice(&[SIGNATURE_VERSION, 7]);
+        assert!(Signature::parse(&data).is_err());
+    }
+}
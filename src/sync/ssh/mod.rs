@@ -1,65 +1,236 @@
-mod proto;
-
+use futures::ready;
+use futures::sink::Sink;
 use futures::stream::StreamExt;
 use log::{debug, info};
+use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::convert::{TryFrom, TryInto};
 use std::fmt::Debug;
 use std::future::Future;
 use std::pin::Pin;
 use std::process::Stdio;
+use std::rc::Rc;
+use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, stdin, stdout};
 use tokio::process::{Child, Command};
 
-use crate::Error;
+use crate::{Error, Filter, RemoteError};
 use crate::streaming_iterator::StreamingIterator;
-use crate::sync::{Destination, Source};
+use crate::SymlinkPolicy;
+use crate::sync::{
+    DEFAULT_COMPRESS_LEVEL, DEFAULT_MAX_MESSAGE_LENGTH, DeleteMode, Destination,
+    DestinationOptions, Source, SourceOptions,
+};
 use crate::sync::locations::SshLocation;
-use crate::sync::ssh::proto::{OwnedMessage, Parser, write_message};
+use crate::sync::proto;
+use crate::sync::proto::{
+    Message, OwnedMessage, PROTOCOL_VERSION, Parser, compress_block,
+    decompress_block, write_message,
+};
 
+/// Quote an argument for the remote shell
+///
+/// Single quotes are used, so that nothing in it gets expanded (`$`,
+/// backticks, `!`...); a single quote is written as `'\''`.
 fn shell_escape(input: &str) -> String {
     let mut result = String::new();
-    result.push('"');
+    result.push('\'');
     for c in input.chars() {
-        if c == '\\' || c == '"' {
-            result.push('\\');
+        if c == '\'' {
+            result.push_str("'\\''");
+        } else {
+            result.push(c);
         }
-        result.push(c);
     }
-    result.push('"');
+    result.push('\'');
     result
 }
 
+/// Build the command-line arguments passing filter rules
+fn filter_args(filter: &Filter, args: &mut Vec<String>) {
+    for rule in filter.rules() {
+        args.push("--filter".to_owned());
+        args.push(rule);
+    }
+}
+
+fn max_message_length_arg(max_message_length: usize, args: &mut Vec<String>) {
+    if max_message_length != DEFAULT_MAX_MESSAGE_LENGTH {
+        args.push(format!("--max-message-length={}", max_message_length));
+    }
+}
+
+/// Read the value of a `--max-message-length=` argument
+fn parse_max_message_length(arg: &str) -> Result<usize, Error> {
+    arg["--max-message-length=".len()..]
+        .parse()
+        .map_err(|_| invalid_arg(arg))
+}
+
+/// Build the command-line arguments passing source options to `remote-send`
+///
+/// They are not escaped for the shell.
+pub(crate) fn source_args(options: &SourceOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    match options.symlinks {
+        SymlinkPolicy::Preserve => {}
+        SymlinkPolicy::Copy => args.push("--copy-links".to_owned()),
+        SymlinkPolicy::Skip => args.push("--no-links".to_owned()),
+        SymlinkPolicy::SkipUnsafe => args.push("--safe-links".to_owned()),
+    }
+    if options.compress_level != DEFAULT_COMPRESS_LEVEL {
+        args.push(format!("--compress-level={}", options.compress_level));
+    }
+    max_message_length_arg(options.max_message_length, &mut args);
+    filter_args(&options.filter, &mut args);
+    args
+}
+
+/// Build the command-line arguments passing destination options to
+/// `remote-recv`
+///
+/// They are not escaped for the shell.
+pub(crate) fn destination_args(options: &DestinationOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    match options.delete {
+        DeleteMode::Never => {}
+        DeleteMode::Before => args.push("--delete-before".to_owned()),
+        DeleteMode::During => args.push("--delete-during".to_owned()),
+        DeleteMode::After => args.push("--delete-after".to_owned()),
+    }
+    if options.dry_run {
+        args.push("--dry-run".to_owned());
+    }
+    if !options.block_summary {
+        args.push("--no-block-summary".to_owned());
+    }
+    max_message_length_arg(options.max_message_length, &mut args);
+    filter_args(&options.filter, &mut args);
+    args
+}
+
+fn invalid_arg(arg: &str) -> Error {
+    Error::Sync(format!("Invalid argument {:?}", arg))
+}
+
+/// Read the options built by `source_args()`
+///
+/// This is used by the daemon, which gets the arguments from the client
+/// instead of a command line. Only the forms that `source_args()` writes are
+/// accepted.
+pub(crate) fn parse_source_args(args: &[String]) -> Result<SourceOptions, Error> {
+    let mut options = SourceOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--copy-links" => options.symlinks = SymlinkPolicy::Copy,
+            "--no-links" => options.symlinks = SymlinkPolicy::Skip,
+            "--safe-links" => options.symlinks = SymlinkPolicy::SkipUnsafe,
+            "--filter" => {
+                let rule = args.next().ok_or_else(|| invalid_arg(arg))?;
+                options.filter.add_rule(rule)?;
+            }
+            _ if arg.starts_with("--compress-level=") => {
+                options.compress_level = arg["--compress-level=".len()..]
+                    .parse()
+                    .map_err(|_| invalid_arg(arg))?;
+            }
+            _ if arg.starts_with("--max-message-length=") => {
+                options.max_message_length = parse_max_message_length(arg)?;
+            }
+            _ => return Err(invalid_arg(arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Read the options built by `destination_args()`, see
+/// `parse_source_args()`
+pub(crate) fn parse_destination_args(args: &[String]) -> Result<DestinationOptions, Error> {
+    let mut options = DestinationOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--delete-before" => options.delete = DeleteMode::Before,
+            "--delete-during" => options.delete = DeleteMode::During,
+            "--delete-after" => options.delete = DeleteMode::After,
+            "--dry-run" => options.dry_run = true,
+            "--no-block-summary" => options.block_summary = false,
+            "--filter" => {
+                let rule = args.next().ok_or_else(|| invalid_arg(arg))?;
+                options.filter.add_rule(rule)?;
+            }
+            _ if arg.starts_with("--max-message-length=") => {
+                options.max_message_length = parse_max_message_length(arg)?;
+            }
+            _ => return Err(invalid_arg(arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Optional features of the protocol supported by this version, announced in
+/// the `HELLO` message
+const CAPABILITIES: &[&str] = &["deflate"];
+
+/// Capabilities of the other side, set once we got its `HELLO`
+///
+/// This is shared between the SshStream receiving the `HELLO` and the SshSink
+/// which can then use those features.
+pub(crate) type PeerCapabilities = Rc<RefCell<Option<Vec<String>>>>;
+
+/// Build the error from an `ERROR` message sent by the other side
+fn remote_error(kind: Vec<u8>, path: Vec<u8>, message: Vec<u8>) -> Error {
+    Error::Remote(RemoteError {
+        kind: String::from_utf8_lossy(&kind).into_owned(),
+        path: if path.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&path).into_owned())
+        },
+        message: String::from_utf8_lossy(&message).into_owned(),
+    })
+}
+
 // First we define the SshStream and SshSink structs, which can read and write
-// messages to/from a process.
+// messages to/from a process. Each side first sends a `HELLO` message, with its
+// version and capabilities.
 // Then we implement SshSource and SshDestination, which run `remote-send` and
 // `remote-recv` and use SshStream and SshSink to do all the messaging.
 
-struct SshStream<R: AsyncRead + Unpin> {
+pub(crate) struct SshStream<R: AsyncRead + Unpin> {
     stdout: R,
     parser: Parser,
     messages: VecDeque<OwnedMessage>,
+    peer_capabilities: PeerCapabilities,
 }
 
 impl<R: AsyncRead + Unpin> SshStream<R> {
-    fn new(stdout: R) -> SshStream<R> {
+    /// Read messages, refusing strings longer than `max_length` bytes
+    pub(crate) fn new(
+        stdout: R,
+        peer_capabilities: PeerCapabilities,
+        max_length: usize,
+    ) -> SshStream<R> {
         SshStream {
             stdout,
-            parser: Default::default(),
+            parser: Parser::with_max_length(max_length),
             messages: VecDeque::new(),
+            peer_capabilities,
         }
     }
 
-    fn project<'b>(self: &'b mut Pin<Box<SshStream<R>>>) -> (&'b mut R, &'b mut Parser, &'b mut VecDeque<OwnedMessage>) where R: 'b {
+    fn project<'b>(self: &'b mut Pin<Box<SshStream<R>>>) -> (&'b mut R, &'b mut Parser, &'b mut VecDeque<OwnedMessage>, &'b PeerCapabilities) where R: 'b {
         unsafe {
             let s = self.as_mut().get_unchecked_mut();
-            (&mut s.stdout, &mut s.parser, &mut s.messages)
+            (&mut s.stdout, &mut s.parser, &mut s.messages, &s.peer_capabilities)
         }
     }
 
-    fn stream<T: TryFrom<OwnedMessage, Error=()> + Debug>(mut arg: Pin<Box<SshStream<R>>>) -> impl Future<Output=Option<(Result<T, Error>, Pin<Box<SshStream< R>>>)>> {
+    #[allow(clippy::manual_async_fn, clippy::type_complexity)]
+    pub(crate) fn stream<T: TryFrom<OwnedMessage, Error=()> + Debug>(mut arg: Pin<Box<SshStream<R>>>) -> impl Future<Output=Option<(Result<T, Error>, Pin<Box<SshStream< R>>>)>> {
         async move {
-            let (mut stream, parser, messages) = arg.project();
+            let (mut stream, parser, messages, peer_capabilities) = arg.project();
 
             macro_rules! err {
                 ($e:expr) => {
@@ -77,7 +248,7 @@ impl<R: AsyncRead + Unpin> SshStream<R> {
             }
 
             let mut end = false;
-            while messages.is_empty() && !end {
+            while (messages.is_empty() || peer_capabilities.borrow().is_none()) && !end {
                 // FIXME: Store the iterator instead of a vector of values,
                 // however this makes it self-referential...
                 let (mut iterator, end_) = try_!(parser.read_async(&mut stream).await);
@@ -85,13 +256,60 @@ impl<R: AsyncRead + Unpin> SshStream<R> {
                 loop {
                     match iterator.next() {
                         Some(Ok(msg)) => messages.push_back(msg.into()),
+                        // Garbage instead of HELLO, probably an older
+                        // syncfast using a different framing
+                        Some(Err(_)) if peer_capabilities.borrow().is_none() && messages.is_empty() => {
+                            return err!(no_hello());
+                        }
                         Some(Err(e)) => return err!(e),
                         None => break,
                     }
                 }
+                // The first message has to be the other side's HELLO
+                if peer_capabilities.borrow().is_none() {
+                    match messages.pop_front() {
+                        Some(OwnedMessage::Hello(version, capabilities)) => {
+                            if version != PROTOCOL_VERSION {
+                                return err!(Error::Sync(format!(
+                                    "Remote syncfast uses protocol version {}, \
+                                     we use version {}",
+                                    version, PROTOCOL_VERSION,
+                                )));
+                            }
+                            let capabilities: Vec<String> = String::from_utf8_lossy(&capabilities)
+                                .split_whitespace()
+                                .map(|s| s.to_owned())
+                                .collect();
+                            debug!("ssh: remote capabilities: {:?}", capabilities);
+                            *peer_capabilities.borrow_mut() = Some(capabilities);
+                        }
+                        // The remote can fail before even sending HELLO
+                        Some(OwnedMessage::Error(kind, path, message)) => {
+                            return err!(remote_error(kind, path, message));
+                        }
+                        Some(_) => return err!(no_hello()),
+                        None => {}
+                    }
+                }
+            }
+            if peer_capabilities.borrow().is_none() {
+                return err!(Error::Sync(
+                    "Connection closed before HELLO, is syncfast installed \
+                     and up to date on the remote?".to_owned(),
+                ));
             }
             match messages.pop_front() {
                 Some(msg) => {
+                    let msg = match msg {
+                        OwnedMessage::CompressedBlockData(hash, data) => {
+                            let data = try_!(decompress_block(&data, parser.max_length()));
+                            OwnedMessage::BlockData(hash, data)
+                        }
+                        OwnedMessage::Error(kind, path, message) => {
+                            return err!(remote_error(kind, path, message));
+                        }
+                        msg => msg,
+                    };
                     let event = match msg.try_into() {
                         Ok(e) => e,
                         Err(()) => return err!(Error::Protocol(Box::new(
@@ -101,48 +319,172 @@ impl<R: AsyncRead + Unpin> SshStream<R> {
                     debug!("ssh: recv {:?}", event);
                     Some((Ok(event), arg))
                 }
-                None => return None,
+                None => None,
             }
         }
     }
 }
 
-struct SshSink<W: AsyncWrite + Unpin> {
+/// The error when the first message from the remote is not `HELLO`
+fn no_hello() -> Error {
+    Error::Sync(
+        "Remote didn't send HELLO, it might be an older version of syncfast"
+            .to_owned(),
+    )
+}
+
+/// Statistics about the compression of the block data we sent
+#[derive(Default)]
+struct CompressionStats {
+    blocks: usize,
+    compressed_blocks: usize,
+    raw_bytes: usize,
+    sent_bytes: usize,
+}
+
+/// Size at which the buffered messages get written out, even if more are
+/// coming
+///
+/// Messages are otherwise only written when the sink is flushed, which happens
+/// when there is nothing else to send for now.
+const WRITE_BUFFER_SIZE: usize = 64 << 10; // 64 KiB
+
+pub(crate) struct SshSink<W: AsyncWrite + Unpin> {
     stdin: W,
+    /// Encoded messages waiting to be written
     buffer: Vec<u8>,
+    /// How much of the buffer has already been written
+    written: usize,
+    hello_sent: bool,
+    peer_capabilities: PeerCapabilities,
+    /// Compression level for block data, 0 to disable
+    compress_level: u32,
+    stats: CompressionStats,
 }
 
 impl<W: AsyncWrite + Unpin> SshSink<W> {
-    fn new(stdin: W) -> SshSink< W> {
+    pub(crate) fn new(
+        stdin: W,
+        peer_capabilities: PeerCapabilities,
+        compress_level: u32,
+    ) -> SshSink< W> {
         SshSink {
             stdin,
             buffer: Vec::new(),
+            written: 0,
+            hello_sent: false,
+            peer_capabilities,
+            compress_level,
+            stats: Default::default(),
         }
     }
 
-    fn project<'a>(self: &'a mut Pin<Box<SshSink< W>>>) -> (&'a mut W, &'a mut Vec<u8>) {
-        unsafe {
-            let s = self.as_mut().get_unchecked_mut();
-            (&mut s.stdin, &mut s.buffer)
+    /// Encode a message into the buffer
+    fn encode(&mut self, mut message: OwnedMessage) -> Result<(), Error> {
+        if !self.hello_sent {
+            let capabilities = CAPABILITIES.join(" ");
+            debug!("ssh: send Hello({}, {:?})", PROTOCOL_VERSION, capabilities);
+            write_message(
+                Message::Hello(PROTOCOL_VERSION, capabilities.as_bytes()),
+                &mut self.buffer,
+            )?;
+            self.hello_sent = true;
+        }
+        if let OwnedMessage::BlockData(ref hash, ref data) = message {
+            // Compress the data if the other side supports it, and if it
+            // actually shrinks
+            let can_compress = self.compress_level > 0 && match *self.peer_capabilities.borrow() {
+                Some(ref c) => c.iter().any(|c| c == "deflate"),
+                None => false,
+            };
+            let compressed = if can_compress {
+                compress_block(data, self.compress_level)
+            } else {
+                None
+            };
+            let stats = &mut self.stats;
+            stats.blocks += 1;
+            stats.raw_bytes += data.len();
+            match compressed {
+                Some(compressed) => {
+                    stats.compressed_blocks += 1;
+                    stats.sent_bytes += compressed.len();
+                    message = OwnedMessage::CompressedBlockData(hash.clone(), compressed);
+                }
+                None => stats.sent_bytes += data.len(),
+            }
         }
+        write_message(&message, &mut self.buffer)?;
+        Ok(())
     }
 
-    fn sink<T: Into<OwnedMessage> + Debug>(mut arg: Pin<Box<SshSink<W>>>, event: T) -> impl Future<Output=Result<Pin<Box<SshSink<W>>>, Error>> {
-        async move {
-            let (sink, mut buffer) = arg.project();
+    /// Write out the buffered messages
+    fn poll_write_buffer(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        while self.written < self.buffer.len() {
+            let written = ready!(Pin::new(&mut self.stdin).poll_write(
+                cx,
+                &self.buffer[self.written..],
+            ))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "Connection closed",
+                ).into()));
+            }
+            self.written += written;
+        }
+        self.buffer.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
 
-            debug!("ssh: send {:?}", event);
-            write_message(&event.into(), &mut buffer)?;
-            //eprintln!("send \"{}\"", String::from_utf8_lossy(&buffer));
-            sink.write_all(buffer).await?;
-            sink.flush().await?;
-            buffer.clear();
-            Ok(arg)
+impl<W: AsyncWrite + Unpin, T: Into<OwnedMessage> + Debug> Sink<T> for SshSink<W> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if this.buffer.len() >= WRITE_BUFFER_SIZE {
+            this.poll_write_buffer(cx)
+        } else {
+            Poll::Ready(Ok(()))
         }
     }
+
+    fn start_send(self: Pin<&mut Self>, event: T) -> Result<(), Error> {
+        debug!("ssh: send {:?}", event);
+        self.get_mut().encode(event.into())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffer(cx))?;
+        Pin::new(&mut this.stdin).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        <Self as Sink<T>>::poll_flush(self, cx)
+    }
 }
 
-pub fn ssh_source(loc: &SshLocation) -> Result<Source, Error> {
+impl<W: AsyncWrite + Unpin> Drop for SshSink<W> {
+    fn drop(&mut self) {
+        let stats = &self.stats;
+        if stats.blocks > 0 {
+            info!(
+                "Sent {} blocks, {} compressed: {} bytes of data, {} bytes \
+                 sent",
+                stats.blocks, stats.compressed_blocks, stats.raw_bytes,
+                stats.sent_bytes,
+            );
+        }
+    }
+}
+
+pub fn ssh_source(
+    loc: &SshLocation,
+    options: &SourceOptions,
+) -> Result<Source, Error> {
     let SshLocation { user, host, path } = loc;
     let connection_arg = match user {
         Some(user) => {
@@ -155,33 +497,41 @@ pub fn ssh_source(loc: &SshLocation) -> Result<Source, Error> {
         }
     };
     let escaped_path = shell_escape(path);
+    let args: Vec<String> = source_args(options).iter().map(|a| shell_escape(a)).collect();
     debug!(
-        "Running command: ssh {} syncfast remote-send {}",
-        connection_arg, escaped_path,
+        "Running command: ssh {} syncfast remote-send {} {}",
+        connection_arg, args.join(" "), escaped_path,
     );
     let process: Child = Command::new("ssh")
         .arg(connection_arg)
         .arg("syncfast")
         .arg("remote-send")
+        .args(&args)
         .arg(escaped_path)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::inherit())
         .spawn()?;
 
+    // We only send requests to the source, there is no block data to compress
+    let peer_capabilities = PeerCapabilities::default();
     Ok(Source {
         stream: futures::stream::unfold(
-            Box::pin(SshStream::new(process.stdout.unwrap())),
+            Box::pin(SshStream::new(
+                process.stdout.unwrap(),
+                peer_capabilities.clone(),
+                options.max_message_length,
+            )),
             SshStream::stream,
         ).boxed_local(),
-        sink: Box::pin(futures::sink::unfold(
-            Box::pin(SshSink::new(process.stdin.unwrap())),
-            SshSink::sink,
-        )),
+        sink: Box::pin(SshSink::new(process.stdin.unwrap(), peer_capabilities, 0)),
     })
 }
 
-pub fn ssh_destination(loc: &SshLocation) -> Result<Destination, Error> {
+pub fn ssh_destination(
+    loc: &SshLocation,
+    options: &DestinationOptions,
+) -> Result<Destination, Error> {
     let SshLocation { user, host, path } = loc;
     let connection_arg = match user {
         Some(user) => {
@@ -194,54 +544,286 @@ pub fn ssh_destination(loc: &SshLocation) -> Result<Destination, Error> {
         }
     };
     let escaped_path = shell_escape(path);
+    let args: Vec<String> = destination_args(options).iter().map(|a| shell_escape(a)).collect();
     debug!(
-        "Running command: ssh {} syncfast remote-recv {}",
-        connection_arg, escaped_path,
+        "Running command: ssh {} syncfast remote-recv {} {}",
+        connection_arg, args.join(" "), escaped_path,
     );
     let process: Child = Command::new("ssh")
         .arg(connection_arg)
         .arg("syncfast")
         .arg("remote-recv")
+        .args(&args)
         .arg(escaped_path)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::inherit())
         .spawn()?;
 
+    let peer_capabilities = PeerCapabilities::default();
     Ok(Destination {
         stream: futures::stream::unfold(
-            Box::pin(SshStream::new(process.stdout.unwrap())),
+            Box::pin(SshStream::new(
+                process.stdout.unwrap(),
+                peer_capabilities.clone(),
+                options.max_message_length,
+            )),
             SshStream::stream,
         ).boxed_local(),
-        sink: Box::pin(futures::sink::unfold(
-            Box::pin(SshSink::new(process.stdin.unwrap())),
-            SshSink::sink,
+        sink: Box::pin(SshSink::new(
+            process.stdin.unwrap(),
+            peer_capabilities,
+            options.compress_level,
         )),
     })
 }
 
-pub fn stdio_source() -> Source {
+/// Source reading from our standard input, for `remote-recv`
+///
+/// Strings longer than `max_message_length` bytes are refused.
+pub fn stdio_source(max_message_length: usize) -> Source {
+    // We only send requests to the source, there is no block data to compress
+    let peer_capabilities = PeerCapabilities::default();
     Source {
         stream: futures::stream::unfold(
-            Box::pin(SshStream::new(stdin())),
+            Box::pin(SshStream::new(stdin(), peer_capabilities.clone(), max_message_length)),
             SshStream::stream,
         ).boxed_local(),
-        sink: Box::pin(futures::sink::unfold(
-            Box::pin(SshSink::new(stdout())),
-            SshSink::sink,
-        )),
+        sink: Box::pin(SshSink::new(stdout(), peer_capabilities, 0)),
     }
 }
 
-pub fn stdio_destination() -> Destination {
+/// Destination sending to our standard output, for `remote-send`
+///
+/// Block data is compressed at `compress_level`, if the other side supports
+/// it; 0 disables compression. Strings longer than `max_message_length` bytes
+/// are refused.
+pub fn stdio_destination(
+    compress_level: u32,
+    max_message_length: usize,
+) -> Destination {
+    let peer_capabilities = PeerCapabilities::default();
     Destination {
         stream: futures::stream::unfold(
-            Box::pin(SshStream::new(stdin())),
+            Box::pin(SshStream::new(stdin(), peer_capabilities.clone(), max_message_length)),
             SshStream::stream,
         ).boxed_local(),
-        sink: Box::pin(futures::sink::unfold(
-            Box::pin(SshSink::new(stdout())),
-            SshSink::sink,
-        )),
+        sink: Box::pin(SshSink::new(stdout(), peer_capabilities, compress_level)),
+    }
+}
+
+/// Send an error to the other side over our standard output, before aborting
+///
+/// This is used by `remote-send` and `remote-recv`, so the error is reported
+/// by the local side instead of just a closed connection. `path` is the
+/// location, if the error happened while opening it; errors during the sync
+/// are sent without one, since they are not about the whole location.
+pub async fn stdio_send_error(
+    error: &Error,
+    path: Option<&str>,
+) -> Result<(), Error> {
+    send_error(&mut stdout(), error, path).await
+}
+
+/// Send an error to the other side, see `stdio_send_error()`
+#[allow(clippy::manual_async_fn)]
+pub(crate) fn send_error<'a, W: AsyncWrite + Unpin>(
+    output: &'a mut W,
+    error: &'a Error,
+    path: Option<&'a str>,
+) -> impl Future<Output=Result<(), Error>> + 'a {
+    async move {
+        let mut buffer = Vec::new();
+        write_message(
+            Message::Error(
+                error.kind().as_bytes(),
+                path.unwrap_or("").as_bytes(),
+                error.to_string().as_bytes(),
+            ),
+            &mut buffer,
+        )?;
+        output.write_all(&buffer).await?;
+        output.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream::StreamExt;
+
+    use crate::{Error, Filter, SymlinkPolicy};
+    use crate::sync::{
+        DEFAULT_MAX_MESSAGE_LENGTH, DeleteMode, DestinationOptions, SourceEvent,
+        SourceOptions,
+    };
+    use super::{
+        SshStream, destination_args, parse_destination_args,
+        parse_source_args, shell_escape, source_args,
+    };
+    use crate::HashDigest;
+    use crate::sync::proto::{Message, PROTOCOL_VERSION, compress_block, write_message};
+
+    fn read_events(
+        messages: &[Message],
+        count: usize,
+    ) -> Vec<Result<SourceEvent, Error>> {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(message.clone(), &mut input).unwrap();
+        }
+        read_raw_events(&input, count)
+    }
+
+    fn read_raw_events(
+        input: &[u8],
+        count: usize,
+    ) -> Vec<Result<SourceEvent, Error>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(
+            futures::stream::unfold(
+                Box::pin(SshStream::new(input, Default::default(), DEFAULT_MAX_MESSAGE_LENGTH)),
+                SshStream::stream,
+            ).take(count).collect()
+        )
+    }
+
+    #[test]
+    fn test_hello() {
+        let events = read_events(
+            &[Message::Hello(PROTOCOL_VERSION, b"foo bar"), Message::EndFiles],
+            2,
+        );
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], Ok(SourceEvent::EndFiles)));
+
+        let events = read_events(
+            &[Message::Hello(999, b""), Message::EndFiles],
+            1,
+        );
+        match &events[0] {
+            Err(Error::Sync(e)) => assert!(e.contains("protocol version 999")),
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        let events = read_events(&[Message::EndFiles], 1);
+        match &events[0] {
+            Err(Error::Sync(e)) => assert!(e.contains("didn't send HELLO")),
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // Older versions used a line-based protocol
+        let events = read_raw_events(b"HELLO\n1\n\nEND_FILES\n", 1);
+        match &events[0] {
+            Err(Error::Sync(e)) => assert!(e.contains("older version of syncfast")),
+            e => panic!("Unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_compressed_block() {
+        let data = b"some data some data some data some data".to_vec();
+        let compressed = compress_block(&data, 6).unwrap();
+        let hash = HashDigest::of(&data);
+        let events = read_events(
+            &[
+                Message::Hello(PROTOCOL_VERSION, b"deflate"),
+                Message::CompressedBlockData(hash.clone(), &compressed),
+            ],
+            1,
+        );
+        match &events[0] {
+            Ok(SourceEvent::BlockData(h, d)) => {
+                assert_eq!(h, &hash);
+                assert_eq!(d, &data);
+            }
+            e => panic!("Unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let events = read_events(
+            &[
+                Message::Hello(PROTOCOL_VERSION, b""),
+                Message::EndFiles,
+                Message::Error(b"io", b"/some/path", b"Permission denied"),
+            ],
+            3,
+        );
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], Ok(SourceEvent::EndFiles)));
+        match &events[1] {
+            Err(Error::Remote(e)) => {
+                assert_eq!(e.kind, "io");
+                assert_eq!(e.path.as_deref(), Some("/some/path"));
+                assert_eq!(e.message, "Permission denied");
+            }
+            e => panic!("Unexpected result: {:?}", e),
+        }
+
+        // Errors can come before HELLO
+        let events = read_events(&[Message::Error(b"sync", b"", b"Oops")], 1);
+        match &events[0] {
+            Err(Error::Remote(e)) => {
+                assert_eq!(e.path, None);
+                assert_eq!(e.to_string(), "Remote error (sync): Oops");
+            }
+            e => panic!("Unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_args() {
+        let mut filter = Filter::new();
+        filter.add_rule("- *.tmp").unwrap();
+        filter.add_rule("+ \"quoted\" name").unwrap();
+
+        let args = source_args(&SourceOptions {
+            symlinks: SymlinkPolicy::SkipUnsafe,
+            filter: filter.clone(),
+            compress_level: 9,
+            max_message_length: 4096,
+        });
+        let options = parse_source_args(&args).unwrap();
+        assert_eq!(options.symlinks, SymlinkPolicy::SkipUnsafe);
+        assert_eq!(options.compress_level, 9);
+        assert_eq!(options.max_message_length, 4096);
+        assert_eq!(options.filter.rules(), filter.rules());
+
+        let args = destination_args(&DestinationOptions {
+            delete: DeleteMode::After,
+            filter: filter.clone(),
+            dry_run: true,
+            block_summary: false,
+            ..Default::default()
+        });
+        let options = parse_destination_args(&args).unwrap();
+        assert_eq!(options.delete, DeleteMode::After);
+        assert!(options.dry_run);
+        assert!(!options.block_summary);
+        assert_eq!(options.filter.rules(), filter.rules());
+
+        assert!(parse_source_args(&["--delete".to_owned()]).is_err());
+        assert!(parse_destination_args(&["--filter".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_shell_escape() {
+        assert_eq!(shell_escape("- $HOME"), "'- $HOME'");
+        assert_eq!(shell_escape("it's"), "'it'\\''s'");
+
+        // Nothing gets expanded by the shell
+        #[cfg(unix)]
+        for arg in &["- $HOME", "- `echo no`", "- $(echo no)", "a!b", "it's", "\\\"\n"] {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_escape(arg)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), *arg);
+        }
     }
 }
@@ -0,0 +1,119 @@
+//! Summary of the blocks present at the destination.
+
+use std::convert::TryInto;
+
+use crate::HashDigest;
+
+/// Bits per block in the summary, giving about 1% of false positives
+const BITS_PER_BLOCK: usize = 10;
+
+/// Maximum size of the summary in bytes, so it fits in a protocol message
+///
+/// Past that, the false positive rate goes up, which only means more blocks
+/// get requested the usual way.
+const MAX_SIZE: usize = 1 << 20; // 1 MiB
+
+/// Maximum number of hash functions, see `BlockSummary::from_parts()`
+const MAX_HASHES: u32 = 16;
+
+/// Bloom filter of the blocks the destination already has
+///
+/// The destination sends this upfront, so that the source can send the blocks
+/// that are not in it without waiting for them to be requested. A block that
+/// is present is always found in the summary; a block that is missing might
+/// be found too (false positive), it is then requested as usual.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockSummary {
+    num_hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl BlockSummary {
+    /// Create an empty summary, sized for the given number of blocks
+    pub fn new(num_blocks: usize) -> BlockSummary {
+        let size = (num_blocks.max(1) * BITS_PER_BLOCK + 7) / 8;
+        let size = size.max(8).min(MAX_SIZE);
+        // Optimal number of hashes is ln(2) * bits / blocks
+        let num_hashes = (size * 8) as f64 / num_blocks.max(1) as f64
+            * std::f64::consts::LN_2;
+        let num_hashes = (num_hashes.round() as u32).max(1).min(MAX_HASHES);
+        BlockSummary {
+            num_hashes,
+            bits: vec![0; size],
+        }
+    }
+
+    /// Build a summary from the fields received from the other side
+    ///
+    /// Returns `None` if they are invalid.
+    pub fn from_parts(num_hashes: u32, bits: Vec<u8>) -> Option<BlockSummary> {
+        if num_hashes == 0 || num_hashes > MAX_HASHES || bits.is_empty() {
+            return None;
+        }
+        Some(BlockSummary { num_hashes, bits })
+    }
+
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    pub fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Positions of the bits for a block
+    ///
+    /// The digests are already uniformly distributed, so the hash functions
+    /// are derived from them directly (double hashing).
+    fn positions<'a>(&'a self, hash: &HashDigest) -> impl Iterator<Item=usize> + 'a {
+        let h1 = u64::from_le_bytes(hash.0[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash.0[8..16].try_into().unwrap()) | 1;
+        let num_bits = self.bits.len() as u64 * 8;
+        (0..self.num_hashes as u64).map(move |i| {
+            (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize
+        })
+    }
+
+    pub fn insert(&mut self, hash: &HashDigest) {
+        let positions: Vec<usize> = self.positions(hash).collect();
+        for pos in positions {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+    }
+
+    /// Whether the block might be present
+    pub fn contains(&self, hash: &HashDigest) -> bool {
+        self.positions(hash).all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HashDigest;
+    use super::BlockSummary;
+
+    #[test]
+    fn test_summary() {
+        let mut summary = BlockSummary::new(1000);
+        for i in 0..1000u32 {
+            summary.insert(&HashDigest::of(&i.to_le_bytes()));
+        }
+        for i in 0..1000u32 {
+            assert!(summary.contains(&HashDigest::of(&i.to_le_bytes())));
+        }
+        let false_positives = (1000..11000u32)
+            .filter(|i| summary.contains(&HashDigest::of(&i.to_le_bytes())))
+            .count();
+        assert!(false_positives < 300);
+
+        let empty = BlockSummary::new(0);
+        assert!(!empty.contains(&HashDigest::of(b"test")));
+
+        assert!(BlockSummary::from_parts(0, vec![0; 8]).is_none());
+        assert!(BlockSummary::from_parts(3, Vec::new()).is_none());
+        assert_eq!(
+            BlockSummary::from_parts(summary.num_hashes(), summary.bits().to_owned()),
+            Some(summary),
+        );
+    }
+}
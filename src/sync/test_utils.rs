@@ -0,0 +1,14 @@
+//! Fixtures shared by the tests of the different protocol handlers.
+
+/// Content of a file spanning many blocks, none of them identical
+pub fn big_file() -> Vec<u8> {
+    (0..200000u32).flat_map(|i| i.to_le_bytes().to_vec()).collect()
+}
+
+/// Copy of the content with a few bytes changed in the middle, in a single
+/// block
+pub fn change_middle(content: &[u8]) -> Vec<u8> {
+    let mut changed = content.to_owned();
+    changed[400000..400008].copy_from_slice(b"changed!");
+    changed
+}
@@ -1,7 +1,13 @@
+use filetime::FileTime;
 use futures::future::{FutureExt, Map};
 use futures::channel::oneshot::{Canceled, Receiver, Sender, channel};
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 
+use crate::{Error, temp_name};
+use crate::sync::FileMetadata;
+
 pub struct Condition {
     sender: Option<Sender<()>>,
     receiver: Option<Receiver<()>>,
@@ -22,11 +28,10 @@ impl Condition {
         sender.send(()).expect("Condition::set()");
     }
 
+    /// Wait for the condition to be set, or for it to be dropped
     pub fn wait(&mut self) -> ConditionFuture {
-        fn error(r: Result<(), Canceled>) {
-            r.expect("Condition::wait()");
-        }
-        self.receiver.take().expect("Condition::wait() called twice").map(error)
+        fn ignore(_: Result<(), Canceled>) {}
+        self.receiver.take().expect("Condition::wait() called twice").map(ignore)
     }
 }
 
@@ -46,3 +51,88 @@ pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
         }
     }
 }
+
+/// Remove a file, if it exists
+pub fn remove_file_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write a file under a temporary name, then rename it
+///
+/// This way the file is never used while incomplete, and it is left
+/// unchanged if writing fails.
+pub fn write_file_atomic<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+{
+    let temp_path = temp_name(path)?;
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let res = write(&mut writer).and_then(|()| writer.flush().map_err(Error::Io));
+    drop(writer);
+    if let Err(e) = res {
+        remove_file_if_exists(&temp_path)?;
+        return Err(e);
+    }
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// The bits of the mode that are applied from the source: read, write and
+/// execute, see `set_metadata()`
+pub const PERMISSION_BITS: u32 = 0o777;
+
+/// Set the modification time and permissions of a file
+///
+/// Only the read, write and execute bits are set: the mode comes from the
+/// source, which shouldn't be able to create setuid or setgid files.
+pub fn set_metadata(path: &Path, metadata: &FileMetadata) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(
+            path,
+            std::fs::Permissions::from_mode(metadata.mode & PERMISSION_BITS),
+        )?;
+    }
+    filetime::set_file_mtime(
+        path,
+        FileTime::from_unix_time(
+            metadata.modified.timestamp(),
+            metadata.modified.timestamp_subsec_nanos(),
+        ),
+    )
+}
+
+/// Create a symbolic link
+#[cfg(unix)]
+pub fn create_symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+pub fn create_symlink(_target: &Path, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "Symbolic links are not supported on this platform",
+    ))
+}
+
+/// Check whether two paths are hard links to the same file
+#[cfg(unix)]
+pub fn is_same_file(a: &Path, b: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let a = std::fs::symlink_metadata(a)?;
+    let b = std::fs::symlink_metadata(b)?;
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+pub fn is_same_file(_a: &Path, _b: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}